@@ -6,6 +6,7 @@ extern crate libc;
 extern crate serde;
 extern crate serde_json;
 extern crate swiboe;
+extern crate time;
 
 use libc::c_char;
 use std::ffi::{CStr, CString};
@@ -25,6 +26,9 @@ pub enum CApiResult {
     ERR_JSON_PARSING = 3,
     ERR_RPC_DONE = 4,
     ERR_INVALID_UTF8 = 5,
+    ERR_INVALID_CONFIG = 6,
+    ERR_RPC = 7,
+    ERR_TIMEOUT = 8,
 }
 
 // Like try!, but instead of Err() returns a CApiResult that represents the error.
@@ -38,6 +42,9 @@ macro_rules! try_capi {
                 swiboe::Error::JsonParsing(_) => CApiResult::ERR_JSON_PARSING,
                 swiboe::Error::RpcDone => CApiResult::ERR_RPC_DONE,
                 swiboe::Error::InvalidUtf8 => CApiResult::ERR_INVALID_UTF8,
+                swiboe::Error::InvalidConfig(_) => CApiResult::ERR_INVALID_CONFIG,
+                swiboe::Error::Rpc(_) => CApiResult::ERR_RPC,
+                swiboe::Error::Timeout => CApiResult::ERR_TIMEOUT,
             }
         }
     })
@@ -50,6 +57,15 @@ pub enum CApiRpcErrorKind {
     RPC_ERR_UNKNOWN = 1,
     RPC_ERR_IO = 2,
     RPC_ERR_INVALID_ARGS = 3,
+    RPC_ERR_TIMEOUT = 4,
+    RPC_ERR_PERMISSION_DENIED = 5,
+    RPC_ERR_RATE_LIMITED = 6,
+    RPC_ERR_DEADLOCK = 7,
+    RPC_ERR_INTERNAL = 8,
+    RPC_ERR_CANCELLED = 9,
+    RPC_ERR_NOT_FOUND = 10,
+    /// A plugin defined error, see 'swiboe_rpc_error_with_message'.
+    RPC_ERR_CUSTOM = 11,
 }
 
 /// Wraps the callback type for RPCs so that C clients can implement them using a single C
@@ -78,13 +94,31 @@ impl client::rpc::server::Rpc for CallbackRpc {
 }
 
 
-// Converts 'kind' to a matching rpc::ErrorKind enum.
-fn to_rpc_error_kind(kind: CApiRpcErrorKind) -> rpc::ErrorKind {
-    match kind {
+// Converts 'kind' to a matching rpc::ErrorKind enum. None for RPC_ERR_CUSTOM, which needs a code.
+fn to_rpc_error_kind(kind: CApiRpcErrorKind) -> Option<rpc::ErrorKind> {
+    let kind = match kind {
         CApiRpcErrorKind::RPC_ERR_UNKNOWN => rpc::ErrorKind::UnknownRpc,
         CApiRpcErrorKind::RPC_ERR_IO => rpc::ErrorKind::Io,
         CApiRpcErrorKind::RPC_ERR_INVALID_ARGS => rpc::ErrorKind::InvalidArgs,
+        CApiRpcErrorKind::RPC_ERR_TIMEOUT => rpc::ErrorKind::Timeout,
+        CApiRpcErrorKind::RPC_ERR_PERMISSION_DENIED => rpc::ErrorKind::PermissionDenied,
+        CApiRpcErrorKind::RPC_ERR_RATE_LIMITED => rpc::ErrorKind::RateLimited,
+        CApiRpcErrorKind::RPC_ERR_DEADLOCK => rpc::ErrorKind::Deadlock,
+        CApiRpcErrorKind::RPC_ERR_INTERNAL => rpc::ErrorKind::Internal,
+        CApiRpcErrorKind::RPC_ERR_CANCELLED => rpc::ErrorKind::Cancelled,
+        CApiRpcErrorKind::RPC_ERR_NOT_FOUND => rpc::ErrorKind::NotFound,
+        CApiRpcErrorKind::RPC_ERR_CUSTOM => return None,
+    };
+    Some(kind)
+}
+
+// Converts a timeout in milliseconds we got passed from the API user. Timeouts too long to be
+// measured in nanoseconds are no timeouts.
+fn to_timeout(timeout_ms: u64) -> Option<time::Duration> {
+    if timeout_ms > i64::max_value() as u64 / 1_000_000 {
+        return None;
     }
+    Some(time::Duration::milliseconds(timeout_ms as i64))
 }
 
 // Converts a buffer we got passed from the API user or dies if the input is invalid.
@@ -145,6 +179,21 @@ pub extern "C" fn swiboe_connect(socket_name: *const c_char, client: *mut *const
     CApiResult::SUCCESS
 }
 
+/// Makes waiting for the results of all calls made by 'client' from now on give up after
+/// 'timeout_ms' milliseconds, with ERR_TIMEOUT. 0 waits forever, which is the default. Calls
+/// the client makes by itself, like the one of 'swiboe_new_rpc', are not affected.
+#[no_mangle]
+pub extern "C" fn swiboe_client_set_default_timeout(client: *mut client::Client, timeout_ms: u64) -> CApiResult {
+    let client: &mut client::Client = unsafe {
+        mem::transmute(client)
+    };
+    client.set_default_timeout(match timeout_ms {
+        0 => None,
+        ms => to_timeout(ms),
+    });
+    CApiResult::SUCCESS
+}
+
 /// Disconnects 'client' from the server and deletes it.
 #[no_mangle]
 pub extern "C" fn swiboe_disconnect(client: *mut client::Client) -> CApiResult {
@@ -210,6 +259,17 @@ pub extern "C" fn swiboe_rpc_ok(json_str: *const c_char) -> *const rpc::Result {
 /// can be NULL.
 #[no_mangle]
 pub extern "C" fn swiboe_rpc_error(error_kind: CApiRpcErrorKind, json_details: *const c_char) -> *const rpc::Result {
+    swiboe_rpc_error_with_message(error_kind, ptr::null(), ptr::null(), json_details)
+}
+
+/// Like 'swiboe_rpc_error', but also takes a human readable 'message' and, if 'error_kind' is
+/// RPC_ERR_CUSTOM, the plugin defined 'code'. 'message' can be NULL. RPC_ERR_CUSTOM without a
+/// 'code' gives an RPC_ERR_INTERNAL error that says so.
+#[no_mangle]
+pub extern "C" fn swiboe_rpc_error_with_message(error_kind: CApiRpcErrorKind,
+                                                code: *const c_char,
+                                                message: *const c_char,
+                                                json_details: *const c_char) -> *const rpc::Result {
     let details = if json_details.is_null() {
         None
     } else {
@@ -219,8 +279,35 @@ pub extern "C" fn swiboe_rpc_error(error_kind: CApiRpcErrorKind, json_details: *
         Some(to_json_or_die(&c_str_details))
     };
 
+    let kind = match to_rpc_error_kind(error_kind) {
+        Some(kind) => kind,
+        None if code.is_null() => {
+            let err = rpc::Error::new(rpc::ErrorKind::Internal,
+                                      "The handler gave RPC_ERR_CUSTOM without a code.");
+            return unsafe {
+                mem::transmute(Box::new(rpc::Result::Err(err)))
+            };
+        },
+        None => {
+            let code_c_str = unsafe {
+                CStr::from_ptr(code)
+            };
+            rpc::ErrorKind::Custom(to_str_or_die(&code_c_str).into())
+        },
+    };
+
+    let message = if message.is_null() {
+        None
+    } else {
+        let message_c_str = unsafe {
+            CStr::from_ptr(message)
+        };
+        Some(to_str_or_die(&message_c_str).into())
+    };
+
     let err = rpc::Error {
-        kind: to_rpc_error_kind(error_kind),
+        kind: kind,
+        message: message,
         details: details,
     };
 
@@ -299,42 +386,52 @@ pub extern "C" fn swiboe_client_context_wait(context: *mut client::rpc::client::
     CApiResult::SUCCESS
 }
 
-/// Cancels a streaming RPC and deletes the 'context'.
+/// Like 'swiboe_client_context_wait', but gives up after 'timeout_ms' milliseconds. The RPC is
+/// cancelled then and ERR_TIMEOUT is returned. Deletes the 'context' in either case.
 #[no_mangle]
-pub extern "C" fn swiboe_client_context_cancel(context: *mut client::rpc::client::Context) -> CApiResult {
-    let context: Box<client::rpc::client::Context> = unsafe {
+pub extern "C" fn swiboe_client_context_wait_timeout(context: *mut client::rpc::client::Context, timeout_ms: u64, rpc_result: *mut *const rpc::Result) -> CApiResult {
+    let mut context: Box<client::rpc::client::Context> = unsafe {
         mem::transmute(context)
     };
-    try_capi!(context.cancel());
-    CApiResult::SUCCESS
-}
 
-trait ClientContextReceiverFunction {
-    fn receive(context: &mut client::rpc::client::Context) -> swiboe::Result<Option<serde_json::Value>>;
+    let result: rpc::Result = match to_timeout(timeout_ms) {
+        Some(timeout) => try_capi!(context.wait_timeout(timeout)),
+        None => try_capi!(context.wait()),
+    };
+    unsafe {
+        *rpc_result = mem::transmute(Box::new(result))
+    }
+    CApiResult::SUCCESS
 }
 
-struct Recv;
-impl ClientContextReceiverFunction for Recv {
-    fn receive(context: &mut client::rpc::client::Context) -> swiboe::Result<Option<serde_json::Value>> {
-        context.recv()
-    }
+/// Deletes the 'context' of an RPC that is done, e.g. after swiboe_client_context_recv() returned
+/// no more results. An RPC that is still running is cancelled.
+#[no_mangle]
+pub extern "C" fn swiboe_client_context_delete(context: *mut client::rpc::client::Context) {
+    let _: Box<client::rpc::client::Context> = unsafe {
+        mem::transmute(context)
+    };
 }
 
-struct TryRecv;
-impl ClientContextReceiverFunction for TryRecv {
-    fn receive(context: &mut client::rpc::client::Context) -> swiboe::Result<Option<serde_json::Value>> {
-        context.try_recv()
-    }
+/// Cancels a streaming RPC and deletes the 'context'.
+#[no_mangle]
+pub extern "C" fn swiboe_client_context_cancel(context: *mut client::rpc::client::Context) -> CApiResult {
+    let context: Box<client::rpc::client::Context> = unsafe {
+        mem::transmute(context)
+    };
+    try_capi!(context.cancel());
+    CApiResult::SUCCESS
 }
 
-fn client_context_receive<T: ClientContextReceiverFunction>(context: *mut client::rpc::client::Context, json_c_str: *mut *const c_char) -> CApiResult {
+fn client_context_receive<F>(context: *mut client::rpc::client::Context, json_c_str: *mut *const c_char, receive: F) -> CApiResult
+    where F: FnOnce(&mut client::rpc::client::Context) -> swiboe::Result<Option<serde_json::Value>> {
     assert_eq!(ptr::null(), unsafe { *json_c_str });
 
     let mut context: &mut client::rpc::client::Context = unsafe {
         mem::transmute(context)
     };
 
-    let object = try_capi!(T::receive(context));
+    let object = try_capi!(receive(context));
     match object {
         None => (),
         Some(json_value) => {
@@ -352,7 +449,20 @@ fn client_context_receive<T: ClientContextReceiverFunction>(context: *mut client
 /// 'json_c_str' with the new result. This has to be freed using swiboe_delete_string().
 #[no_mangle]
 pub extern "C" fn swiboe_client_context_recv(context: *mut client::rpc::client::Context, json_c_str: *mut *const c_char) -> CApiResult {
-    client_context_receive::<Recv>(context, json_c_str)
+    client_context_receive(context, json_c_str, |context| context.recv())
+}
+
+/// Like 'swiboe_client_context_recv', but gives up after 'timeout_ms' milliseconds. The RPC is
+/// cancelled then and ERR_TIMEOUT is returned. The 'context' still needs to be deleted using
+/// swiboe_client_context_delete().
+#[no_mangle]
+pub extern "C" fn swiboe_client_context_recv_timeout(context: *mut client::rpc::client::Context, timeout_ms: u64, json_c_str: *mut *const c_char) -> CApiResult {
+    client_context_receive(context, json_c_str, |context| {
+        match to_timeout(timeout_ms) {
+            Some(timeout) => context.recv_timeout(timeout),
+            None => context.recv(),
+        }
+    })
 }
 
 /// Tries to read a partial result for the RPC represented by 'context'. If no result is pending,
@@ -360,7 +470,7 @@ pub extern "C" fn swiboe_client_context_recv(context: *mut client::rpc::client::
 /// to be freed using swiboe_delete_string().
 #[no_mangle]
 pub extern "C" fn swiboe_client_context_try_recv(context: *mut client::rpc::client::Context, json_c_str: *mut *const c_char) -> CApiResult {
-    client_context_receive::<TryRecv>(context, json_c_str)
+    client_context_receive(context, json_c_str, |context| context.try_recv())
 }
 
 /// Returns true if this RPC call has completely finished, i.e. has no more streaming results to
@@ -403,6 +513,24 @@ pub extern "C" fn swiboe_rpc_result_unwrap(rpc_result: *const rpc::Result, c_buf
 /// Deletes 'rpc_result'. 'details' must be freed using swiboe_delete_string().
 #[no_mangle]
 pub extern "C" fn swiboe_rpc_result_unwrap_err(rpc_result: *const rpc::Result, details: *mut *const c_char) -> CApiRpcErrorKind {
+    let mut code = ptr::null();
+    let mut message = ptr::null();
+    let kind = swiboe_rpc_result_unwrap_err_with_message(rpc_result, &mut code, &mut message, details);
+    swiboe_delete_string(code as *mut c_char);
+    swiboe_delete_string(message as *mut c_char);
+    kind
+}
+
+/// Like 'swiboe_rpc_result_unwrap_err', but also returns the human readable 'message' and, for
+/// RPC_ERR_CUSTOM, the plugin defined 'code'. Both stay NULL if there are none and must be freed
+/// using swiboe_delete_string() otherwise.
+#[no_mangle]
+pub extern "C" fn swiboe_rpc_result_unwrap_err_with_message(rpc_result: *const rpc::Result,
+                                                            code: *mut *const c_char,
+                                                            message: *mut *const c_char,
+                                                            details: *mut *const c_char) -> CApiRpcErrorKind {
+    assert_eq!(ptr::null(), unsafe { *code });
+    assert_eq!(ptr::null(), unsafe { *message });
     assert_eq!(ptr::null(), unsafe { *details });
 
     let rpc_result: Box<rpc::Result> = unsafe {
@@ -410,6 +538,20 @@ pub extern "C" fn swiboe_rpc_result_unwrap_err(rpc_result: *const rpc::Result, d
     };
     let err = rpc_result.unwrap_err();
 
+    if let rpc::ErrorKind::Custom(ref err_code) = err.kind {
+        let code_c_str = CString::new(err_code.clone()).unwrap().into_raw();
+        unsafe {
+            *code = code_c_str;
+        }
+    }
+
+    if let Some(ref err_message) = err.message {
+        let message_c_str = CString::new(err_message.clone()).unwrap().into_raw();
+        unsafe {
+            *message = message_c_str;
+        }
+    }
+
     if let Some(err_details) = err.details {
         let json_string = serde_json::to_string(&err_details).unwrap();
         let details_c_str = CString::new(json_string).unwrap().into_raw();
@@ -422,6 +564,14 @@ pub extern "C" fn swiboe_rpc_result_unwrap_err(rpc_result: *const rpc::Result, d
         rpc::ErrorKind::UnknownRpc => CApiRpcErrorKind::RPC_ERR_UNKNOWN,
         rpc::ErrorKind::Io => CApiRpcErrorKind::RPC_ERR_IO,
         rpc::ErrorKind::InvalidArgs => CApiRpcErrorKind::RPC_ERR_INVALID_ARGS,
+        rpc::ErrorKind::Timeout => CApiRpcErrorKind::RPC_ERR_TIMEOUT,
+        rpc::ErrorKind::PermissionDenied => CApiRpcErrorKind::RPC_ERR_PERMISSION_DENIED,
+        rpc::ErrorKind::RateLimited => CApiRpcErrorKind::RPC_ERR_RATE_LIMITED,
+        rpc::ErrorKind::Deadlock => CApiRpcErrorKind::RPC_ERR_DEADLOCK,
+        rpc::ErrorKind::Internal => CApiRpcErrorKind::RPC_ERR_INTERNAL,
+        rpc::ErrorKind::Cancelled => CApiRpcErrorKind::RPC_ERR_CANCELLED,
+        rpc::ErrorKind::NotFound => CApiRpcErrorKind::RPC_ERR_NOT_FOUND,
+        rpc::ErrorKind::Custom(_) => CApiRpcErrorKind::RPC_ERR_CUSTOM,
     }
 }
 
@@ -4,7 +4,7 @@
 # Licensed under the Apache License, Version 2.0. See LICENSE.txt
 # in the project root for license information.
 
-from ctypes import c_void_p, c_char_p, c_uint16, c_int32, CFUNCTYPE, POINTER
+from ctypes import c_void_p, c_char_p, c_uint16, c_int32, c_uint64, CFUNCTYPE, POINTER
 import ctypes
 import os
 import platform
@@ -25,11 +25,22 @@ ERR_IO = 2
 ERR_JSON_PARSING = 3
 ERR_RPC_DONE = 4
 ERR_INVALID_UTF8 = 5
+ERR_INVALID_CONFIG = 6
+ERR_RPC = 7
+ERR_TIMEOUT = 8
 
 # RPC error codes
 RPC_ERR_UNKNOWN = 1
 RPC_ERR_IO = 2
 RPC_ERR_INVALID_ARGS = 3
+RPC_ERR_TIMEOUT = 4
+RPC_ERR_PERMISSION_DENIED = 5
+RPC_ERR_RATE_LIMITED = 6
+RPC_ERR_DEADLOCK = 7
+RPC_ERR_INTERNAL = 8
+RPC_ERR_CANCELLED = 9
+RPC_ERR_NOT_FOUND = 10
+RPC_ERR_CUSTOM = 11
 
 
 def load_shared_library(shared_library):
@@ -50,6 +61,9 @@ def load_shared_library(shared_library):
     library.swiboe_disconnect.restype = Result
     library.swiboe_disconnect.argtypes = [PtrClient]
 
+    library.swiboe_client_set_default_timeout.restype = Result
+    library.swiboe_client_set_default_timeout.argtypes = [PtrClient, c_uint64]
+
     library.swiboe_new_rpc.restype = Result
     library.swiboe_new_rpc.argtypes = [PtrClient, c_char_p, c_uint16, RPC]
 
@@ -62,6 +76,10 @@ def load_shared_library(shared_library):
     library.swiboe_rpc_error.restype = PtrRpcResult
     library.swiboe_rpc_error.argtypes = [RpcErrorKind, c_char_p]
 
+    library.swiboe_rpc_error_with_message.restype = PtrRpcResult
+    library.swiboe_rpc_error_with_message.argtypes = [RpcErrorKind, c_char_p,
+                                                      c_char_p, c_char_p]
+
     library.swiboe_rpc_result_is_ok.restype = bool
     library.swiboe_rpc_result_is_ok.argtypes = [PtrRpcResult]
 
@@ -73,6 +91,11 @@ def load_shared_library(shared_library):
     library.swiboe_rpc_result_unwrap_err.argtypes = [PtrRpcResult,
                                                      POINTER(c_char_p)]
 
+    library.swiboe_rpc_result_unwrap_err_with_message.restype = RpcErrorKind
+    library.swiboe_rpc_result_unwrap_err_with_message.argtypes = [
+        PtrRpcResult, POINTER(c_char_p), POINTER(c_char_p), POINTER(c_char_p)
+    ]
+
     library.swiboe_client_call_rpc.restype = Result
     library.swiboe_client_call_rpc.argtypes = [PtrClient, c_char_p, c_char_p,
                                                POINTER(PtrClientContext)]
@@ -81,13 +104,26 @@ def load_shared_library(shared_library):
     library.swiboe_client_context_wait.argtypes = [PtrClientContext,
                                                    POINTER(PtrRpcResult)]
 
+    library.swiboe_client_context_wait_timeout.restype = Result
+    library.swiboe_client_context_wait_timeout.argtypes = [
+        PtrClientContext, c_uint64, POINTER(PtrRpcResult)
+    ]
+
     library.swiboe_client_context_cancel.restype = Result
     library.swiboe_client_context_cancel.argtypes = [PtrClientContext]
 
+    library.swiboe_client_context_delete.restype = None
+    library.swiboe_client_context_delete.argtypes = [PtrClientContext]
+
     library.swiboe_client_context_recv.restype = Result
     library.swiboe_client_context_recv.argtypes = [PtrClientContext,
                                                    POINTER(c_char_p)]
 
+    library.swiboe_client_context_recv_timeout.restype = Result
+    library.swiboe_client_context_recv_timeout.argtypes = [
+        PtrClientContext, c_uint64, POINTER(c_char_p)
+    ]
+
     library.swiboe_client_context_try_recv.restype = Result
     library.swiboe_client_context_try_recv.argtypes = [PtrClientContext,
                                                        POINTER(c_char_p)]
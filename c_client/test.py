@@ -122,6 +122,72 @@ class TestSwiboeClientLowLevel(unittest.TestCase):
         self._ok(self.library.swiboe_disconnect(client))
         self._ok(self.library.swiboe_disconnect(serving_client))
 
+    def test_call_rpc_returning_custom_error(self):
+        serving_client = self._checked_connect()
+
+        def callback(server_context, args_string):
+            call_result = self.library.swiboe_rpc_error_with_message(
+                swiboe.RPC_ERR_CUSTOM, 'test.too_late', 'It is too late.',
+                None)
+            self._ok(self.library.swiboe_server_context_finish(
+                server_context, call_result))
+
+        rpc_callback = swiboe.RPC(callback)
+        self._ok(self.library.swiboe_new_rpc(
+            serving_client, 'test.test', 100, rpc_callback))
+
+        client = self._checked_connect()
+        client_context = swiboe.PtrClientContext()
+        self._ok(self.library.swiboe_client_call_rpc(
+            client, 'test.test', 'null', byref(client_context)))
+        call_result = swiboe.PtrRpcResult()
+        self._ok(self.library.swiboe_client_context_wait(
+            client_context, byref(call_result)))
+
+        code = c_char_p()
+        message = c_char_p()
+        details = c_char_p()
+        rpc_error_code = self.library.swiboe_rpc_result_unwrap_err_with_message(
+            call_result, byref(code), byref(message), byref(details))
+        self.assertEqual(swiboe.RPC_ERR_CUSTOM, rpc_error_code)
+        self.assertEqual('test.too_late', code.value)
+        self.assertEqual('It is too late.', message.value)
+        self.assertEqual(None, details.value)
+        self.library.swiboe_delete_string(code)
+        self.library.swiboe_delete_string(message)
+
+        self._ok(self.library.swiboe_disconnect(client))
+        self._ok(self.library.swiboe_disconnect(serving_client))
+
+    def test_custom_error_without_code_is_internal_error(self):
+        serving_client = self._checked_connect()
+
+        def callback(server_context, args_string):
+            call_result = self.library.swiboe_rpc_error(
+                swiboe.RPC_ERR_CUSTOM, None)
+            self._ok(self.library.swiboe_server_context_finish(
+                server_context, call_result))
+
+        rpc_callback = swiboe.RPC(callback)
+        self._ok(self.library.swiboe_new_rpc(
+            serving_client, 'test.test', 100, rpc_callback))
+
+        client = self._checked_connect()
+        client_context = swiboe.PtrClientContext()
+        self._ok(self.library.swiboe_client_call_rpc(
+            client, 'test.test', 'null', byref(client_context)))
+        call_result = swiboe.PtrRpcResult()
+        self._ok(self.library.swiboe_client_context_wait(
+            client_context, byref(call_result)))
+
+        details = c_char_p()
+        rpc_error_code = self.library.swiboe_rpc_result_unwrap_err(
+            call_result, byref(details))
+        self.assertEqual(swiboe.RPC_ERR_INTERNAL, rpc_error_code)
+
+        self._ok(self.library.swiboe_disconnect(client))
+        self._ok(self.library.swiboe_disconnect(serving_client))
+
     def test_call_successfull_rpc(self):
         serving_client = self._checked_connect()
         golden_return = {'blub': 'foo'}
@@ -312,6 +378,65 @@ class TestSwiboeClientLowLevel(unittest.TestCase):
         self._ok(self.library.swiboe_disconnect(client))
         self._ok(self.library.swiboe_disconnect(serving_client))
 
+    def test_waiting_for_rpc_times_out(self):
+        serving_client = self._checked_connect()
+
+        done_event = threading.Event()
+
+        def callback(server_context, args_string):
+            while not self.library.swiboe_server_context_cancelled(
+                server_context):
+                pass
+            done_event.set()
+
+        rpc_callback = swiboe.RPC(callback)
+        self._ok(self.library.swiboe_new_rpc(
+            serving_client, 'test.test', 100, rpc_callback))
+
+        client = self._checked_connect()
+        client_context = swiboe.PtrClientContext()
+        self._ok(self.library.swiboe_client_call_rpc(
+            client, 'test.test', 'null', byref(client_context)))
+
+        call_result = swiboe.PtrRpcResult()
+        self.assertEqual(swiboe.ERR_TIMEOUT,
+                         self.library.swiboe_client_context_wait_timeout(
+                             client_context, 50, byref(call_result)))
+
+        done_event.wait()
+        self._ok(self.library.swiboe_disconnect(client))
+        self._ok(self.library.swiboe_disconnect(serving_client))
+
+    def test_receiving_from_rpc_times_out(self):
+        serving_client = self._checked_connect()
+
+        done_event = threading.Event()
+
+        def callback(server_context, args_string):
+            while not self.library.swiboe_server_context_cancelled(
+                server_context):
+                pass
+            done_event.set()
+
+        rpc_callback = swiboe.RPC(callback)
+        self._ok(self.library.swiboe_new_rpc(
+            serving_client, 'test.test', 100, rpc_callback))
+
+        client = self._checked_connect()
+        client_context = swiboe.PtrClientContext()
+        self._ok(self.library.swiboe_client_call_rpc(
+            client, 'test.test', 'null', byref(client_context)))
+
+        json_str = c_char_p()
+        self.assertEqual(swiboe.ERR_TIMEOUT,
+                         self.library.swiboe_client_context_recv_timeout(
+                             client_context, 50, byref(json_str)))
+        self.library.swiboe_client_context_delete(client_context)
+
+        done_event.wait()
+        self._ok(self.library.swiboe_disconnect(client))
+        self._ok(self.library.swiboe_disconnect(serving_client))
+
 
 def flatten_test_suite(suite):
     flatten = unittest.TestSuite()
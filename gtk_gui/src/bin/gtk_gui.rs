@@ -144,28 +144,36 @@ impl SwiboeGtkGui {
                             });
                         },
                         "Up" => {
-                            thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
+                            if let Ok(rpc) = thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
                                 cursor_id: cursor_id.clone(),
                                 delta: buffer_views::Position { line_index: -1, column_index: 0, },
-                            });
+                            }) {
+                                rpc.detach();
+                            }
                         },
                         "Down" => {
-                            thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
+                            if let Ok(rpc) = thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
                                 cursor_id: cursor_id.clone(),
                                 delta: buffer_views::Position { line_index: 1, column_index: 0, },
-                            });
+                            }) {
+                                rpc.detach();
+                            }
                         }
                         "Left" => {
-                            thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
+                            if let Ok(rpc) = thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
                                 cursor_id: cursor_id.clone(),
                                 delta: buffer_views::Position { line_index: 0, column_index: -1, },
-                            });
+                            }) {
+                                rpc.detach();
+                            }
                         },
                         "Right" => {
-                            thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
+                            if let Ok(rpc) = thin_client.call("gui.buffer_view.move_cursor", &buffer_views::MoveCursorRequest {
                                 cursor_id: cursor_id.clone(),
                                 delta: buffer_views::Position { line_index: 0, column_index: 1, },
-                            });
+                            }) {
+                                rpc.detach();
+                            }
                         },
                         _ => (),
                     // // if let Some(button) = shortcuts.get(&name_str) {
@@ -28,12 +28,13 @@ impl From<BufferViewError> for rpc::Error {
      fn from(error: BufferViewError) -> Self {
          use swiboe::rpc::ErrorKind::*;
 
-         let (kind, details) = match error {
-             BufferViewError::UnknownCursor => (InvalidArgs, format!("unknown_cursor")),
+         let (kind, message, details) = match error {
+             BufferViewError::UnknownCursor => (NotFound, "Unknown cursor.", "unknown_cursor"),
          };
 
          rpc::Error {
              kind: kind,
+             message: Some(message.into()),
              details: Some(serde_json::to_value(&details)),
          }
      }
@@ -0,0 +1,44 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+#![cfg(not(test))]
+
+#[macro_use]
+extern crate clap;
+extern crate swiboe;
+
+use std::path::Path;
+use std::process;
+use swiboe::testing::replay;
+
+fn main() {
+    let matches = clap::App::new("replay")
+        .about("Replays a recorded session against a fresh server and reports where the server \
+                answers differently.")
+        .version(&crate_version!()[..])
+        .arg(clap::Arg::with_name("SESSION")
+             .help("The recorded session, i.e. a trace written by core.trace without filters.")
+             .required(true)
+             .index(1))
+        .get_matches();
+
+    let session = matches.value_of("SESSION").unwrap();
+    let divergences = match replay::replay_file(Path::new(session)) {
+        Ok(divergences) => divergences,
+        Err(err) => {
+            println!("Could not replay {}: {}", session, err);
+            process::exit(2);
+        },
+    };
+
+    if divergences.is_empty() {
+        println!("No divergences.");
+        return;
+    }
+    for divergence in &divergences {
+        print!("{}", divergence);
+    }
+    println!("{} divergences.", divergences.len());
+    process::exit(1);
+}
@@ -9,32 +9,133 @@ extern crate clap;
 extern crate swiboe;
 
 use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use swiboe::server::{config, signals};
+
+// Parses the 'value' given for 'flag' or exits with an error message.
+fn parse_number<T: FromStr>(flag: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        println!("{} must be a number, got '{}'.", flag, value);
+        process::exit(1);
+    })
+}
 
 fn main() {
     let matches = clap::App::new("server")
         .about("Swiboe stand alone server.")
         .version(&crate_version!()[..])
+        .arg(clap::Arg::with_name("CONFIG")
+             .short("c")
+             .long("config")
+             .help("JSON configuration file. Values given on the command line take precedence.")
+             .takes_value(true))
         .arg(clap::Arg::with_name("SOCKET")
              .short("s")
              .long("socket")
-             .help("Socket address on which to listen.")
-             .required(true)
+             .help("Socket address on which to listen. Can be given multiple times.")
+             .multiple(true)
              .takes_value(true))
         .arg(clap::Arg::with_name("LISTEN")
              .short("l")
              .long("listen")
              .help("IP address to listen on, e.g. 0.0.0.0:12345 to listen on all network \
-                   interfaces.")
+                   interfaces. Can be given multiple times.")
+             .multiple(true)
+             .takes_value(true))
+        .arg(clap::Arg::with_name("PLUGINS")
+             .short("p")
+             .long("plugins")
+             .help("Directory containing plugin manifests. The described plugins are started and \
+                   supervised by the server.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("PIDFILE")
+             .long("pidfile")
+             .help("Write the process id to this file while the server is running.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("HANDOVER_SOCKET")
+             .long("handover_socket")
+             .help("Socket through which a new server process can take over all clients.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("TAKEOVER")
+             .long("takeover")
+             .help("Take over the clients of the server listening on the handover socket instead \
+                   of opening new sockets. The old server exits afterwards."))
+        .arg(clap::Arg::with_name("IPC_THREADS")
+             .long("ipc_threads")
+             .help("Number of threads used for socket IO.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("RPC_TIMEOUT_MS")
+             .long("rpc_timeout_ms")
+             .help("RPCs running for longer than this are answered with a timeout error.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("LOG_LEVEL")
+             .long("log_level")
+             .help("Minimum level of log messages: debug, info, warn or error.")
+             .takes_value(true))
+        .arg(clap::Arg::with_name("LOG_FILE")
+             .long("log_file")
+             .help("Write log messages to this file instead of stdout.")
              .takes_value(true))
         .get_matches();
 
-    let path = Path::new(matches.value_of("SOCKET").unwrap());
-    let ips = if let Some(addr) = matches.value_of("LISTEN") {
-        vec![addr.into()]
-    } else {
-        Vec::new()
+    let mut config = match matches.value_of("CONFIG") {
+        Some(file) => config::Config::from_file(Path::new(file)).unwrap_or_else(|err| {
+            println!("Could not read config file {}: {}", file, err);
+            process::exit(1);
+        }),
+        None => config::Config::default(),
     };
 
-    let mut server = swiboe::server::Server::launch(path, &ips).unwrap();
+    if let Some(sockets) = matches.values_of("SOCKET") {
+        config.unix_sockets = Some(sockets.iter().map(|socket| socket.to_string()).collect());
+    }
+    if let Some(addresses) = matches.values_of("LISTEN") {
+        config.tcp_addresses = Some(addresses.iter().map(|addr| addr.to_string()).collect());
+    }
+    if let Some(directory) = matches.value_of("PLUGINS") {
+        config.plugin_directory = Some(directory.into());
+    }
+    if let Some(pidfile) = matches.value_of("PIDFILE") {
+        config.pidfile = Some(pidfile.into());
+    }
+    if let Some(handover_socket) = matches.value_of("HANDOVER_SOCKET") {
+        config.handover_socket = Some(handover_socket.into());
+    }
+    if let Some(threads) = matches.value_of("IPC_THREADS") {
+        config.ipc_threads = Some(parse_number("--ipc_threads", threads));
+    }
+    if let Some(timeout) = matches.value_of("RPC_TIMEOUT_MS") {
+        config.rpc_timeout_ms = Some(parse_number("--rpc_timeout_ms", timeout));
+    }
+    {
+        let mut log = config.log.take().unwrap_or(config::LogConfig::default());
+        if let Some(level) = matches.value_of("LOG_LEVEL") {
+            log.level = Some(level.into());
+        }
+        if let Some(file) = matches.value_of("LOG_FILE") {
+            log.file = Some(file.into());
+        }
+        config.log = Some(log);
+    }
+
+    if let Err(err) = config.validate() {
+        println!("Invalid configuration: {}", err);
+        process::exit(1);
+    }
+
+    // Must happen before the server spawns its threads, so that only our signal thread sees them.
+    let termination_signals = signals::block_termination_signals();
+
+    let server = if matches.is_present("TAKEOVER") {
+        swiboe::server::Server::take_over(&config)
+    } else {
+        swiboe::server::Server::launch_with_config(&config)
+    };
+    let mut server = server.unwrap_or_else(|err| {
+        println!("Could not start server: {}", err);
+        process::exit(1);
+    });
+    signals::shutdown_on_signal(termination_signals, server.shutdown_handle());
     server.wait_for_shutdown();
 }
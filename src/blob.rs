@@ -0,0 +1,170 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Large payloads for clients on the same host as the sender. The data is put into a POSIX
+//! shared memory segment and only the 'Blob' referring to it travels through the messages, so it
+//! is not copied through the socket to the server and on to the receiver.
+//!
+//! Clients on the same host as the server send successful results of at least MIN_RESULT_BYTES
+//! this way. The server passes the Blob on to the caller, whose client takes the result out
+//! before handing it to the caller. For callers connected through TCP, the server takes it out
+//! and sends the result itself.
+//!
+//! Taking the data out removes the segment. Since the receiver might never do that, the client
+//! that made the Blob removes it after TTL_SECS as well. A segment still survives a client that
+//! exits before that, until the machine restarts.
+//!
+//! Only segments with names like the ones 'create' makes are read or removed, so a Blob received
+//! from somewhere else cannot be used to remove other segments.
+
+use ::error::{Error, Result};
+use libc;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+use uuid::Uuid;
+
+/// Successful results whose JSON has at least this many bytes are sent as a Blob.
+pub const MIN_RESULT_BYTES: usize = 64 * 1024;
+
+/// How long a receiver has to take the Blob of a result. Afterwards it is removed.
+pub const TTL_SECS: u64 = 60;
+
+const NAME_PREFIX: &'static str = "/swiboe-";
+
+// Characters of the uuid in a name. Some systems allow only 31 characters.
+const NAME_ID_LEN: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Blob {
+    /// The name of the segment, see shm_open(3).
+    pub name: String,
+    pub len: usize,
+}
+
+fn last_error() -> Error {
+    Error::Io(io::Error::last_os_error())
+}
+
+// An open segment, closed when dropped.
+struct Segment {
+    fd: libc::c_int,
+}
+
+impl Segment {
+    fn open(name: &CString, flags: libc::c_int) -> Result<Self> {
+        let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+        if fd < 0 {
+            return Err(last_error());
+        }
+        Ok(Segment {
+            fd: fd,
+        })
+    }
+
+    fn size(&self) -> Result<u64> {
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        if unsafe { libc::fstat(self.fd, &mut stat) } < 0 {
+            return Err(last_error());
+        }
+        Ok(stat.st_size as u64)
+    }
+
+    // Maps 'len' bytes of the segment and hands them to 'f'. Nothing is mapped for 'len' 0.
+    fn with_mapping<F: FnOnce(*mut u8)>(&self, len: usize, protection: libc::c_int, f: F) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        unsafe {
+            let address = libc::mmap(ptr::null_mut(), len, protection, libc::MAP_SHARED, self.fd, 0);
+            if address == libc::MAP_FAILED {
+                return Err(last_error());
+            }
+            f(address as *mut u8);
+            libc::munmap(address, len);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Checks that 'name' is one 'Blob::create' could have made.
+fn c_name(name: &str) -> Result<CString> {
+    let is_blob_name = name.starts_with(NAME_PREFIX) && name.len() == NAME_PREFIX.len() + NAME_ID_LEN &&
+        name[NAME_PREFIX.len()..].chars().all(|c| c.is_digit(16) && !c.is_uppercase());
+    if !is_blob_name {
+        return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput, format!("'{}' is not the name of a Blob.", name))));
+    }
+    Ok(CString::new(name).unwrap())
+}
+
+impl Blob {
+    /// Copies 'data' into a new segment.
+    pub fn create(data: &[u8]) -> Result<Self> {
+        let name = format!("{}{}", NAME_PREFIX, &Uuid::new_v4().to_simple_string()[..NAME_ID_LEN]);
+        let c_name = try!(c_name(&name));
+        let segment = try!(Segment::open(&c_name, libc::O_CREAT | libc::O_EXCL | libc::O_RDWR));
+
+        let written = if unsafe { libc::ftruncate(segment.fd, data.len() as libc::off_t) } < 0 {
+            Err(last_error())
+        } else {
+            segment.with_mapping(data.len(), libc::PROT_READ | libc::PROT_WRITE, |address| unsafe {
+                ptr::copy_nonoverlapping(data.as_ptr(), address, data.len());
+            })
+        };
+        if let Err(err) = written {
+            unsafe {
+                libc::shm_unlink(c_name.as_ptr());
+            }
+            return Err(err);
+        }
+
+        Ok(Blob {
+            name: name,
+            len: data.len(),
+        })
+    }
+
+    /// Reads the data and removes the segment, so a Blob can only be taken once. Fails if the
+    /// segment has fewer than 'len' bytes. It might have more, some systems round the size up to
+    /// whole pages.
+    pub fn take(&self) -> Result<Vec<u8>> {
+        let c_name = try!(c_name(&self.name));
+        let segment = try!(Segment::open(&c_name, libc::O_RDONLY));
+        try!(self.discard());
+
+        // Reading beyond the end of the segment would kill us with SIGBUS.
+        let size = try!(segment.size());
+        if size < self.len as u64 {
+            return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("The Blob has only {} bytes, not {}.", size, self.len))));
+        }
+
+        let mut data = Vec::with_capacity(self.len);
+        try!(segment.with_mapping(self.len, libc::PROT_READ, |address| unsafe {
+            ptr::copy_nonoverlapping(address, data.as_mut_ptr(), self.len);
+            data.set_len(self.len);
+        }));
+        Ok(data)
+    }
+
+    /// Removes the segment without reading it.
+    pub fn discard(&self) -> Result<()> {
+        let c_name = try!(c_name(&self.name));
+        if unsafe { libc::shm_unlink(c_name.as_ptr()) } < 0 {
+            return Err(last_error());
+        }
+        Ok(())
+    }
+}
@@ -4,24 +4,54 @@
 
 #![allow(deprecated)]
 
-use ::error::Result;
+use ::error::{Error, Result};
 use ::ipc;
+use ::rpc::RpcDefinition;
 
 // NOCOM such class/module should be pulled out
 //       server and client should not depend each other
-use ::server::plugin_core::NewRpcRequest;
+use ::server::plugin_core::{Coalesce, HandshakeRequest, NewRpcRequest, SubscribeRequest, ThreadPoolRequest};
+pub use ::server::config::Heartbeat;
 
 use serde;
+use serde_json;
 use std::io;
 use std::net::{self, TcpStream};
 use std::path;
-use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use time;
 use unix_socket::UnixStream;
 
+/// Number of threads running the calls of RPCs with 'rpc::server::Execution::Concurrent'. Their
+/// calls run one after the other unless 'Client::set_thread_pool_size' asks for more.
+pub const DEFAULT_THREAD_POOL_SIZE: usize = 1;
+
+/// Changes of the connection to the server. See 'Client::connection_events'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection was lost. All calls that were still running failed with
+    /// Error::Disconnected and all RPCs served by this client were cancelled.
+    Disconnected,
+    /// A reconnecting client connected again and registered its RPCs anew.
+    Reconnected,
+}
+
 /// An abstraction that can call remove RPCs.
 pub trait RpcCaller {
     fn call<T: serde::Serialize>(&mut self, function: &str, args: &T) -> Result<::client::rpc::client::Context>;
+
+    /// Like 'call', but returns a Future for the result. It is completed by the client's read
+    /// thread, nothing needs to poll for it.
+    fn call_async<T: serde::Serialize>(&mut self, function: &str, args: &T) -> Result<::client::rpc::client::CallFuture> {
+        Ok(try!(self.call(function, args)).future())
+    }
+
+    /// Calls the RPC 'D'. The result is parsed into its response type.
+    fn call_typed<D: RpcDefinition>(&mut self, request: &D::Request) -> Result<::client::rpc::typed::Call<D>> {
+        Ok(::client::rpc::typed::Call::new(try!(self.call(D::name(), request))))
+    }
 }
 
 /// A client maintains a connection to a Swiboe server. It can also serve RPCs that can only be
@@ -41,6 +71,15 @@ pub struct Client {
     // Function to bring down the connection used for IO. The 'read_thread' and 'write_thread' will
     // both error then and terminate.
     shutdown_socket_func: Box<Fn() -> ()>,
+
+    // Cleared once the connection to the server is lost.
+    connected: Arc<AtomicBool>,
+
+    // Given to the Context of every call. See 'set_default_timeout'.
+    default_timeout: Option<time::Duration>,
+
+    // See 'is_same_host'.
+    same_host: bool,
 }
 
 
@@ -51,74 +90,273 @@ impl Client {
         let shutdown_stream = try!(writer_stream.try_clone());
         Ok(Client::common_connect(reader_stream, writer_stream, Box::new(move || {
             let _ = shutdown_stream.shutdown(net::Shutdown::Read);
-        })))
+        }), true))
+    }
+
+    /// Like 'connect_unix', but when the connection is lost, the client keeps trying to connect
+    /// again, e.g. to a restarted server. After reconnecting it repeats its 'handshake' and
+    /// registers all its RPCs again. Use 'connection_events' to learn about this.
+    pub fn connect_unix_reconnecting(socket_name: &path::Path) -> Result<Self> {
+        let socket_name = socket_name.to_path_buf();
+        Client::reconnecting(true, Box::new(move || {
+            let writer_stream = try!(UnixStream::connect(&socket_name));
+            let reader_stream = try!(writer_stream.try_clone());
+            let shutdown_stream = try!(writer_stream.try_clone());
+            Ok(reconnect::Connection {
+                reader: Box::new(reader_stream),
+                writer: Box::new(writer_stream),
+                shutdown: Box::new(move || {
+                    let _ = shutdown_stream.shutdown(net::Shutdown::Both);
+                }),
+            })
+        }))
+    }
+
+    /// Like 'connect_tcp', but reconnects. See 'connect_unix_reconnecting'.
+    pub fn connect_tcp_reconnecting(address: &net::SocketAddr) -> Result<Self> {
+        let address = address.clone();
+        Client::reconnecting(false, Box::new(move || {
+            let writer_stream = try!(TcpStream::connect(&address));
+            let reader_stream = try!(writer_stream.try_clone());
+            let shutdown_stream = try!(writer_stream.try_clone());
+            Ok(reconnect::Connection {
+                reader: Box::new(reader_stream),
+                writer: Box::new(writer_stream),
+                shutdown: Box::new(move || {
+                    let _ = shutdown_stream.shutdown(net::Shutdown::Both);
+                }),
+            })
+        }))
     }
 
     pub fn connect_tcp(address: &net::SocketAddr) -> Result<Self> {
         let writer_stream = try!(TcpStream::connect(address));
         let reader_stream = try!(writer_stream.try_clone());
         let shutdown_stream = try!(writer_stream.try_clone());
+        // The server might be anywhere.
         Ok(Client::common_connect(reader_stream, writer_stream, Box::new(move || {
             let _ = shutdown_stream.shutdown(net::Shutdown::Read);
-        })))
+        }), false))
     }
 
-    fn common_connect<Reader: io::Read + Send + 'static, Writer: io::Write + Send + 'static>(reader_stream: Reader, writer_stream: Writer, shutdown_func: Box<Fn() -> ()>) -> Self {
+    /// Connects a client that talks to the server through channels instead of a socket. This is
+    /// how the server connects its built-in plugins, see 'Server::connect_in_process'.
+    #[doc(hidden)]
+    pub fn connect_in_process(incoming: mpsc::Receiver<ipc::Message>,
+                              outgoing: Box<FnMut(ipc::Message) -> Result<()> + Send>,
+                              shutdown_func: Box<Fn() -> ()>) -> Self {
+        Client::spawn(Box::new(move || Ok(rpc_loop::Command::Received(try!(incoming.recv())))),
+                      outgoing, shutdown_func, None, true)
+    }
+
+    fn reconnecting(same_host: bool, connector: reconnect::Connector) -> Result<Self> {
+        let transport = try!(reconnect::connect(connector));
+        Ok(Client::spawn(transport.read_func,
+                         transport.write_func,
+                         transport.shutdown_func,
+                         Some(transport.drop_connection_func),
+                         same_host))
+    }
+
+    fn common_connect<Reader: io::Read + Send + 'static, Writer: io::Write + Send + 'static>(reader_stream: Reader, writer_stream: Writer, shutdown_func: Box<Fn() -> ()>, same_host: bool) -> Self {
+        let mut reader = ipc::Reader::new(reader_stream);
+        let mut writer = ipc::Writer::new(writer_stream);
+        Client::spawn(Box::new(move || Ok(rpc_loop::Command::Received(try!(reader.read_message())))),
+                      Box::new(move |message| writer.write_message(&message)),
+                      shutdown_func,
+                      None,
+                      same_host)
+    }
+
+    // 'read_func' produces the commands for the rpc loop, usually 'Received'. 'drop_connection'
+    // is called if the server stops answering pings; without it, the client gives up instead.
+    fn spawn(mut read_func: Box<FnMut() -> Result<rpc_loop::Command> + Send>,
+             mut write_func: Box<FnMut(ipc::Message) -> Result<()> + Send>,
+             shutdown_func: Box<Fn() -> ()>,
+             drop_connection: Option<Box<Fn() + Send>>,
+             same_host: bool) -> Self {
         let (commands_tx, commands_rx) = mpsc::channel();
         let (send_tx, send_rx) = mpsc::channel::<ipc::Message>();
+        let connected = Arc::new(AtomicBool::new(true));
 
         let reader_commands_tx = commands_tx.clone();
+        let reader_connected = connected.clone();
         let read_thread = thread::spawn(move || {
-            let mut reader = ipc::Reader::new(reader_stream);
-            while let Ok(message) = reader.read_message() {
-                let command = rpc_loop::Command::Received(message);
+            while let Ok(command) = read_func() {
                 if reader_commands_tx.send(command).is_err() {
                     break;
                 }
             };
+            reader_connected.store(false, Ordering::SeqCst);
+            let _ = reader_commands_tx.send(rpc_loop::Command::Disconnected);
         });
 
         let write_thread = thread::spawn(move || {
-            let mut writer = ipc::Writer::new(writer_stream);
             while let Ok(message) = send_rx.recv() {
-                writer.write_message(&message).expect("Writing failed");
+                if write_func(message).is_err() {
+                    break;
+                }
             }
         });
 
         Client {
             rpc_loop_commands: commands_tx.clone(),
-            rpc_loop_thread: Some(rpc_loop::spawn(commands_rx, commands_tx, send_tx)),
+            rpc_loop_thread: Some(rpc_loop::spawn(
+                    commands_rx, commands_tx, send_tx, connected.clone(),
+                    drop_connection, same_host)),
             read_thread: Some(read_thread),
             write_thread: Some(write_thread),
             shutdown_socket_func: shutdown_func,
+            connected: connected,
+            default_timeout: None,
+            same_host: same_host,
+        }
+    }
+
+    /// False once the connection to the server was closed or the server stopped answering pings.
+    /// All calls fail with Error::Disconnected then. A reconnecting client is connected again
+    /// once it reconnected.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// True if the client is connected through a Unix socket or in-process, so that it shares
+    /// large results with the server through shared memory, see 'blob'. Clients connected
+    /// through TCP might not be on the same host.
+    pub fn is_same_host(&self) -> bool {
+        self.same_host
+    }
+
+    /// Returns a channel that receives every change of the connection from now on.
+    pub fn connection_events(&mut self) -> Result<mpsc::Receiver<ConnectionEvent>> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::WatchConnection(tx)));
+        Ok(rx)
+    }
+
+    /// Returns a channel that receives every notification whose topic starts with 'prefix', see
+    /// 'notify'.
+    pub fn subscribe(&mut self, prefix: &str) -> Result<mpsc::Receiver<::rpc::Notification>> {
+        self.subscribe_with(prefix, None)
+    }
+
+    /// Like 'subscribe', but the server holds notifications back and thins them out as described
+    /// by 'coalesce', see 'server::subscriptions'. Meant for subscribers that cannot keep up with
+    /// every single event. All subscriptions of this client to 'prefix' are coalesced then.
+    pub fn subscribe_coalesced(&mut self, prefix: &str, coalesce: Coalesce) -> Result<mpsc::Receiver<::rpc::Notification>> {
+        self.subscribe_with(prefix, Some(coalesce))
+    }
+
+    fn subscribe_with(&mut self, prefix: &str, coalesce: Option<Coalesce>) -> Result<mpsc::Receiver<::rpc::Notification>> {
+        // Registered before asking the server, so that no notification sent right after the
+        // subscription is missed.
+        let (tx, rx) = mpsc::channel();
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::Subscribe(prefix.into(), coalesce.clone(), tx)));
+        let result = self.core_call("core.subscribe", &SubscribeRequest {
+            prefix: prefix.into(),
+            coalesce: coalesce,
+        }).and_then(|mut subscribe| subscribe.wait()).and_then(core_result);
+        if let Err(err) = result {
+            let _ = self.rpc_loop_commands.send(rpc_loop::Command::Unsubscribe(prefix.into()));
+            return Err(err);
+        }
+        Ok(rx)
+    }
+
+    /// Ends all subscriptions to 'prefix'. Their channels do not receive anything anymore.
+    pub fn unsubscribe(&mut self, prefix: &str) -> Result<()> {
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::Unsubscribe(prefix.into())));
+        let mut unsubscribe = try!(self.core_call("core.unsubscribe", &SubscribeRequest {
+            prefix: prefix.into(),
+            coalesce: None,
+        }));
+        core_result(try!(unsubscribe.wait()))
+    }
+
+    /// Sends a notification to all subscribers of 'topic'. Nobody answers it, so it is cheaper
+    /// than a call for events nobody might listen to.
+    pub fn notify<T: serde::Serialize>(&mut self, topic: &str, args: &T) -> Result<()> {
+        send_notification(&self.rpc_loop_commands, topic, args)
+    }
+
+    /// Changes how the server is pinged. None, the default, turns pinging off. An in-process
+    /// client does not need it, the server cannot go away under it.
+    pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) -> Result<()> {
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::SetHeartbeat(heartbeat)));
+        Ok(())
+    }
+
+    /// Changes the number of threads running the calls of RPCs with
+    /// 'rpc::server::Execution::Concurrent'. Calls that are already running are not affected.
+    /// The server is told as well: only with a single thread it can tell that a call into this
+    /// client would wait for a handler that waits for the call.
+    pub fn set_thread_pool_size(&mut self, size: usize) -> Result<()> {
+        if size == 0 {
+            return Err(Error::InvalidConfig("The thread pool needs at least one thread.".into()));
         }
+        let mut announce = try!(self.core_call("core.set_thread_pool_size", &ThreadPoolRequest {
+            size: size,
+        }));
+        try!(core_result(try!(announce.wait())));
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::SetThreadPoolSize(size)));
+        Ok(())
+    }
+
+    /// Makes 'recv' and 'wait' of all calls started from now on give up after 'timeout', see
+    /// 'rpc::client::Context::set_timeout'. ThinClients inherit the timeout when they are
+    /// created. None blocks forever, which is the default. The 'core.' calls the client makes by
+    /// itself, e.g. in 'new_rpc' or 'subscribe', always wait for their answer.
+    pub fn set_default_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.default_timeout = timeout;
+    }
+
+    /// Registers a handler for the RPC of its 'Definition'.
+    pub fn new_typed_rpc<R: rpc::typed::Rpc + 'static>(&mut self, rpc: R) -> Result<()> {
+        self.new_rpc(<R::Definition as RpcDefinition>::name(), Box::new(rpc::typed::Handler(rpc)))
     }
 
     pub fn new_rpc(&mut self, name: &str, rpc: Box<rpc::server::Rpc>) -> Result<()> {
-        let mut new_rpc = try!(self.call("core.new_rpc", &NewRpcRequest {
+        let mut new_rpc = try!(self.core_call("core.new_rpc", &NewRpcRequest {
             priority: rpc.priority(),
             name: name.into(),
+            execution: Some(rpc.execution()),
         }));
-        let result = new_rpc.wait();
-
-        if !result.is_ok() {
-            return Err(result.unwrap_err().into());
-        }
+        try!(core_result(try!(new_rpc.wait())));
 
         self.rpc_loop_commands.send(rpc_loop::Command::NewRpc(name.into(), rpc)).expect("NewRpc");
         Ok(())
     }
 
+    /// Tells the server who we are. The server's permission rules can refer to this name.
+    pub fn handshake(&mut self, name: &str) -> Result<()> {
+        let mut handshake = try!(self.core_call("core.handshake", &HandshakeRequest {
+            name: name.into(),
+        }));
+        try!(core_result(try!(handshake.wait())));
+        try!(self.rpc_loop_commands.send(rpc_loop::Command::SetName(name.into())));
+        Ok(())
+    }
+
+    // Calls into the server for the client itself. The default timeout is not applied: giving up
+    // on them would leave the client and the server disagreeing about its state.
+    fn core_call<T: serde::Serialize>(&mut self, function: &str, args: &T) -> Result<rpc::client::Context> {
+        rpc::client::Context::new(self.rpc_loop_commands.clone(), function, args)
+    }
+
     pub fn clone(&self) -> Result<ThinClient> {
         Ok(ThinClient {
             rpc_loop_commands: Mutex::new(self.rpc_loop_commands.clone()),
+            default_timeout: self.default_timeout,
+            same_host: self.same_host,
         })
     }
 }
 
 impl RpcCaller for Client {
     fn call<T: serde::Serialize>(&mut self, function: &str, args: &T) -> Result<rpc::client::Context> {
-        rpc::client::Context::new(self.rpc_loop_commands.clone(), function, args)
+        let mut context = try!(rpc::client::Context::new(self.rpc_loop_commands.clone(), function, args));
+        context.set_timeout(self.default_timeout);
+        Ok(context)
     }
 }
 
@@ -141,13 +379,50 @@ impl Drop for Client {
     }
 }
 
+fn send_notification<T: serde::Serialize>(rpc_loop_commands: &rpc_loop::CommandSender, topic: &str, args: &T) -> Result<()> {
+    let message = ipc::Message::Notification(::rpc::Notification {
+        topic: topic.into(),
+        args: serde_json::to_value(args),
+    });
+    try!(rpc_loop_commands.send(rpc_loop::Command::Send(message)));
+    Ok(())
+}
+
+// Turns the result of a 'core.' call that does not return anything into an Error if it failed.
+// Errors of the server, like PermissionDenied, are passed on as they are.
+fn core_result(result: ::rpc::Result) -> Result<()> {
+    match result {
+        ::rpc::Result::Ok(_) => Ok(()),
+        ::rpc::Result::Err(err) => Err(Error::Rpc(err)),
+        other => Err(Error::Io(io::Error::new(io::ErrorKind::Other, format!("{:?}", other)))),
+    }
+}
+
 /// A ThinClient is an RpcCaller, but does not maintain and cannot register new RPCs. It can
 /// be cloned, so that many threads can do RPCs in parallel.
 pub struct ThinClient {
     rpc_loop_commands: Mutex<rpc_loop::CommandSender>,
+    default_timeout: Option<time::Duration>,
+    same_host: bool,
 }
 
 impl ThinClient {
+    /// See 'Client::is_same_host'.
+    pub fn is_same_host(&self) -> bool {
+        self.same_host
+    }
+
+    /// See 'Client::notify'.
+    pub fn notify<T: serde::Serialize>(&mut self, topic: &str, args: &T) -> Result<()> {
+        let commands = self.rpc_loop_commands.lock().unwrap();
+        send_notification(&commands, topic, args)
+    }
+
+    /// See 'Client::set_default_timeout'.
+    pub fn set_default_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.default_timeout = timeout;
+    }
+
     pub fn clone(&self) -> Self {
         let commands = {
             let commands = self.rpc_loop_commands.lock().unwrap();
@@ -155,6 +430,8 @@ impl ThinClient {
         };
         ThinClient {
             rpc_loop_commands: Mutex::new(commands),
+            default_timeout: self.default_timeout,
+            same_host: self.same_host,
         }
     }
 }
@@ -165,11 +442,14 @@ impl RpcCaller for ThinClient {
             let commands = self.rpc_loop_commands.lock().unwrap();
             commands.clone()
         };
-        rpc::client::Context::new(commands, function, args)
+        let mut context = try!(rpc::client::Context::new(commands, function, args));
+        context.set_timeout(self.default_timeout);
+        Ok(context)
     }
 }
 
 
+mod reconnect;
 mod rpc_loop;
 
 pub mod rpc;
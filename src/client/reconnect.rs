@@ -0,0 +1,142 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! The IO side of a reconnecting client. The read function owns the connection: when reading
+//! fails, it reports the disconnect to the rpc loop, connects again with exponential backoff and
+//! reports the new connection. The write function always writes to the current connection and
+//! drops messages while there is none.
+
+use ::client::rpc_loop;
+use ::error::{Error, Result};
+use ::ipc;
+use std::cmp;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const FIRST_RETRY_MS: u64 = 50;
+const MAX_RETRY_MS: u64 = 5000;
+// Sleeping is done in slices of this, so that shutting down does not wait for a whole retry
+// interval.
+const SLEEP_SLICE_MS: u64 = 50;
+
+pub struct Connection {
+    pub reader: Box<io::Read + Send>,
+    pub writer: Box<io::Write + Send>,
+    pub shutdown: Box<Fn() + Send>,
+}
+
+pub type Connector = Box<Fn() -> Result<Connection> + Send>;
+
+struct Shared {
+    writer: Mutex<Option<ipc::Writer<Box<io::Write + Send>>>>,
+    shutdown: Mutex<Option<Box<Fn() + Send>>>,
+    quit: AtomicBool,
+}
+
+impl Shared {
+    fn drop_connection(&self) {
+        *self.writer.lock().unwrap() = None;
+        if let Some(shutdown) = self.shutdown.lock().unwrap().take() {
+            shutdown();
+        }
+    }
+}
+
+pub struct Transport {
+    pub read_func: Box<FnMut() -> Result<rpc_loop::Command> + Send>,
+    pub write_func: Box<FnMut(ipc::Message) -> Result<()> + Send>,
+    /// Ends the read thread for good.
+    pub shutdown_func: Box<Fn() -> ()>,
+    /// Closes the current connection, so that a new one is made.
+    pub drop_connection_func: Box<Fn() + Send>,
+}
+
+/// Connects using 'connector'. Only this first connection attempt can fail, later ones are
+/// retried until the client is dropped.
+pub fn connect(connector: Connector) -> Result<Transport> {
+    let connection = try!(connector());
+    let shared = Arc::new(Shared {
+        writer: Mutex::new(Some(ipc::Writer::new(connection.writer))),
+        shutdown: Mutex::new(Some(connection.shutdown)),
+        quit: AtomicBool::new(false),
+    });
+
+    let read_shared = shared.clone();
+    let mut reader = Some(ipc::Reader::new(connection.reader));
+    let mut retry_ms = FIRST_RETRY_MS;
+    let read_func = Box::new(move || {
+        loop {
+            if read_shared.quit.load(Ordering::SeqCst) {
+                return Err(Error::Disconnected);
+            }
+
+            if reader.is_some() {
+                let result = reader.as_mut().unwrap().read_message();
+                match result {
+                    Ok(message) => return Ok(rpc_loop::Command::Received(message)),
+                    Err(_) => {
+                        reader = None;
+                        read_shared.drop_connection();
+                        if read_shared.quit.load(Ordering::SeqCst) {
+                            return Err(Error::Disconnected);
+                        }
+                        retry_ms = FIRST_RETRY_MS;
+                        return Ok(rpc_loop::Command::Disconnected);
+                    }
+                }
+            }
+
+            match connector() {
+                Ok(connection) => {
+                    *read_shared.writer.lock().unwrap() = Some(ipc::Writer::new(connection.writer));
+                    *read_shared.shutdown.lock().unwrap() = Some(connection.shutdown);
+                    reader = Some(ipc::Reader::new(connection.reader));
+                    return Ok(rpc_loop::Command::Reconnected);
+                },
+                Err(_) => {
+                    let mut slept = 0;
+                    while slept < retry_ms && !read_shared.quit.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(SLEEP_SLICE_MS));
+                        slept += SLEEP_SLICE_MS;
+                    }
+                    retry_ms = cmp::min(retry_ms * 2, MAX_RETRY_MS);
+                },
+            }
+        }
+    });
+
+    let write_shared = shared.clone();
+    let write_func = Box::new(move |message| {
+        let mut writer = write_shared.writer.lock().unwrap();
+        let failed = match *writer {
+            Some(ref mut writer) => writer.write_message(&message).is_err(),
+            // Nobody is waiting for this message anymore, the rpc loop failed all calls when the
+            // connection went away.
+            None => false,
+        };
+        if failed {
+            // The read function notices too and takes care of reconnecting.
+            *writer = None;
+        }
+        Ok(())
+    });
+
+    let shutdown_shared = shared.clone();
+    let shutdown_func = Box::new(move || {
+        shutdown_shared.quit.store(true, Ordering::SeqCst);
+        shutdown_shared.drop_connection();
+    });
+
+    let drop_connection_func = Box::new(move || shared.drop_connection());
+
+    Ok(Transport {
+        read_func: read_func,
+        write_func: write_func,
+        shutdown_func: shutdown_func,
+        drop_connection_func: drop_connection_func,
+    })
+}
@@ -4,43 +4,120 @@
 
 use ::client::rpc_loop::{Command, CommandSender};
 use ::error::{Error, Result};
+use futures::{Async, Future, Poll, Stream};
+use futures::task::{self, Task};
 use serde;
 use serde_json;
-use std::sync::mpsc;
+use std::sync::{Arc, Mutex, mpsc};
+use time;
 use uuid::Uuid;
 
+/// The task polling a Context as Future or Stream, if any.
+type Waiter = Arc<Mutex<Option<Task>>>;
+
+/// Used by the rpc loop to pass the responses of a call on. Wakes up a task polling the Context,
+/// also when it is dropped, since that ends the call.
+pub struct ResponseSender {
+    values: mpsc::Sender<::rpc::Response>,
+    waiter: Waiter,
+}
+
+impl ResponseSender {
+    pub fn send(&self, response: ::rpc::Response) -> Result<()> {
+        try!(self.values.send(response));
+        self.wake();
+        Ok(())
+    }
+
+    fn wake(&self) {
+        if let Some(ref task) = *self.waiter.lock().unwrap() {
+            task.notify();
+        }
+    }
+}
+
+impl Drop for ResponseSender {
+    fn drop(&mut self) {
+        self.wake();
+    }
+}
+
 pub struct Context {
     context: String,
     values: mpsc::Receiver<::rpc::Response>,
+    waiter: Waiter,
     result: Option<::rpc::Result>,
     commands: CommandSender,
+    // True until the last response arrived or the call was cancelled or detached. A running call
+    // is cancelled when the Context is dropped.
+    running: bool,
+    // Used by 'recv' and 'wait' if set.
+    timeout: Option<time::Duration>,
 }
 
 impl Context {
     pub fn new<T: serde::Serialize>(commands: CommandSender,
                          function: &str,
                          args: &T) -> Result<Self> {
+        Context::with_parent(commands, function, args, None)
+    }
+
+    /// Starts a call made from inside the RPC with context 'parent' and trace id 'trace_id'.
+    pub fn nested<T: serde::Serialize>(commands: CommandSender,
+                                       function: &str,
+                                       args: &T,
+                                       parent: &str,
+                                       trace_id: &str) -> Result<Self> {
+        Context::with_parent(commands, function, args, Some((parent, trace_id)))
+    }
+
+    fn with_parent<T: serde::Serialize>(commands: CommandSender,
+                                        function: &str,
+                                        args: &T,
+                                        parent: Option<(&str, &str)>) -> Result<Self> {
         let args = serde_json::to_value(&args);
         let context = Uuid::new_v4().to_hyphenated_string();
+        let trace_id = match parent {
+            Some((_, trace_id)) => trace_id.to_string(),
+            None => context.clone(),
+        };
         let message = ::ipc::Message::RpcCall(::rpc::Call {
             function: function.into(),
             context: context.clone(),
             args: args,
+            parent: parent.map(|(parent, _)| parent.to_string()),
+            trace_id: Some(trace_id),
         });
 
         let (tx, rx) = mpsc::channel();
-        // NOCOM(#sirver): this tx is only for cancelling. Maybe this can be avoided.
-        // NOCOM(#sirver): the next one should be done with try!
-        commands.send(Command::OutgoingCall(context.clone(), tx, message)).expect("Command::OutgoingCall");
-        // NOCOM(#sirver): implement drop so that we can cancel an RPC.
+        let waiter = Arc::new(Mutex::new(None));
+        let sender = ResponseSender {
+            values: tx,
+            waiter: waiter.clone(),
+        };
+        // Fails with Error::Disconnected once the rpc loop gave up on the server.
+        try!(commands.send(Command::OutgoingCall(context.clone(), sender, message)));
         Ok(Context {
             values: rx,
+            waiter: waiter,
             commands: commands,
             context: context,
             result: None,
+            running: true,
+            timeout: None,
         })
     }
 
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// Makes 'recv' and 'wait' give up after 'timeout' like 'recv_timeout' and 'wait_timeout'.
+    /// None blocks forever.
+    pub fn set_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.timeout = timeout;
+    }
+
     pub fn try_recv(&mut self) -> Result<Option<serde_json::Value>> {
         if self.result.is_some() {
             return Ok(None);
@@ -50,58 +127,217 @@ impl Context {
             Ok(value) => value,
             Err(err) => match err {
                 mpsc::TryRecvError::Empty => return Ok(None),
-                _ => return Err(Error::Disconnected),
+                _ => {
+                    self.running = false;
+                    return Err(Error::Disconnected);
+                },
             }
         };
+        Ok(self.handle_response(rpc_response))
+    }
 
-        match rpc_response.kind {
-            ::rpc::ResponseKind::Partial(value) => Ok(Some(value)),
-            ::rpc::ResponseKind::Last(result) => {
-                self.result = Some(result);
-                Ok(None)
-            },
+    pub fn recv(&mut self) -> Result<Option<serde_json::Value>> {
+        if let Some(timeout) = self.timeout {
+            return self.recv_timeout(timeout);
         }
+        if self.result.is_some() {
+            return Ok(None);
+        }
+
+        let rpc_response = match self.values.recv() {
+            Ok(value) => value,
+            Err(_) => {
+                self.running = false;
+                return Err(Error::Disconnected);
+            },
+        };
+        Ok(self.handle_response(rpc_response))
     }
 
-    // NOCOM(#sirver): timeout?
-    pub fn recv(&mut self) -> Result<Option<serde_json::Value>> {
+    /// Like 'recv', but gives up if nothing arrived within 'timeout'. The call is cancelled then
+    /// and Error::Timeout is returned.
+    pub fn recv_timeout(&mut self, timeout: time::Duration) -> Result<Option<serde_json::Value>> {
         if self.result.is_some() {
             return Ok(None);
         }
 
-        let rpc_response = try!(self.values.recv());
+        // A negative timeout is already over.
+        let timeout = timeout.to_std().unwrap_or(::std::time::Duration::from_millis(0));
+        let rpc_response = match self.values.recv_timeout(timeout) {
+            Ok(value) => value,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.running = false;
+                try!(self.commands.send(Command::CancelOutgoingRpc(self.context.clone())));
+                return Err(Error::Timeout);
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                self.running = false;
+                return Err(Error::Disconnected);
+            },
+        };
+        Ok(self.handle_response(rpc_response))
+    }
+
+    fn handle_response(&mut self, rpc_response: ::rpc::Response) -> Option<serde_json::Value> {
         match rpc_response.kind {
-            ::rpc::ResponseKind::Partial(value) => Ok(Some(value)),
+            ::rpc::ResponseKind::Partial(value) => Some(value),
             ::rpc::ResponseKind::Last(result) => {
                 self.result = Some(result);
-                Ok(None)
+                self.running = false;
+                None
             },
         }
     }
 
     // NOCOM(#sirver): should this not consume the context?
     pub fn wait(&mut self) -> Result<::rpc::Result> {
+        if let Some(timeout) = self.timeout {
+            return self.wait_timeout(timeout);
+        }
         while let Some(_) = try!(self.recv()) {
         }
         Ok(self.result.take().unwrap())
     }
 
+    /// Like 'wait', but gives up if the call did not finish within 'timeout'. The call is
+    /// cancelled then and Error::Timeout is returned.
+    pub fn wait_timeout(&mut self, timeout: time::Duration) -> Result<::rpc::Result> {
+        let deadline = time::SteadyTime::now() + timeout;
+        while let Some(_) = try!(self.recv_timeout(deadline - time::SteadyTime::now())) {
+        }
+        Ok(self.result.take().unwrap())
+    }
+
+    /// Streams 'value' to the handler of the call, which receives it through
+    /// 'rpc::server::Context::recv_partial'.
+    pub fn send_partial<T: serde::Serialize>(&mut self, value: &T) -> Result<()> {
+        self.send_call_partial(Some(serde_json::to_value(value)))
+    }
+
+    /// Tells the handler that no more values will be streamed.
+    pub fn close_partials(&mut self) -> Result<()> {
+        self.send_call_partial(None)
+    }
+
+    fn send_call_partial(&mut self, value: Option<serde_json::Value>) -> Result<()> {
+        if !self.running {
+            return Err(Error::RpcDone);
+        }
+        let message = ::ipc::Message::RpcCallPartial(::rpc::CallPartial {
+            context: self.context.clone(),
+            value: value,
+        });
+        try!(self.commands.send(Command::Send(message)));
+        Ok(())
+    }
+
     // NOCOM(#sirver): this feels not useful, once wait() consumes the context.
     pub fn done(&self) -> bool {
         self.result.is_some()
     }
 
+    /// Waits for the result and deserializes it. Errors of the RPC are returned as Error::Rpc.
     pub fn wait_for<T: serde::Deserialize>(&mut self) -> Result<T> {
         match try!(self.wait()) {
             ::rpc::Result::Ok(value) => Ok(try!(serde_json::from_value(value))),
-            ::rpc::Result::Err(err) => panic!("#sirver err: {:#?}", err),
-            // NOCOM(#sirver): probably should ignore other errors.
-            other => panic!("#sirver other: {:#?}", other),
+            ::rpc::Result::Err(err) => Err(Error::Rpc(err)),
+            ::rpc::Result::NotHandled => Err(Error::Rpc(::rpc::Error::new(
+                        ::rpc::ErrorKind::UnknownRpc, "No handler handled the call."))),
         }
     }
 
-    pub fn cancel(self) -> Result<()> {
-        try!(self.commands.send(Command::CancelOutgoingRpc(self.context)));
+    pub fn cancel(mut self) -> Result<()> {
+        self.running = false;
+        try!(self.commands.send(Command::CancelOutgoingRpc(self.context.clone())));
         Ok(())
     }
+
+    /// Lets the call run to its end without anybody waiting for its result. Dropping the Context
+    /// would cancel it instead. A call made from inside an RPC is then no longer cancelled when
+    /// that RPC ends.
+    pub fn detach(mut self) {
+        if self.running {
+            // The rpc loop might be gone already, then the call is over anyways.
+            let _ = self.commands.send(Command::DetachOutgoingRpc(self.context.clone()));
+        }
+        self.running = false;
+    }
+
+    /// A Future for the result of the call. Partial results are skipped. Dropping the Future
+    /// cancels the call.
+    pub fn future(self) -> CallFuture {
+        CallFuture {
+            context: self,
+        }
+    }
+
+    /// A Stream of the partial results of the call. Dropping the Stream cancels the call.
+    pub fn stream(self) -> CallStream {
+        CallStream {
+            context: self,
+        }
+    }
+
+    // The next partial result, None once the call is done. The rpc loop wakes the current task
+    // when something arrives.
+    fn poll_partial(&mut self) -> Poll<Option<serde_json::Value>, Error> {
+        // Registering before looking makes sure that a response arriving in between wakes us.
+        *self.waiter.lock().unwrap() = Some(task::current());
+        if let Some(value) = try!(self.try_recv()) {
+            return Ok(Async::Ready(Some(value)));
+        }
+        if self.result.is_some() || !self.running {
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+/// See 'Context::future'.
+pub struct CallFuture {
+    context: Context,
+}
+
+impl Future for CallFuture {
+    type Item = ::rpc::Result;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<::rpc::Result, Error> {
+        while let Some(_) = try_ready!(self.context.poll_partial()) {
+        }
+        match self.context.result.take() {
+            Some(result) => Ok(Async::Ready(result)),
+            None => Err(Error::RpcDone),
+        }
+    }
+}
+
+/// See 'Context::stream'.
+pub struct CallStream {
+    context: Context,
+}
+
+impl CallStream {
+    /// The result of the call, once the Stream ended.
+    pub fn result(&mut self) -> Option<::rpc::Result> {
+        self.context.result.take()
+    }
+}
+
+impl Stream for CallStream {
+    type Item = serde_json::Value;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<serde_json::Value>, Error> {
+        self.context.poll_partial()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.running {
+            // The rpc loop might be gone already, then there is nobody left to tell.
+            let _ = self.commands.send(Command::CancelOutgoingRpc(self.context.clone()));
+        }
+    }
 }
@@ -7,7 +7,12 @@ use ::client::rpc_loop;
 use ::error::{Result, Error};
 use serde::Serialize;
 use serde_json;
-use std::sync::mpsc;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::panic;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
 
 #[derive(Clone, Debug, PartialEq)]
 enum ContextState {
@@ -18,43 +23,89 @@ enum ContextState {
 
 pub enum Command {
     Cancel,
+    // Streamed by the caller, see 'Context::recv_partial'.
+    Partial(serde_json::Value),
+    ClosePartials,
 }
 
+pub use ::server::plugin_core::Execution;
+
 pub trait Rpc: Send + Sync {
     fn priority(&self) -> u16 { u16::max_value() }
+    fn execution(&self) -> Execution { Execution::Concurrent }
     fn call(&self, context: Context, args: serde_json::Value);
 }
 
 pub struct Context {
     context: String,
+    // Passed on to calls made from this RPC, so that the server can link them.
+    trace_id: String,
     commands: mpsc::Receiver<Command>,
     rpc_loop_commands: rpc_loop::CommandSender,
     state: ContextState,
+    // Contexts of the calls made through 'call'. They are cancelled together with this RPC and
+    // once it finished. Shared with the rpc loop, which cancels them as soon as the cancel
+    // arrives.
+    children: Arc<Mutex<Vec<String>>>,
+    // Partial values streamed by the caller that were not yet received.
+    partials: VecDeque<serde_json::Value>,
+    partials_closed: bool,
 }
 
 impl Context {
-    pub fn new(context: String, commands: mpsc::Receiver<Command>,
-           rpc_loop_commands: rpc_loop::CommandSender) -> Self {
+    pub fn new(context: String, trace_id: String, commands: mpsc::Receiver<Command>,
+           rpc_loop_commands: rpc_loop::CommandSender, children: Arc<Mutex<Vec<String>>>) -> Self {
         Context {
             context: context,
+            trace_id: trace_id,
             commands: commands,
             rpc_loop_commands: rpc_loop_commands,
-            state: ContextState::Alive
+            state: ContextState::Alive,
+            children: children,
+            partials: VecDeque::new(),
+            partials_closed: false,
+        }
+    }
+
+    // Calls that already ended are ignored by the rpc loop.
+    fn cancel_children(&self) {
+        let children: Vec<_> = self.children.lock().unwrap().drain(..).collect();
+        for child in children {
+            // The rpc loop might be gone already, then there is nothing left to cancel.
+            let _ = self.rpc_loop_commands.send(rpc_loop::Command::CancelOutgoingRpc(child));
+        }
+    }
+
+    fn mark_cancelled(&mut self) {
+        if self.state == ContextState::Alive {
+            self.cancel_children();
+        }
+        self.state = ContextState::Cancelled;
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Cancel => self.mark_cancelled(),
+            Command::Partial(value) => self.partials.push_back(value),
+            Command::ClosePartials => self.partials_closed = true,
         }
     }
 
     fn update_state(&mut self) {
-        match self.commands.try_recv() {
-            Ok(value) => match value {
-                Command::Cancel => self.state = ContextState::Cancelled,
-            },
-            Err(err) => match err {
-                mpsc::TryRecvError::Empty => (),
-                mpsc::TryRecvError::Disconnected => {
-                    // The FunctionThread terminated - that means that the client must be shutting
-                    // down. That is like we are canceled.
-                    self.state = ContextState::Cancelled;
-                }
+        loop {
+            match self.commands.try_recv() {
+                Ok(command) => self.handle_command(command),
+                Err(err) => {
+                    match err {
+                        mpsc::TryRecvError::Empty => (),
+                        mpsc::TryRecvError::Disconnected => {
+                            // The FunctionThread terminated - that means that the client must be
+                            // shutting down. That is like we are canceled.
+                            self.mark_cancelled();
+                        }
+                    }
+                    break;
+                },
             }
         }
     }
@@ -78,6 +129,24 @@ impl Context {
         Ok(try!(self.rpc_loop_commands.send(rpc_loop::Command::Send(msg))))
     }
 
+    /// Blocks till the caller streamed the next value, see
+    /// 'rpc::client::Context::send_partial'. Returns None once the caller closed its side.
+    pub fn recv_partial(&mut self) -> Result<Option<serde_json::Value>> {
+        loop {
+            try!(self.check_liveness());
+            if let Some(value) = self.partials.pop_front() {
+                return Ok(Some(value));
+            }
+            if self.partials_closed {
+                return Ok(None);
+            }
+            match self.commands.recv() {
+                Ok(command) => self.handle_command(command),
+                Err(_) => self.mark_cancelled(),
+            }
+        }
+    }
+
     // NOCOM(#sirver): maybe call is_cancelled?
     pub fn cancelled(&mut self) -> bool {
         self.update_state();
@@ -89,6 +158,7 @@ impl Context {
         try!(self.check_liveness());
 
         self.state = ContextState::Finished;
+        self.cancel_children();
         let msg = ::ipc::Message::RpcResponse(::rpc::Response {
             context: self.context.clone(),
             kind: ::rpc::ResponseKind::Last(result),
@@ -100,15 +170,80 @@ impl Context {
 impl RpcCaller for Context {
     fn call<T: Serialize>(&mut self, function: &str, args: &T) -> Result<::client::rpc::client::Context> {
         try!(self.check_liveness());
-        Ok(try!(::client::rpc::client::Context::new(self.rpc_loop_commands.clone(), function, args)))
+        let child = try!(::client::rpc::client::Context::nested(
+                    self.rpc_loop_commands.clone(), function, args, &self.context, &self.trace_id));
+        self.children.lock().unwrap().push(child.context().to_string());
+        Ok(child)
+    }
+}
+
+// Calls whose Context was dropped unfinished while a handler run by 'run' was panicking. 'run'
+// answers them with the panic message. None outside of 'run'.
+thread_local!(static UNFINISHED: RefCell<Option<Vec<(String, rpc_loop::CommandSender)>>> = RefCell::new(None));
+
+fn internal_error(context: String, message: &str) -> ::ipc::Message {
+    ::ipc::Message::RpcResponse(::rpc::Response {
+        context: context,
+        kind: ::rpc::ResponseKind::Last(::rpc::Result::Err(
+                ::rpc::Error::new(::rpc::ErrorKind::Internal, message))),
+    })
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "unknown panic".into()
+}
+
+/// Runs 'rpc' for one call. If it panics, the call is answered with ErrorKind::Internal and the
+/// panic message and the thread lives on.
+pub fn run(rpc: &Rpc, context: Context, args: serde_json::Value) {
+    UNFINISHED.with(|unfinished| *unfinished.borrow_mut() = Some(Vec::new()));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| rpc.call(context, args)));
+    let unfinished = UNFINISHED.with(|unfinished| unfinished.borrow_mut().take()).unwrap_or(Vec::new());
+
+    if let Err(payload) = result {
+        let message = panic_message(&payload);
+        for (context, rpc_loop_commands) in unfinished {
+            // The rpc loop might be gone already, then there is nobody left to answer.
+            let _ = rpc_loop_commands.send(rpc_loop::Command::Send(
+                    internal_error(context, &format!("Handler panicked: {}", message))));
+        }
     }
 }
 
 impl Drop for Context {
     fn drop(&mut self) {
-        match self.state {
-            ContextState::Finished | ContextState::Cancelled => (),
-            ContextState::Alive => panic!("Context dropped while still alive. Call finish()!."),
+        self.update_state();
+        self.cancel_children();
+        if self.state != ContextState::Alive {
+            return;
+        }
+
+        if thread::panicking() {
+            let context = self.context.clone();
+            let rpc_loop_commands = self.rpc_loop_commands.clone();
+            let reported = UNFINISHED.with(|unfinished| match *unfinished.borrow_mut() {
+                Some(ref mut unfinished) => {
+                    unfinished.push((context, rpc_loop_commands));
+                    true
+                },
+                None => false,
+            });
+            if reported {
+                return;
+            }
         }
+        let message = if thread::panicking() {
+            "Handler panicked."
+        } else {
+            "Handler dropped the call without finishing it."
+        };
+        let _ = self.rpc_loop_commands.send(rpc_loop::Command::Send(
+                internal_error(self.context.clone(), message)));
     }
 }
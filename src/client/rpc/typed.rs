@@ -0,0 +1,122 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Serving and calling RPCs through their 'rpc::RpcDefinition' instead of a name and JSON values.
+//! The arguments are parsed before the handler runs and the results are parsed for the caller.
+
+use ::client::RpcCaller;
+use ::client::rpc::{client, server};
+use ::error::Result;
+use ::rpc::{self, RpcDefinition};
+use serde;
+use serde_json;
+use std::marker::PhantomData;
+use std::convert;
+use std::result;
+
+/// A handler for the RPC 'Definition'. Register it with 'Client::new_typed_rpc'.
+pub trait Rpc: Send + Sync {
+    type Definition: RpcDefinition;
+
+    fn priority(&self) -> u16 { u16::max_value() }
+    fn execution(&self) -> server::Execution { server::Execution::Concurrent }
+    fn call(&self, context: Context<Self::Definition>,
+            request: <Self::Definition as RpcDefinition>::Request);
+}
+
+/// Adapts a typed handler to 'server::Rpc'.
+pub struct Handler<R: Rpc>(pub R);
+
+impl<R: Rpc> server::Rpc for Handler<R> {
+    fn priority(&self) -> u16 { self.0.priority() }
+    fn execution(&self) -> server::Execution { self.0.execution() }
+
+    fn call(&self, mut context: server::Context, args: serde_json::Value) {
+        let request = try_rpc!(context, serde_json::from_value(args));
+        self.0.call(Context::new(context), request);
+    }
+}
+
+/// The server side of a call to the RPC 'D'.
+pub struct Context<D: RpcDefinition> {
+    context: server::Context,
+    definition: PhantomData<D>,
+}
+
+impl<D: RpcDefinition> Context<D> {
+    fn new(context: server::Context) -> Self {
+        Context {
+            context: context,
+            definition: PhantomData,
+        }
+    }
+
+    pub fn update(&mut self, update: &D::Update) -> Result<()> {
+        self.context.update(update)
+    }
+
+    pub fn finish(&mut self, result: result::Result<D::Response, rpc::Error>) -> Result<()> {
+        self.context.finish(match result {
+            Ok(response) => rpc::Result::success(response),
+            Err(err) => rpc::Result::Err(err),
+        })
+    }
+
+    /// Lets the next handler of the RPC try.
+    pub fn not_handled(&mut self) -> Result<()> {
+        self.context.finish(rpc::Result::NotHandled)
+    }
+
+    pub fn cancelled(&mut self) -> bool {
+        self.context.cancelled()
+    }
+}
+
+impl<D: RpcDefinition> RpcCaller for Context<D> {
+    fn call<T: serde::Serialize>(&mut self, function: &str, args: &T) -> Result<client::Context> {
+        self.context.call(function, args)
+    }
+}
+
+/// The caller side of a call to the RPC 'D'. See 'RpcCaller::call_typed'.
+pub struct Call<D: RpcDefinition> {
+    context: client::Context,
+    definition: PhantomData<D>,
+}
+
+impl<D: RpcDefinition> Call<D> {
+    pub fn new(context: client::Context) -> Self {
+        Call {
+            context: context,
+            definition: PhantomData,
+        }
+    }
+
+    /// The next update, or None once the call is done.
+    pub fn recv(&mut self) -> Result<Option<D::Update>> {
+        match try!(self.context.recv()) {
+            Some(value) => Ok(Some(try!(serde_json::from_value(value)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for the result. Updates that were not received yet are skipped.
+    pub fn wait(&mut self) -> Result<result::Result<D::Response, rpc::Error>> {
+        match try!(self.context.wait()) {
+            rpc::Result::Ok(value) => Ok(Ok(try!(serde_json::from_value(value)))),
+            rpc::Result::Err(err) => Ok(Err(err)),
+            rpc::Result::NotHandled => Ok(Err(rpc::Error::new(
+                        rpc::ErrorKind::UnknownRpc, "No handler handled the call."))),
+        }
+    }
+
+    pub fn cancel(self) -> Result<()> {
+        self.context.cancel()
+    }
+
+    /// See 'client::Context::detach'.
+    pub fn detach(self) {
+        self.context.detach()
+    }
+}
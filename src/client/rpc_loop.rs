@@ -2,39 +2,88 @@
 // Licensed under the Apache License, Version 2.0. See LICENSE.txt
 // in the project root for license information.
 
+use ::blob::Blob;
 use ::client::rpc;
+use ::client::ConnectionEvent;
 use ::error::{Error, Result};
 use ::ipc;
+use ::server::config::Heartbeat;
+use ::server::plugin_core::{Coalesce, HandshakeRequest, NewRpcRequest, SubscribeRequest, ThreadPoolRequest};
 use ::spinner;
+use serde;
+use serde_json;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::thread;
 use threadpool::ThreadPool;
+use time;
+use uuid::Uuid;
 
+// How often the rpc loop checks whether a ping is due, once a heartbeat is set.
+const TICK_INTERVAL_MS: u64 = 100;
 
 pub type CommandSender = mpsc::Sender<Command>;
 pub enum Command {
     Quit,
     NewRpc(String, Box<rpc::server::Rpc>),
     Received(::ipc::Message),
-    OutgoingCall(String, mpsc::Sender<::rpc::Response>, ipc::Message),
+    OutgoingCall(String, rpc::client::ResponseSender, ipc::Message),
     CancelOutgoingRpc(String),
+    DetachOutgoingRpc(String),
     Send(::ipc::Message),
+    // Sent regularly to take care of heartbeats and Blobs, once there are any.
+    Tick,
+    SetHeartbeat(Option<Heartbeat>),
+    SetThreadPoolSize(usize),
+    // The name given in 'core.handshake', repeated after reconnecting.
+    SetName(String),
+    WatchConnection(mpsc::Sender<ConnectionEvent>),
+    // Delivers the notifications received for the subscription to the prefix, see
+    // 'Client::subscribe'.
+    Subscribe(String, Option<Coalesce>, mpsc::Sender<::rpc::Notification>),
+    Unsubscribe(String),
+    Disconnected,
+    Reconnected,
 }
 
 struct RunningRpc {
     commands: mpsc::Sender<rpc::server::Command>,
+    // Contexts of the calls this RPC made, see 'rpc::server::Context::call'.
+    children: Arc<Mutex<Vec<String>>>,
 }
 
 impl RunningRpc {
-    fn new(commands: mpsc::Sender<rpc::server::Command>) -> Self {
+    fn new(commands: mpsc::Sender<rpc::server::Command>, children: Arc<Mutex<Vec<String>>>) -> Self {
         RunningRpc {
             commands: commands,
+            children: children,
         }
     }
 }
 
+// A call waiting for an RPC with Execution::Serial.
+type SerialCall = (rpc::server::Context, serde_json::Value);
+
+// Runs the calls of one RPC with Execution::Serial one after the other on a thread of its own.
+fn spawn_serial_worker(function: Arc<Box<rpc::server::Rpc>>) -> mpsc::Sender<SerialCall> {
+    let (tx, rx) = mpsc::channel::<SerialCall>();
+    thread::spawn(move || {
+        for (context, args) in rx.iter() {
+            rpc::server::run(&**function, context, args);
+        }
+    });
+    tx
+}
+
+struct Subscriber {
+    prefix: String,
+    // Repeated when subscribing again after reconnecting.
+    coalesce: Option<Coalesce>,
+    notifications: mpsc::Sender<::rpc::Notification>,
+}
+
 struct Receiver {
     commands: mpsc::Receiver<Command>,
 }
@@ -62,22 +111,314 @@ struct Handler {
     running_rpc_calls: HashMap<String, RunningRpc>,
     command_sender: CommandSender,
     // NOCOM(#sirver): maybe not use a channel to send data to rpcs?
-    running_function_calls: HashMap<String, mpsc::Sender<::rpc::Response>>,
+    running_function_calls: HashMap<String, rpc::client::ResponseSender>,
     thread_pool: ThreadPool,
+    // Set through 'Client::set_thread_pool_size', repeated after reconnecting.
+    thread_pool_size: Option<usize>,
+    // Queues of the RPCs with Execution::Serial, created on their first call.
+    serial_workers: HashMap<String, mpsc::Sender<SerialCall>>,
+    heartbeat: Option<Heartbeat>,
+    // Whether a thread is sending us Ticks. Only started once a heartbeat is set or a Blob was
+    // made.
+    ticking: bool,
+    last_ping: time::SteadyTime,
+    last_pong: time::SteadyTime,
+    connected: Arc<AtomicBool>,
+    drop_connection: Option<Box<Fn() + Send>>,
+    name: Option<String>,
+    connection_watchers: Vec<mpsc::Sender<ConnectionEvent>>,
+    subscribers: Vec<Subscriber>,
+    // Whether large results can be sent as a Blob, see 'Client::is_same_host'.
+    same_host: bool,
+    // The Blobs made for results and when to remove them if they were not taken by then.
+    expiring_blobs: Vec<(time::SteadyTime, Blob)>,
 }
 
 impl Handler {
     pub fn new(command_sender: CommandSender,
-               send_queue: mpsc::Sender<ipc::Message>) -> Self {
+               send_queue: mpsc::Sender<ipc::Message>,
+               connected: Arc<AtomicBool>,
+               drop_connection: Option<Box<Fn() + Send>>,
+               same_host: bool) -> Self {
+        let now = time::SteadyTime::now();
         Handler {
             remote_procedures: HashMap::new(),
             running_function_calls: HashMap::new(),
             running_rpc_calls: HashMap::new(),
             send_queue: send_queue,
             command_sender: command_sender,
-            // NOCOM(#sirver): that seems silly.
-            thread_pool: ThreadPool::new(1),
+            thread_pool: ThreadPool::new(::client::DEFAULT_THREAD_POOL_SIZE),
+            thread_pool_size: None,
+            serial_workers: HashMap::new(),
+            heartbeat: None,
+            ticking: false,
+            last_ping: now,
+            last_pong: now,
+            connected: connected,
+            drop_connection: drop_connection,
+            name: None,
+            connection_watchers: Vec::new(),
+            subscribers: Vec::new(),
+            same_host: same_host,
+            expiring_blobs: Vec::new(),
+        }
+    }
+
+    fn notify(&mut self, event: ConnectionEvent) {
+        self.connection_watchers.retain(|watcher| watcher.send(event).is_ok());
+    }
+
+    // Sends a call whose response nobody waits for.
+    fn send_call<T: serde::Serialize>(&self, function: &str, args: &T) -> Result<()> {
+        try!(self.send_queue.send(ipc::Message::RpcCall(::rpc::Call {
+            function: function.into(),
+            context: Uuid::new_v4().to_hyphenated_string(),
+            args: serde_json::to_value(args),
+            parent: None,
+            trace_id: None,
+        })));
+        Ok(())
+    }
+
+    // Cancels an outgoing call that did not end yet. A caller that is still waiting on it gets
+    // ErrorKind::Cancelled.
+    fn cancel_outgoing_call(&mut self, context: String) -> Result<()> {
+        let channel = match self.running_function_calls.remove(&context) {
+            Some(channel) => channel,
+            None => return Ok(()),
+        };
+        let _ = channel.send(::rpc::Response {
+            context: context.clone(),
+            kind: ::rpc::ResponseKind::Last(::rpc::Result::Err(
+                    ::rpc::Error::new(::rpc::ErrorKind::Cancelled, "The call was cancelled."))),
+        });
+        try!(self.send_queue.send(ipc::Message::RpcCancel(::rpc::Cancel {
+            context: context,
+        })));
+        Ok(())
+    }
+
+    // Tells the server that nobody waits for an outgoing call anymore. It is no longer a child of
+    // the RPC that made it.
+    fn detach_outgoing_call(&mut self, context: String) -> Result<()> {
+        if self.running_function_calls.remove(&context).is_none() {
+            return Ok(());
+        }
+        for running_rpc in self.running_rpc_calls.values() {
+            running_rpc.children.lock().unwrap().retain(|child| *child != context);
+        }
+        try!(self.send_queue.send(ipc::Message::RpcDetach(::rpc::Detach {
+            context: context,
+        })));
+        Ok(())
+    }
+
+    // Puts the result of 'message' into a Blob if it is a large successful result and the server
+    // can take it from there.
+    fn blob_for_result(&mut self, message: &ipc::Message) -> Option<::rpc::ResponseBlob> {
+        if !self.same_host {
+            return None;
+        }
+        let (context, value) = match *message {
+            ipc::Message::RpcResponse(::rpc::Response {
+                ref context,
+                kind: ::rpc::ResponseKind::Last(::rpc::Result::Ok(ref value)),
+            }) => (context, value),
+            _ => return None,
+        };
+        let data = match serde_json::to_vec(value) {
+            Ok(data) => data,
+            Err(_) => return None,
+        };
+        if data.len() < ::blob::MIN_RESULT_BYTES {
+            return None;
+        }
+        // Without shared memory, the result still gets there the slow way.
+        let blob = match Blob::create(&data) {
+            Ok(blob) => blob,
+            Err(_) => return None,
+        };
+        let deadline = time::SteadyTime::now() + time::Duration::seconds(::blob::TTL_SECS as i64);
+        self.expiring_blobs.push((deadline, blob.clone()));
+        self.start_ticking();
+        Some(::rpc::ResponseBlob {
+            context: context.clone(),
+            blob: blob,
+        })
+    }
+
+    fn on_rpc_response(&mut self, rpc_data: ::rpc::Response) {
+        // NOCOM(#sirver): if this is a streaming RPC, we should cancel the
+        // RPC.
+        // This will quietly drop any updates on functions that we no longer
+        // know/care about.
+        let is_last = match rpc_data.kind {
+            ::rpc::ResponseKind::Last(_) => true,
+            ::rpc::ResponseKind::Partial(_) => false,
+        };
+        let context = rpc_data.context.clone();
+        self.running_function_calls
+            .get(&context)
+            .map(|channel| {
+                // The other side of this channel might not exist anymore - we
+                // might have dropped the RPC already. Just ignore it.
+                let _ = channel.send(rpc_data);
+            });
+        if is_last {
+            // Nothing to cancel anymore.
+            self.running_function_calls.remove(&context);
+        }
+    }
+
+    fn on_rpc_call(&mut self, rpc_call: ::rpc::Call) {
+        let function = match self.remote_procedures.get(&rpc_call.function) {
+            Some(function) => function.clone(),
+            // NOCOM(#sirver): return an error - though if that has happened the
+            // server messed up too.
+            None => return,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let children = Arc::new(Mutex::new(Vec::new()));
+        self.running_rpc_calls.insert(
+            rpc_call.context.clone(), RunningRpc::new(tx, children.clone()));
+        let trace_id = rpc_call.trace_id.unwrap_or(rpc_call.context.clone());
+        let context = rpc::server::Context::new(
+            rpc_call.context, trace_id, rx, self.command_sender.clone(), children);
+        let args = rpc_call.args;
+
+        match function.execution() {
+            rpc::server::Execution::Concurrent => {
+                self.thread_pool.execute(move || rpc::server::run(&**function, context, args));
+            },
+            rpc::server::Execution::Dedicated => {
+                thread::spawn(move || rpc::server::run(&**function, context, args));
+            },
+            rpc::server::Execution::Serial => {
+                let mut call = (context, args);
+                loop {
+                    let worker = self.serial_workers
+                        .entry(rpc_call.function.clone())
+                        .or_insert_with(|| spawn_serial_worker(function.clone()))
+                        .clone();
+                    match worker.send(call) {
+                        Ok(()) => break,
+                        // The worker is gone, so we start a new one.
+                        Err(mpsc::SendError(unsent)) => {
+                            call = unsent;
+                            self.serial_workers.remove(&rpc_call.function);
+                        },
+                    }
+                }
+            },
+        }
+    }
+
+    fn on_disconnected(&mut self) {
+        if !self.connected.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        // Dropping the channels makes the callers see Error::Disconnected.
+        self.running_function_calls.clear();
+        for (_, running_rpc) in self.running_rpc_calls.drain() {
+            let _ = running_rpc.commands.send(rpc::server::Command::Cancel);
+        }
+        self.notify(ConnectionEvent::Disconnected);
+    }
+
+    // The server does not know us anymore, so we tell it again who we are and what we serve.
+    // Failures cannot be reported to anyone, the RPCs will just not be called then.
+    fn on_reconnected(&mut self) -> Result<()> {
+        let now = time::SteadyTime::now();
+        self.last_ping = now;
+        self.last_pong = now;
+        self.connected.store(true, Ordering::SeqCst);
+
+        if let Some(name) = self.name.clone() {
+            try!(self.send_call("core.handshake", &HandshakeRequest {
+                name: name,
+            }));
+        }
+        for (name, rpc) in &self.remote_procedures {
+            try!(self.send_call("core.new_rpc", &NewRpcRequest {
+                name: name.clone(),
+                priority: rpc.priority(),
+                execution: Some(rpc.execution()),
+            }));
+        }
+        if let Some(size) = self.thread_pool_size {
+            try!(self.send_call("core.set_thread_pool_size", &ThreadPoolRequest {
+                size: size,
+            }));
+        }
+        // Only the latest subscription to a prefix counts.
+        let mut subscriptions = HashMap::new();
+        for subscriber in &self.subscribers {
+            subscriptions.insert(subscriber.prefix.clone(), subscriber.coalesce.clone());
+        }
+        for (prefix, coalesce) in subscriptions {
+            try!(self.send_call("core.subscribe", &SubscribeRequest {
+                prefix: prefix,
+                coalesce: coalesce,
+            }));
         }
+        self.notify(ConnectionEvent::Reconnected);
+        Ok(())
+    }
+
+    fn start_ticking(&mut self) {
+        if self.ticking {
+            return;
+        }
+        self.ticking = true;
+        let ticker = self.command_sender.clone();
+        thread::spawn(move || {
+            // Stops once the rpc loop is gone.
+            loop {
+                thread::sleep(::std::time::Duration::from_millis(TICK_INTERVAL_MS));
+                if ticker.send(Command::Tick).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Removes the Blobs that were not taken in time. Most of them are taken already.
+    fn expire_blobs(&mut self) {
+        let now = time::SteadyTime::now();
+        self.expiring_blobs.retain(|&(ref deadline, ref blob)| {
+            if *deadline > now {
+                return true;
+            }
+            let _ = blob.discard();
+            false
+        });
+    }
+
+    // Pings the server and gives up on it once it stopped answering.
+    fn on_tick(&mut self) -> Result<spinner::Command> {
+        self.expire_blobs();
+        let heartbeat = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(spinner::Command::Continue),
+        };
+        let now = time::SteadyTime::now();
+        if now - self.last_pong > heartbeat.timeout {
+            if let Some(ref drop_connection) = self.drop_connection {
+                // The reader notices and reconnects.
+                self.last_pong = now;
+                drop_connection();
+                return Ok(spinner::Command::Continue);
+            }
+            // Nobody reconnects, so the callers and watchers learn about it right here.
+            self.on_disconnected();
+            return Ok(spinner::Command::Quit);
+        }
+        if now - self.last_ping >= heartbeat.interval {
+            self.last_ping = now;
+            try!(self.send_queue.send(ipc::Message::Ping));
+        }
+        Ok(spinner::Command::Continue)
     }
 }
 
@@ -86,64 +427,133 @@ impl spinner::Handler<Command> for Handler {
         match command {
             Command::Quit => Ok(spinner::Command::Quit),
             Command::NewRpc(name, rpc) => {
+                // Calls already queued for the old RPC still go to it.
+                self.serial_workers.remove(&name);
                 self.remote_procedures.insert(name, Arc::new(rpc));
                 Ok(spinner::Command::Continue)
             },
             Command::Received(message) => {
                 match message {
-                    ::ipc::Message::RpcCall(rpc_call) => {
-
-                        if let Some(function) = self.remote_procedures.get(&rpc_call.function) {
-                            let (tx, rx) = mpsc::channel();
-                            self.running_rpc_calls.insert(rpc_call.context.clone(), RunningRpc::new(tx));
-                            let command_sender = self.command_sender.clone();
-                            let function = function.clone();
-                            self.thread_pool.execute(move || {
-                                function.call(rpc::server::Context::new(
-                                        rpc_call.context, rx, command_sender), rpc_call.args);
-                            })
-                        }
-                        // NOCOM(#sirver): return an error - though if that has happened the
-                        // server messed up too.
-                    },
+                    ::ipc::Message::RpcCall(rpc_call) => self.on_rpc_call(rpc_call),
                     ::ipc::Message::RpcCancel(rpc_cancel) => {
                         // NOCOM(#sirver): on drop, the rpcservercontext must delete the entry.
                         if let Some(function) = self.running_rpc_calls.remove(&rpc_cancel.context) {
                             // The function might be dead already, so we ignore errors.
                             let _ = function.commands.send(rpc::server::Command::Cancel);
+                            // Its calls are cancelled right away, the function might be blocked
+                            // waiting for one of them.
+                            let children: Vec<_> = function.children.lock().unwrap().drain(..).collect();
+                            for child in children {
+                                try!(self.cancel_outgoing_call(child));
+                            }
+                        }
+                    },
+                    ipc::Message::RpcCallPartial(partial) => {
+                        if let Some(function) = self.running_rpc_calls.get(&partial.context) {
+                            let command = match partial.value {
+                                Some(value) => rpc::server::Command::Partial(value),
+                                None => rpc::server::Command::ClosePartials,
+                            };
+                            // The function might be done already, so we ignore errors.
+                            let _ = function.commands.send(command);
                         }
                     },
-                    ipc::Message::RpcResponse(rpc_data) => {
-                        // NOCOM(#sirver): if this is a streaming RPC, we should cancel the
-                        // RPC.
-                        // This will quietly drop any updates on functions that we no longer
-                        // know/care about.
-                        self.running_function_calls
-                            .get(&rpc_data.context)
-                            .map(|channel| {
-                                // The other side of this channel might not exist anymore - we
-                                // might have dropped the RPC already. Just ignore it.
-                                let _ = channel.send(rpc_data);
-                            });
+                    ipc::Message::Notifications(notifications) => {
+                        // Subscribers that went away are forgotten.
+                        self.subscribers.retain(|subscriber| {
+                            subscriber.prefix != notifications.prefix ||
+                                notifications.notifications.iter().all(|notification| {
+                                    subscriber.notifications.send(notification.clone()).is_ok()
+                                })
+                        });
+                    },
+                    // Only sent by clients.
+                    ipc::Message::Notification(_) | ipc::Message::RpcDetach(_) => (),
+                    ipc::Message::Ping => {
+                        try!(self.send_queue.send(ipc::Message::Pong));
+                    },
+                    ipc::Message::Pong => {
+                        self.last_pong = time::SteadyTime::now();
+                    },
+                    ipc::Message::RpcResponse(rpc_data) => self.on_rpc_response(rpc_data),
+                    ipc::Message::RpcResponseBlob(response_blob) => {
+                        if self.running_function_calls.contains_key(&response_blob.context) {
+                            self.on_rpc_response(response_blob.take());
+                        } else {
+                            // Nobody is going to take it.
+                            let _ = response_blob.blob.discard();
+                        }
                     },
                 }
                 Ok(spinner::Command::Continue)
             },
             Command::Send(message) => {
+                let message = match self.blob_for_result(&message) {
+                    Some(response_blob) => ipc::Message::RpcResponseBlob(response_blob),
+                    None => message,
+                };
                 try!(self.send_queue.send(message));
                 Ok(spinner::Command::Continue)
             },
             Command::OutgoingCall(context, tx, message) => {
+                if !self.connected.load(Ordering::SeqCst) {
+                    // Dropping 'tx' fails the call right away.
+                    return Ok(spinner::Command::Continue);
+                }
                 self.running_function_calls.insert(context, tx);
                 // NOCOM(#sirver): can the message be constructed here?
                 try!(self.send_queue.send(message));
                 Ok(spinner::Command::Continue)
             }
-            Command::CancelOutgoingRpc(context) => {
-                let msg = ::ipc::Message::RpcCancel(::rpc::Cancel {
-                    context: context,
+            Command::Tick => self.on_tick(),
+            Command::SetHeartbeat(heartbeat) => {
+                if heartbeat.is_some() {
+                    self.start_ticking();
+                }
+                self.heartbeat = heartbeat;
+                self.last_pong = time::SteadyTime::now();
+                Ok(spinner::Command::Continue)
+            },
+            Command::SetThreadPoolSize(size) => {
+                // Calls running on the old pool finish there.
+                self.thread_pool = ThreadPool::new(size);
+                self.thread_pool_size = Some(size);
+                Ok(spinner::Command::Continue)
+            },
+            Command::SetName(name) => {
+                self.name = Some(name);
+                Ok(spinner::Command::Continue)
+            },
+            Command::WatchConnection(watcher) => {
+                self.connection_watchers.push(watcher);
+                Ok(spinner::Command::Continue)
+            },
+            Command::Subscribe(prefix, coalesce, notifications) => {
+                self.subscribers.push(Subscriber {
+                    prefix: prefix,
+                    coalesce: coalesce,
+                    notifications: notifications,
                 });
-                try!(self.send_queue.send(msg));
+                Ok(spinner::Command::Continue)
+            },
+            Command::Unsubscribe(prefix) => {
+                self.subscribers.retain(|subscriber| subscriber.prefix != prefix);
+                Ok(spinner::Command::Continue)
+            },
+            Command::Disconnected => {
+                self.on_disconnected();
+                Ok(spinner::Command::Continue)
+            },
+            Command::Reconnected => {
+                try!(self.on_reconnected());
+                Ok(spinner::Command::Continue)
+            },
+            Command::CancelOutgoingRpc(context) => {
+                try!(self.cancel_outgoing_call(context));
+                Ok(spinner::Command::Continue)
+            },
+            Command::DetachOutgoingRpc(context) => {
+                try!(self.detach_outgoing_call(context));
                 Ok(spinner::Command::Continue)
             }
         }
@@ -152,9 +562,12 @@ impl spinner::Handler<Command> for Handler {
 
 pub fn spawn(commands: mpsc::Receiver<Command>,
                  command_sender: CommandSender,
-                 send_queue: mpsc::Sender<ipc::Message>) -> thread::JoinHandle<()>
+                 send_queue: mpsc::Sender<ipc::Message>,
+                 connected: Arc<AtomicBool>,
+                 drop_connection: Option<Box<Fn() + Send>>,
+                 same_host: bool) -> thread::JoinHandle<()>
 {
     let recver = Receiver::new(commands);
-    let handler = Handler::new(command_sender, send_queue);
+    let handler = Handler::new(command_sender, send_queue, connected, drop_connection, same_host);
     spinner::spawn(recver, handler)
 }
@@ -21,6 +21,11 @@ pub enum Error {
     JsonParsing(serde_json::error::Error),
     RpcDone,
     InvalidUtf8,
+    InvalidConfig(String),
+    /// Waiting for an RPC took too long. The call was cancelled.
+    Timeout,
+    /// An RPC answered with an error.
+    Rpc(::rpc::Error),
 }
 
 impl fmt::Display for Error {
@@ -37,6 +42,12 @@ impl error::Error for Error {
           Error::JsonParsing(ref e) => e.description(),
           Error::RpcDone => "RPC is already finished or cancelled.",
           Error::InvalidUtf8 => "Invalid utf-8 string encountered.",
+          Error::InvalidConfig(ref reason) => reason,
+          Error::Timeout => "Timed out waiting for the RPC.",
+          Error::Rpc(ref error) => match error.message {
+              Some(ref message) => message as &str,
+              None => "RPC answered with an error.",
+          },
       }
   }
 
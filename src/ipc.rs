@@ -12,7 +12,19 @@ use std::io::{Read, Write};
 pub enum Message {
     RpcCall(rpc::Call),
     RpcResponse(rpc::Response),
+    // Replaces a large successful last RpcResponse between clients on the same host.
+    RpcResponseBlob(rpc::ResponseBlob),
     RpcCancel(rpc::Cancel),
+    // Sent by the caller of a running RPC, only seen by the server.
+    RpcDetach(rpc::Detach),
+    // Sent by the caller of a running RPC, passed on to its handler.
+    RpcCallPartial(rpc::CallPartial),
+    // Sent by clients to publish, by the server to deliver to subscribers.
+    Notification(rpc::Notification),
+    Notifications(rpc::Notifications),
+    // Keepalives. Both sides answer a Ping with a Pong.
+    Ping,
+    Pong,
 }
 
 pub struct Reader<T: Read> {
@@ -41,6 +53,20 @@ impl<T: Read> Reader<T> {
         }
     }
 
+    /// Continues reading from 'socket' where another Reader left off. 'buffer' is the data the
+    /// other Reader had already received, but not yet parsed.
+    pub fn with_buffer(socket: T, buffer: Vec<u8>) -> Self {
+        Reader {
+            socket: socket,
+            buffer: buffer,
+        }
+    }
+
+    /// Data that has been read from the socket, but does not yet form a full message.
+    pub fn unread(&self) -> &[u8] {
+        &self.buffer
+    }
+
     /// Read one full message - this expects the underlying socket to be blocking.
     pub fn read_message(&mut self) -> Result<Message> {
         let mut size_buf = [0u8; 4];
@@ -121,6 +147,24 @@ impl<T: Write> Writer<T> {
         self.to_write.push(buffer);
     }
 
+    /// Queues data that has already been encoded, e.g. the unwritten data of another Writer.
+    pub fn queue_raw(&mut self, data: Vec<u8>) {
+        if !data.is_empty() {
+            self.to_write.push(data);
+        }
+    }
+
+    /// Removes all queued data that has not yet been written to the socket and returns it.
+    pub fn take_unwritten(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (index, chunk) in self.to_write.drain(..).enumerate() {
+            let start = if index == 0 { self.num_written } else { 0 };
+            data.extend_from_slice(&chunk[start..]);
+        }
+        self.num_written = 0;
+        data
+    }
+
     pub fn try_write(&mut self) -> Result<WriterState> {
         if self.to_write.is_empty() {
             return Ok(WriterState::AllWritten);
@@ -5,6 +5,8 @@
 #![feature(custom_derive, plugin)]
 #![plugin(serde_macros)]
 
+#[macro_use]
+extern crate futures;
 extern crate libc;
 extern crate mio;
 extern crate serde;
@@ -27,7 +29,20 @@ macro_rules! try_rpc {
     })
 }
 
+/// Like 'try_rpc!', for the Context of a typed RPC (see 'client::rpc::typed').
+#[macro_export]
+macro_rules! try_typed_rpc {
+    ($context:ident, $expr:expr) => (match $expr {
+        Ok(val) => val,
+        Err(err) => {
+            $context.finish(Err(::std::convert::From::from(err))).unwrap();
+            return;
+        }
+    })
+}
+
 mod ipc;
+pub mod blob;
 pub mod client;
 pub mod error;
 pub mod plugin;
@@ -1,7 +1,6 @@
 // Copyright (c) The Swiboe development team. All rights reserved.
 // Licensed under the Apache License, Version 2.0. See LICENSE.txt
 // in the project root for license information.
-use ::client::RpcCaller;
 use ::client;
 use ::rpc;
 use serde_json;
@@ -20,12 +19,13 @@ impl From<BufferError> for rpc::Error {
      fn from(error: BufferError) -> Self {
          use rpc::ErrorKind::*;
 
-         let (kind, details) = match error {
-             BufferError::UnknownBuffer => (InvalidArgs, format!("unknown_buffer")),
+         let (kind, message, details) = match error {
+             BufferError::UnknownBuffer => (NotFound, "Unknown buffer.", "unknown_buffer"),
          };
 
          rpc::Error {
              kind: kind,
+             message: Some(message.into()),
              details: Some(serde_json::to_value(&details)),
          }
      }
@@ -35,7 +35,7 @@ impl From<io::Error> for rpc::Error {
      fn from(error: io::Error) -> Self {
          let details = match error.kind() {
              io::ErrorKind::NotFound => "not_found",
-             io::ErrorKind::PermissionDenied => "not_found",
+             io::ErrorKind::PermissionDenied => "permission_denied",
              io::ErrorKind::ConnectionRefused => "connection_refused",
              io::ErrorKind::ConnectionReset => "connection_reset",
              io::ErrorKind::ConnectionAborted => "connection_aborted",
@@ -52,8 +52,15 @@ impl From<io::Error> for rpc::Error {
              io::ErrorKind::Interrupted => "interrupted",
              _ => "unknown",
          };
+         let kind = match error.kind() {
+             io::ErrorKind::NotFound => rpc::ErrorKind::NotFound,
+             io::ErrorKind::PermissionDenied => rpc::ErrorKind::PermissionDenied,
+             io::ErrorKind::TimedOut => rpc::ErrorKind::Timeout,
+             _ => rpc::ErrorKind::Io,
+         };
          rpc::Error {
-             kind: rpc::ErrorKind::Io,
+             kind: kind,
+             message: Some(format!("{}", error)),
              details: Some(serde_json::to_value(&details)),
          }
      }
@@ -92,6 +99,13 @@ impl Buffer {
     }
 }
 
+/// The content of all buffers. Used to carry them over into a new server process.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub next_buffer_index: usize,
+    pub buffers: Vec<(usize, String)>,
+}
+
 pub struct BuffersManager {
     next_buffer_index: usize,
     buffers: HashMap<usize, Buffer>,
@@ -114,8 +128,8 @@ impl BuffersManager {
         self.buffers.insert(current_buffer_index, buffer);
 
         // NOCOM(#sirver): new is not good. should be create.
-        // Fire the callback, but we do not wait for it's conclusion.
-        let _ = self.client.call("on.buffer.new", &BufferCreated {
+        // Nobody might be listening, so a failure is not an error.
+        let _ = self.client.notify("on.buffer.new", &BufferCreated {
             buffer_index: current_buffer_index,
         });
         current_buffer_index
@@ -124,8 +138,7 @@ impl BuffersManager {
     pub fn delete_buffer(&mut self, buffer_index: usize) -> result::Result<(), BufferError> {
         try!(self.buffers.remove(&buffer_index).ok_or(BufferError::UnknownBuffer));
 
-        // Fire the callback, but we do not wait for it's conclusion.
-        let _ = self.client.call("on.buffer.deleted", &BufferDeleted {
+        let _ = self.client.notify("on.buffer.deleted", &BufferDeleted {
             buffer_index: buffer_index,
         });
 
@@ -136,6 +149,24 @@ impl BuffersManager {
         let buffer = try!(self.buffers.get(&index).ok_or(BufferError::UnknownBuffer));
         Ok(buffer)
     }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            next_buffer_index: self.next_buffer_index,
+            buffers: self.buffers.iter()
+                .map(|(index, buffer)| (*index, buffer.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Replaces all buffers with the ones in 'snapshot'. No events are fired, since the buffers
+    /// are not new to anybody.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.next_buffer_index = snapshot.next_buffer_index;
+        self.buffers = snapshot.buffers.into_iter()
+            .map(|(index, content)| (index, Buffer::from_string(content)))
+            .collect();
+    }
 }
 
 impl ops::Deref for BuffersManager {
@@ -5,8 +5,6 @@
 use ::client;
 use ::plugin::buffer::base;
 use ::rpc;
-use serde_json;
-use std::convert;
 use std::sync::{RwLock, Arc};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -17,17 +15,29 @@ pub struct Request {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Response;
 
+/// The RPC 'buffer.delete'.
+pub struct Definition;
+
+impl rpc::RpcDefinition for Definition {
+    type Request = Request;
+    type Response = Response;
+    type Update = ();
+
+    fn name() -> &'static str { "buffer.delete" }
+}
+
 pub struct Rpc {
     pub buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
-impl client::rpc::server::Rpc for Rpc {
-    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
-        let request: Request = try_rpc!(context, serde_json::from_value(args));
+impl client::rpc::typed::Rpc for Rpc {
+    type Definition = Definition;
+
+    fn call(&self, mut context: client::rpc::typed::Context<Definition>, request: Request) {
         let mut buffers = self.buffers.write().expect("Delete::call: locking buffers.");
-        try_rpc!(context, buffers.delete_buffer(request.buffer_index));
+        try_typed_rpc!(context, buffers.delete_buffer(request.buffer_index));
 
         let response = Response;
-        context.finish(rpc::Result::success(response)).expect("Delete::call: finish");
+        context.finish(Ok(response)).expect("Delete::call: finish");
     }
 }
@@ -4,8 +4,6 @@
 use ::client;
 use ::plugin::buffer::base;
 use ::rpc;
-use serde_json;
-use std::convert;
 use std::sync::{RwLock, Arc};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -18,20 +16,32 @@ pub struct Response {
     pub content: String,
 }
 
+/// The RPC 'buffer.get_content'.
+pub struct Definition;
+
+impl rpc::RpcDefinition for Definition {
+    type Request = Request;
+    type Response = Response;
+    type Update = ();
+
+    fn name() -> &'static str { "buffer.get_content" }
+}
+
 pub struct Rpc {
     pub buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
-impl client::rpc::server::Rpc for Rpc {
-    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
-        let request: Request = try_rpc!(context, serde_json::from_value(args));
+impl client::rpc::typed::Rpc for Rpc {
+    type Definition = Definition;
+
+    fn call(&self, mut context: client::rpc::typed::Context<Definition>, request: Request) {
         let buffers = self.buffers.read().unwrap();
 
-        let buffer = try_rpc!(context, buffers.get(request.buffer_index));
+        let buffer = try_typed_rpc!(context, buffers.get(request.buffer_index));
 
         let response = Response {
             content: buffer.to_string(),
         };
-        context.finish(rpc::Result::success(response)).unwrap();
+        context.finish(Ok(response)).unwrap();
     }
 }
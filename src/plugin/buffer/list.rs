@@ -5,10 +5,8 @@
 // NOCOM(#sirver): add a test for this.
 
 use ::client;
-use ::serde_json;
 use ::plugin::buffer::base;
 use ::rpc;
-use std::convert;
 use std::sync::{RwLock, Arc};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -19,18 +17,29 @@ pub struct Response {
     pub buffer_indices: Vec<usize>,
 }
 
+/// The RPC 'buffer.list'.
+pub struct Definition;
+
+impl rpc::RpcDefinition for Definition {
+    type Request = Request;
+    type Response = Response;
+    type Update = ();
+
+    fn name() -> &'static str { "buffer.list" }
+}
+
 pub struct Rpc {
     pub buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
-impl client::rpc::server::Rpc for Rpc {
-    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
-        let _: Request = try_rpc!(context, serde_json::from_value(args));
+impl client::rpc::typed::Rpc for Rpc {
+    type Definition = Definition;
 
+    fn call(&self, mut context: client::rpc::typed::Context<Definition>, _: Request) {
         let buffers = self.buffers.read().unwrap();
         let response = Response {
             buffer_indices: buffers.keys().map(|c| *c).collect(),
         };
-        context.finish(rpc::Result::success(response)).unwrap();
+        context.finish(Ok(response)).unwrap();
     }
 }
@@ -4,33 +4,35 @@
 
 use ::client;
 use ::error::Result;
-use ::plugin;
 use std::sync::{RwLock, Arc};
 
 pub struct Plugin {
     _client: client::Client,
-    _buffers: Arc<RwLock<base::BuffersManager>>,
+    buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
 impl Plugin {
     pub fn new(mut client: client::Client) -> Result<Self> {
         let buffers = Arc::new(RwLock::new(base::BuffersManager::new(try!(client.clone()))));
-        let rpc_map = rpc_map! {
-            "buffer.new" => new::Rpc { buffers: buffers.clone() },
-            "buffer.delete" => delete::Rpc { buffers: buffers.clone() },
-            "buffer.get_content" => get_content::Rpc { buffers: buffers.clone() },
-            "buffer.open" => open::Rpc { buffers: buffers.clone() },
-            "buffer.list" => list::Rpc { buffers: buffers.clone() },
-        };
-        try!(plugin::register_rpc(&mut client, rpc_map));
+        try!(client.new_typed_rpc(new::Rpc { buffers: buffers.clone() }));
+        try!(client.new_typed_rpc(delete::Rpc { buffers: buffers.clone() }));
+        try!(client.new_typed_rpc(get_content::Rpc { buffers: buffers.clone() }));
+        try!(client.new_typed_rpc(open::Rpc { buffers: buffers.clone() }));
+        try!(client.new_typed_rpc(list::Rpc { buffers: buffers.clone() }));
         Ok(Plugin{
             _client: client,
-            _buffers: buffers,
+            buffers: buffers,
         })
     }
+
+    /// Shared access to the buffers, e.g. to take a snapshot of them from another thread.
+    pub fn buffers(&self) -> Arc<RwLock<BuffersManager>> {
+        self.buffers.clone()
+    }
 }
 
 mod base;
+pub use self::base::{BuffersManager, Snapshot};
 pub mod new;
 pub mod delete;
 pub mod get_content;
@@ -5,8 +5,6 @@
 use ::client;
 use ::plugin::buffer::base;
 use ::rpc;
-use serde_json;
-use std::convert;
 use std::sync::{RwLock, Arc};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -19,15 +17,26 @@ pub struct Response {
     pub buffer_index: usize,
 }
 
+/// The RPC 'buffer.new'.
+pub struct Definition;
+
+impl rpc::RpcDefinition for Definition {
+    type Request = Request;
+    type Response = Response;
+    type Update = ();
+
+    fn name() -> &'static str { "buffer.new" }
+}
+
 pub struct Rpc {
     pub buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
 // NOCOM(#sirver): what does serde do if there are extra values in the JSON?
-impl client::rpc::server::Rpc for Rpc {
-    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
-        // NOCOM(#sirver): need testing for bad request results
-        let request: Request = try_rpc!(context, serde_json::from_value(args));
+impl client::rpc::typed::Rpc for Rpc {
+    type Definition = Definition;
+
+    fn call(&self, mut context: client::rpc::typed::Context<Definition>, request: Request) {
         let mut buffers = self.buffers.write().unwrap();
 
         let buffer = match request.content {
@@ -38,7 +47,7 @@ impl client::rpc::server::Rpc for Rpc {
         let response = Response {
             buffer_index: buffers.new_buffer(buffer),
         };
-        context.finish(rpc::Result::success(response)).unwrap();
+        context.finish(Ok(response)).unwrap();
     }
 }
 
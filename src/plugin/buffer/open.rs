@@ -5,8 +5,6 @@
 use ::client;
 use ::plugin::buffer::base;
 use ::rpc;
-use serde_json;
-use std::convert;
 use std::fs;
 use std::path;
 use std::io::Read;
@@ -22,23 +20,35 @@ pub struct Response {
     pub buffer_index: usize,
 }
 
+/// The RPC 'buffer.open'.
+pub struct Definition;
+
+impl rpc::RpcDefinition for Definition {
+    type Request = Request;
+    type Response = Response;
+    type Update = ();
+
+    fn name() -> &'static str { "buffer.open" }
+}
+
 pub struct Rpc {
     pub buffers: Arc<RwLock<base::BuffersManager>>,
 }
 
-impl client::rpc::server::Rpc for Rpc {
-    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
+impl client::rpc::typed::Rpc for Rpc {
+    type Definition = Definition;
+
+    fn call(&self, mut context: client::rpc::typed::Context<Definition>, mut request: Request) {
         const FILE_PREFIX: &'static str = "file://";
-        let mut request: Request = try_rpc!(context, serde_json::from_value(args));
         if !request.uri.starts_with(FILE_PREFIX) {
-            context.finish(rpc::Result::NotHandled).unwrap();
+            context.not_handled().unwrap();
             return;
         }
         request.uri.drain(..FILE_PREFIX.len());
 
-        let mut file = try_rpc!(context, fs::File::open(path::Path::new(&request.uri)));
+        let mut file = try_typed_rpc!(context, fs::File::open(path::Path::new(&request.uri)));
         let mut content = String::new();
-        try_rpc!(context, file.read_to_string(&mut content));
+        try_typed_rpc!(context, file.read_to_string(&mut content));
 
         let buffer = base::Buffer::from_string(content);
 
@@ -46,7 +56,7 @@ impl client::rpc::server::Rpc for Rpc {
         let response = Response {
             buffer_index: buffers.new_buffer(buffer),
         };
-        context.finish(rpc::Result::success(response)).unwrap();
+        context.finish(Ok(response)).unwrap();
     }
 }
 
@@ -66,10 +66,12 @@ impl client::rpc::server::Rpc for ListFiles {
     fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
         let request: ListFilesRequest = try_rpc!(context, serde_json::from_value(args));
         // NOCOM handle the result
-        let _ = self.client.write().unwrap().call("log.debug", &plugin::log::debug::Request {
+        if let Ok(rpc) = self.client.write().unwrap().call("log.debug", &plugin::log::debug::Request {
             message: String::from("list files called"),
             time: plugin::log::current(),
-        });
+        }) {
+            rpc.detach();
+        }
 
         thread::spawn(move || {
             let mut files = Vec::new();
@@ -2,9 +2,14 @@
 // Licensed under the Apache License, Version 2.0. See LICENSE.txt
 // in the project root for license information.
 use ::client;
+use ::error::{Error, Result};
 use ::rpc;
-use std::convert;
 use serde_json;
+use std::convert;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Request {
@@ -15,9 +20,64 @@ pub struct Request {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Response;
 
-pub fn log(mut context: client::rpc::server::Context, level: &str, args: serde_json::Value) {
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn parse(level: &str) -> Result<Self> {
+        match level {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            other => Err(Error::InvalidConfig(format!("Unknown log level '{}'.", other))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        }
+    }
+}
+
+/// Writes log messages of at least 'level' to stdout or a file.
+pub struct Logger {
+    level: Level,
+    output: Mutex<Box<Write + Send>>,
+}
+
+impl Logger {
+    pub fn stdout(level: Level) -> Self {
+        Logger {
+            level: level,
+            output: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+
+    /// Appends to the file at 'path', creating it if needed.
+    pub fn file(level: Level, path: &Path) -> Result<Self> {
+        let file = try!(fs::OpenOptions::new().create(true).append(true).open(path));
+        Ok(Logger {
+            level: level,
+            output: Mutex::new(Box::new(file)),
+        })
+    }
+}
+
+pub fn log(logger: &Logger, mut context: client::rpc::server::Context, level: Level, args: serde_json::Value) {
     let request: Request = try_rpc!(context, serde_json::from_value(args));
-    // NOCOM make output customizable
-    println!("{} - [{}] - {}", request.time, level, request.message);
+    if level >= logger.level {
+        let mut output = logger.output.lock().unwrap();
+        try_rpc!(context, writeln!(output, "{} - [{}] - {}", request.time, level.name(), request.message));
+    }
     context.finish(rpc::Result::success(Response)).unwrap();
 }
@@ -4,15 +4,18 @@
 use ::client;
 use ::plugin::log;
 use serde_json;
+use std::sync::Arc;
 
 pub type Request = log::base::Request;
 
 pub type Response = log::base::Response;
 
-pub struct Rpc;
+pub struct Rpc {
+    pub logger: Arc<log::base::Logger>,
+}
 
 impl client::rpc::server::Rpc for Rpc {
     fn call(&self, context: client::rpc::server::Context, args: serde_json::Value) {
-        log::base::log(context, "INFO", args)
+        log::base::log(&self.logger, context, log::base::Level::Info, args)
     }
 }
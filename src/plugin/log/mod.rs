@@ -4,20 +4,28 @@
 use ::client;
 use ::error::Result;
 use ::plugin;
+use std::sync::Arc;
 use time;
 
+pub use self::base::{Level, Logger};
+
 
 pub struct Plugin {
     _client: client::Client,
 }
 
 impl Plugin {
-    pub fn new(mut client: client::Client) -> Result<Self> {
+    pub fn new(client: client::Client) -> Result<Self> {
+        Plugin::with_logger(client, Logger::stdout(Level::Debug))
+    }
+
+    pub fn with_logger(mut client: client::Client, logger: Logger) -> Result<Self> {
+        let logger = Arc::new(logger);
         try!(plugin::register_rpc(&mut client, rpc_map! {
-            "log.debug" => debug::Rpc,
-            "log.info" => info::Rpc,
-            "log.warn" => warn::Rpc,
-            "log.error" => error::Rpc,
+            "log.debug" => debug::Rpc { logger: logger.clone() },
+            "log.info" => info::Rpc { logger: logger.clone() },
+            "log.warn" => warn::Rpc { logger: logger.clone() },
+            "log.error" => error::Rpc { logger: logger.clone() },
         }));
         Ok(Plugin{
             _client: client,
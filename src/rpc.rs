@@ -2,8 +2,10 @@
 // Licensed under the Apache License, Version 2.0. See LICENSE.txt
 // in the project root for license information.
 
+use ::blob::Blob;
 use serde;
 use serde_json;
+use std::fmt;
 use std::error::Error as StdError;
 
 // NOCOM(#sirver): add documentation (using this lint that forbids not having documentation).
@@ -20,6 +22,33 @@ pub struct Response {
     pub kind: ResponseKind,
 }
 
+/// A successful last response whose result is in 'blob' instead of the message. Only sent to
+/// clients on the same host, see 'blob'.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseBlob {
+    pub context: String,
+    pub blob: Blob,
+}
+
+impl ResponseBlob {
+    /// The response with the result taken out of 'blob'. If that fails, e.g. because the Blob
+    /// expired, the response is an error.
+    pub fn take(&self) -> Response {
+        let value: ::error::Result<serde_json::Value> = self.blob.take().and_then(|data| {
+            serde_json::from_slice(&data).map_err(::error::Error::from)
+        });
+        let result = match value {
+            Ok(value) => Result::Ok(value),
+            Err(err) => Result::Err(Error::new(
+                    ErrorKind::Io, &format!("The result could not be taken from its Blob: {}", err))),
+        };
+        Response {
+            context: self.context.clone(),
+            kind: ResponseKind::Last(result),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StreamingResult {
     pub context: String,
@@ -31,23 +60,79 @@ pub enum ErrorKind {
     UnknownRpc,
     Io,
     InvalidArgs,
+    Timeout,
+    PermissionDenied,
+    RateLimited,
+    /// The call would have to be handled by a client that is waiting for it to finish.
+    Deadlock,
+    /// The handler panicked or dropped the call without answering it.
+    Internal,
+    /// The caller cancelled the call or the RPC it was made from ended.
+    Cancelled,
+    /// Something the call refers to does not exist.
+    NotFound,
+    /// An error defined by a plugin. Callers can match on the code.
+    Custom(String),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Error {
     pub kind: ErrorKind,
+    /// Explains the error to humans. Programs should look at 'kind' and 'details'.
+    pub message: Option<String>,
     pub details: Option<serde_json::Value>,
 }
 
+impl Error {
+    pub fn new(kind: ErrorKind, message: &str) -> Self {
+        Error {
+            kind: kind,
+            message: Some(message.into()),
+            details: None,
+        }
+    }
+
+    pub fn custom(code: &str, message: &str) -> Self {
+        Error::new(ErrorKind::Custom(code.into()), message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ErrorKind::Custom(ref code) => code.clone(),
+            ref other => format!("{:?}", other),
+        };
+        match self.message {
+            Some(ref message) => write!(f, "{}: {}", kind, message),
+            None => write!(f, "{}", kind),
+        }
+    }
+}
+
 impl From<serde_json::error::Error> for Error {
      fn from(error: serde_json::error::Error) -> Self {
          Error {
              kind: ErrorKind::InvalidArgs,
+             message: Some(format!("{}", error)),
              details: Some(serde_json::to_value(&error.description())),
          }
      }
 }
 
+/// Describes an RPC, so that the plugin serving it and its callers agree on its name and types at
+/// compile time. See 'client::rpc::typed'.
+pub trait RpcDefinition {
+    /// The arguments.
+    type Request: serde::Serialize + serde::Deserialize;
+    /// The value of a successful result.
+    type Response: serde::Serialize + serde::Deserialize;
+    /// The partial results of a streaming RPC. '()' if there are none.
+    type Update: serde::Serialize + serde::Deserialize;
+
+    fn name() -> &'static str;
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum Result {
     // NOCOM(#sirver): mention success as a convenient creating for this.
@@ -92,9 +177,45 @@ pub struct Call {
     pub function: String,
     pub context: String,
     pub args: serde_json::Value,
+    /// The context of the call this one was made from, if it was made from inside an RPC.
+    pub parent: Option<String>,
+    /// Shared by a call and all calls made from it, directly or indirectly. It is the context of
+    /// the outermost call.
+    pub trace_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Cancel {
     pub context: String,
 }
+
+/// Sent by the caller of a running RPC that no longer waits for its result. The call keeps
+/// running, but the server knows that the caller is not blocked on it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Detach {
+    pub context: String,
+}
+
+/// A one-way message. It is delivered to every client that subscribed to a prefix of 'topic'
+/// through 'core.subscribe' and is never answered.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Notification {
+    pub topic: String,
+    pub args: serde_json::Value,
+}
+
+/// Notifications delivered for the subscription to 'prefix'. There are several if the
+/// subscription asked for them to be coalesced.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Notifications {
+    pub prefix: String,
+    pub notifications: Vec<Notification>,
+}
+
+/// A value streamed by the caller of a running RPC to its handler.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CallPartial {
+    pub context: String,
+    /// None tells the handler that the caller has nothing more to send.
+    pub value: Option<serde_json::Value>,
+}
@@ -3,12 +3,15 @@
 // in the project root for license information.
 
 use ::server::ipc_bridge;
+use ::server::plugin_core;
 use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct ApiInfo {
     pub client_id: ipc_bridge::ClientId,
     pub priority: u16,
+    /// As announced by the client, see 'plugin_core::NewRpcRequest'.
+    pub execution: Option<plugin_core::Execution>,
 }
 
 pub struct ApiTable {
@@ -43,6 +46,13 @@ impl ApiTable {
         }
     }
 
+    /// All registrations, ordered by priority for each name.
+    pub fn entries(&self) -> Vec<(&String, &ApiInfo)> {
+        self.name_infos.iter()
+            .flat_map(|(name, infos)| infos.iter().map(move |info| (name, info)))
+            .collect()
+    }
+
     pub fn get_first(&self, name: &String) -> Option<&ApiInfo> {
         match self.name_infos.get(name) {
             Some(infos) => infos.first(),
@@ -50,6 +60,14 @@ impl ApiTable {
         }
     }
 
+    /// The registration of 'client_id' for 'name'.
+    pub fn get(&self, name: &String, client_id: &ipc_bridge::ClientId) -> Option<&ApiInfo> {
+        match self.name_infos.get(name) {
+            Some(infos) => infos.iter().find(|info| info.client_id == *client_id),
+            None => None
+        }
+    }
+
     pub fn get_next(&self, name: &String, client_id: &ipc_bridge::ClientId) -> Option<&ApiInfo> {
         match self.name_infos.get(name) {
             Some(infos) => {
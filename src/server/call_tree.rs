@@ -0,0 +1,215 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Keeps the timings of recent calls, grouped by trace. Calls made from inside an RPC carry the
+//! context of that RPC as 'parent' and share its 'trace_id' (see 'rpc::Call'), so every trace
+//! forms a tree. 'core.call_trees' returns these trees, including calls that are still running,
+//! which shows where the time of a slow call went.
+
+use ::rpc;
+use std::collections::{HashMap, VecDeque};
+use time;
+
+/// Number of traces that are remembered. Older ones are forgotten.
+pub const DEFAULT_MAX_TRACES: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Error,
+    NotHandled,
+    TimedOut,
+    /// The caller cancelled the call.
+    Cancelled,
+}
+
+impl Outcome {
+    pub fn from_result(result: &rpc::Result) -> Self {
+        match *result {
+            rpc::Result::Ok(_) => Outcome::Ok,
+            rpc::Result::Err(_) => Outcome::Error,
+            rpc::Result::NotHandled => Outcome::NotHandled,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        *self == Outcome::Error || *self == Outcome::TimedOut
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Outcome::Ok => "ok",
+            Outcome::Error => "error",
+            Outcome::NotHandled => "not_handled",
+            Outcome::TimedOut => "timed_out",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Arguments of 'core.call_trees'.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CallTreesRequest {
+    /// Only trees whose outermost call is to this function.
+    pub function: Option<String>,
+    /// Only trees whose outermost call took at least this long (or is running for this long).
+    pub min_duration_us: Option<i64>,
+    /// Only this many of the most recent trees.
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CallNode {
+    pub context: String,
+    pub function: String,
+    /// Serial of the calling client.
+    pub caller: u64,
+    /// When the call was received, relative to the outermost call.
+    pub offset_us: i64,
+    pub duration_us: i64,
+    /// "ok", "error", "not_handled", "timed_out", "cancelled" or "running".
+    pub outcome: String,
+    pub children: Vec<CallNode>,
+}
+
+/// Returned by 'core.call_trees', oldest tree first.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CallTreesResponse {
+    pub trees: Vec<CallNode>,
+}
+
+/// A call that is still running, as passed to 'CallTrees::report'.
+pub struct RunningCall<'a> {
+    pub rpc_call: &'a rpc::Call,
+    pub caller: u64,
+    pub started: time::SteadyTime,
+}
+
+#[derive(Clone)]
+struct Span {
+    context: String,
+    parent: Option<String>,
+    function: String,
+    caller: u64,
+    started: time::SteadyTime,
+    duration: time::Duration,
+    outcome: &'static str,
+}
+
+fn trace_id(rpc_call: &rpc::Call) -> &str {
+    // Callers that do not know about tracing start a trace with every call.
+    rpc_call.trace_id.as_ref().unwrap_or(&rpc_call.context)
+}
+
+pub struct CallTrees {
+    max_traces: usize,
+    traces: HashMap<String, Vec<Span>>,
+    // Trace ids, oldest first.
+    order: VecDeque<String>,
+}
+
+impl CallTrees {
+    pub fn new(max_traces: usize) -> Self {
+        CallTrees {
+            max_traces: max_traces,
+            traces: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records a call that just got its final answer.
+    pub fn finished(&mut self, rpc_call: &rpc::Call, caller: u64, started: time::SteadyTime, outcome: Outcome) {
+        let trace_id = trace_id(rpc_call).to_string();
+        if !self.traces.contains_key(&trace_id) {
+            if self.order.len() >= self.max_traces {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.traces.remove(&oldest);
+                }
+            }
+            self.order.push_back(trace_id.clone());
+            self.traces.insert(trace_id.clone(), Vec::new());
+        }
+        self.traces.get_mut(&trace_id).unwrap().push(Span {
+            context: rpc_call.context.clone(),
+            parent: rpc_call.parent.clone(),
+            function: rpc_call.function.clone(),
+            caller: caller,
+            started: started,
+            duration: time::SteadyTime::now() - started,
+            outcome: outcome.name(),
+        });
+    }
+
+    pub fn report(&self, request: &CallTreesRequest, running: &[RunningCall]) -> CallTreesResponse {
+        let now = time::SteadyTime::now();
+        let mut traces = self.traces.clone();
+        let mut order: Vec<_> = self.order.iter().cloned().collect();
+        for call in running {
+            let trace_id = trace_id(call.rpc_call).to_string();
+            if !traces.contains_key(&trace_id) {
+                order.push(trace_id.clone());
+            }
+            traces.entry(trace_id).or_insert(Vec::new()).push(Span {
+                context: call.rpc_call.context.clone(),
+                parent: call.rpc_call.parent.clone(),
+                function: call.rpc_call.function.clone(),
+                caller: call.caller,
+                started: call.started,
+                duration: now - call.started,
+                outcome: "running",
+            });
+        }
+
+        let mut trees = Vec::new();
+        for trace_id in &order {
+            let spans = &traces[trace_id];
+            let is_root = |span: &Span| match span.parent {
+                Some(ref parent) => !spans.iter().any(|other| other.context == *parent),
+                None => true,
+            };
+            let mut roots: Vec<_> = spans.iter().filter(|span| is_root(span)).collect();
+            roots.sort_by(|a, b| a.started.cmp(&b.started));
+            for root in roots {
+                if let Some(ref function) = request.function {
+                    if root.function != *function {
+                        continue;
+                    }
+                }
+                if let Some(min_duration_us) = request.min_duration_us {
+                    if root.duration.num_microseconds().unwrap_or(::std::i64::MAX) < min_duration_us {
+                        continue;
+                    }
+                }
+                trees.push(build_node(root, root.started, spans));
+            }
+        }
+        if let Some(limit) = request.limit {
+            if trees.len() > limit {
+                let skip = trees.len() - limit;
+                trees.drain(..skip);
+            }
+        }
+        CallTreesResponse {
+            trees: trees,
+        }
+    }
+}
+
+fn build_node(span: &Span, root_started: time::SteadyTime, spans: &[Span]) -> CallNode {
+    let mut children: Vec<_> = spans.iter()
+        .filter(|child| child.parent.as_ref() == Some(&span.context))
+        .collect();
+    children.sort_by(|a, b| a.started.cmp(&b.started));
+    CallNode {
+        context: span.context.clone(),
+        function: span.function.clone(),
+        caller: span.caller,
+        offset_us: (span.started - root_started).num_microseconds().unwrap_or(0),
+        duration_us: span.duration.num_microseconds().unwrap_or(::std::i64::MAX),
+        outcome: span.outcome.into(),
+        children: children.into_iter()
+            .map(|child| build_node(child, root_started, spans))
+            .collect(),
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Server configuration. It is usually read from a JSON file like this one:
+//!
+//! ```json
+//! {
+//!     "unix_sockets": ["/tmp/swiboe.socket"],
+//!     "tcp_addresses": ["127.0.0.1:12345"],
+//!     "builtin_plugins": ["buffer", "log"],
+//!     "plugin_directory": "/usr/local/share/swiboe/plugins",
+//!     "ipc_threads": 8,
+//!     "rpc_timeout_ms": 5000,
+//!     "log": { "level": "info", "file": "/var/log/swiboe.log" },
+//!     "pidfile": "/var/run/swiboe.pid",
+//!     "handover_socket": "/tmp/swiboe.handover",
+//!     "permissions": [{ "listener": "127.0.0.1:12345", "call": { "deny": ["core"] } }],
+//!     "rate_limits": [{ "max_in_flight": 100, "calls_per_second": 1000 }],
+//!     "heartbeat": { "interval_ms": 5000, "timeout_ms": 15000 },
+//!     "trace": { "file": "/tmp/swiboe.trace", "max_bytes": 10485760, "max_files": 3 },
+//!     "prometheus_address": "127.0.0.1:9123"
+//! }
+//! ```
+//!
+//! All fields are optional.
+
+use ::error::{Error, Result};
+use ::plugin;
+use ::rpc;
+use ::server::permissions;
+use ::server::quota;
+use ::server::tracer;
+use serde_json;
+use std::fs;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use time;
+
+pub const BUILTIN_PLUGINS: [&'static str; 3] = ["buffer", "list_files", "log"];
+
+const DEFAULT_IPC_THREADS: usize = 4;
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 5000;
+const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 15000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LogConfig {
+    /// One of "debug" (the default), "info", "warn" or "error".
+    pub level: Option<String>,
+    /// Log to this file instead of stdout.
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HeartbeatConfig {
+    /// How often clients connected through a socket are pinged. 0 turns heartbeats off.
+    pub interval_ms: Option<u64>,
+    /// Clients that did not answer a ping for this long are disconnected.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TraceConfig {
+    /// Where 'core.trace' writes to if it is not given a file.
+    pub file: Option<String>,
+    /// Size at which the trace file is rotated.
+    pub max_bytes: Option<u64>,
+    /// Number of rotated files to keep.
+    pub max_files: Option<usize>,
+    /// Start tracing right away instead of waiting for 'core.trace'.
+    pub enabled: Option<bool>,
+}
+
+/// Keepalive settings, used by the server and by clients (see 'Client::set_heartbeat'). A peer
+/// is pinged every 'interval' and given up on if it did not answer for 'timeout'.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heartbeat {
+    pub interval: time::Duration,
+    pub timeout: time::Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Config {
+    /// Unix domain sockets to listen on. The first one is handed to external plugins.
+    pub unix_sockets: Option<Vec<String>>,
+    /// IP addresses to listen on, e.g. 0.0.0.0:12345.
+    pub tcp_addresses: Option<Vec<String>>,
+    /// The built-in plugins to start. Defaults to all of them.
+    pub builtin_plugins: Option<Vec<String>>,
+    /// Directory with manifests of external plugins to supervise.
+    pub plugin_directory: Option<String>,
+    /// Number of threads used for socket IO.
+    pub ipc_threads: Option<usize>,
+    /// RPCs that did not finish within this time are answered with a Timeout error.
+    pub rpc_timeout_ms: Option<u64>,
+    pub log: Option<LogConfig>,
+    /// Write the process id of the server to this file while it is running.
+    pub pidfile: Option<String>,
+    /// A new server process can take over this server's clients through this socket. See
+    /// 'server::handover'.
+    pub handover_socket: Option<String>,
+    /// Restrictions on what clients may call and register. See 'server::permissions'.
+    pub permissions: Option<Vec<permissions::PermissionRule>>,
+    /// Limits on how much load a client may cause. See 'server::quota'.
+    pub rate_limits: Option<Vec<quota::RateLimitRule>>,
+    /// Pinging of clients connected through a socket. Off unless given.
+    pub heartbeat: Option<HeartbeatConfig>,
+    /// Tracing of all messages, see 'server::tracer'.
+    pub trace: Option<TraceConfig>,
+    /// Serve the stats of 'core.stats' for Prometheus on this address, see 'server::stats'.
+    pub prometheus_address: Option<String>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut file = try!(fs::File::open(path));
+        let mut content = String::new();
+        try!(file.read_to_string(&mut content));
+        let config: Config = try!(serde_json::from_str(&content));
+        try!(config.validate());
+        Ok(config)
+    }
+
+    /// Checks for values that would only be noticed when the server is already half started.
+    pub fn validate(&self) -> Result<()> {
+        if self.unix_sockets().is_empty() {
+            return Err(Error::InvalidConfig("At least one unix socket is required.".into()));
+        }
+        for name in self.builtin_plugins() {
+            if !BUILTIN_PLUGINS.contains(&&name[..]) {
+                return Err(Error::InvalidConfig(format!("Unknown built-in plugin '{}'.", name)));
+            }
+        }
+        if self.ipc_threads() == 0 {
+            return Err(Error::InvalidConfig("ipc_threads must be at least 1.".into()));
+        }
+        try!(self.log_level());
+        if let Some(heartbeat) = self.heartbeat() {
+            if heartbeat.timeout <= heartbeat.interval {
+                return Err(Error::InvalidConfig(
+                        "The heartbeat timeout must be longer than the interval.".into()));
+            }
+        }
+        for rule in self.permissions.iter().flat_map(|rules| rules.iter()) {
+            try!(rule.validate());
+        }
+        if let Some(ref address) = self.prometheus_address {
+            if address.parse::<SocketAddr>().is_err() {
+                return Err(Error::InvalidConfig(
+                        format!("Invalid prometheus_address '{}'.", address)));
+            }
+        }
+        if let Some(ref trace) = self.trace {
+            if trace.enabled == Some(true) && trace.file.is_none() {
+                return Err(Error::InvalidConfig("Tracing needs a 'file' to write to.".into()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn unix_sockets(&self) -> Vec<PathBuf> {
+        self.unix_sockets.as_ref()
+            .map(|sockets| sockets.iter().map(PathBuf::from).collect())
+            .unwrap_or(Vec::new())
+    }
+
+    pub fn tcp_addresses(&self) -> Vec<String> {
+        self.tcp_addresses.clone().unwrap_or(Vec::new())
+    }
+
+    pub fn builtin_plugins(&self) -> Vec<String> {
+        self.builtin_plugins.clone().unwrap_or(
+            BUILTIN_PLUGINS.iter().map(|name| name.to_string()).collect())
+    }
+
+    pub fn builtin_plugin_enabled(&self, name: &str) -> bool {
+        self.builtin_plugins().iter().any(|enabled| enabled == name)
+    }
+
+    pub fn plugin_directory(&self) -> Option<PathBuf> {
+        self.plugin_directory.as_ref().map(PathBuf::from)
+    }
+
+    pub fn pidfile(&self) -> Option<PathBuf> {
+        self.pidfile.as_ref().map(PathBuf::from)
+    }
+
+    pub fn prometheus_address(&self) -> Option<SocketAddr> {
+        self.prometheus_address.as_ref().and_then(|address| address.parse().ok())
+    }
+
+    pub fn handover_socket(&self) -> Option<PathBuf> {
+        self.handover_socket.as_ref().map(PathBuf::from)
+    }
+
+    pub fn permissions(&self) -> permissions::Permissions {
+        permissions::Permissions::new(self.permissions.clone().unwrap_or(Vec::new()))
+    }
+
+    pub fn rate_limits(&self) -> quota::RateLimits {
+        quota::RateLimits::new(self.rate_limits.clone().unwrap_or(Vec::new()))
+    }
+
+    /// None if heartbeats are turned off, which they are unless configured.
+    pub fn heartbeat(&self) -> Option<Heartbeat> {
+        let config = match self.heartbeat {
+            Some(ref config) => config.clone(),
+            None => return None,
+        };
+        let interval_ms = config.interval_ms.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_MS);
+        if interval_ms == 0 {
+            return None;
+        }
+        let timeout_ms = config.timeout_ms.unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT_MS);
+        Some(Heartbeat {
+            interval: time::Duration::milliseconds(interval_ms as i64),
+            timeout: time::Duration::milliseconds(timeout_ms as i64),
+        })
+    }
+
+    /// A tracer that is already enabled if the config says so.
+    pub fn tracer(&self) -> tracer::Tracer {
+        let config = self.trace.clone().unwrap_or(TraceConfig::default());
+        let mut tracer = tracer::Tracer::new(
+            config.file.as_ref().map(PathBuf::from),
+            config.max_bytes.unwrap_or(tracer::DEFAULT_MAX_BYTES),
+            config.max_files.unwrap_or(tracer::DEFAULT_MAX_FILES));
+        if config.enabled == Some(true) {
+            if let rpc::Result::Err(err) = tracer.configure(tracer::TraceRequest {
+                enabled: true,
+                .. tracer::TraceRequest::default()
+            }) {
+                println!("Could not start tracing: {:?}", err);
+            }
+        }
+        tracer
+    }
+
+    pub fn ipc_threads(&self) -> usize {
+        self.ipc_threads.unwrap_or(DEFAULT_IPC_THREADS)
+    }
+
+    pub fn rpc_timeout(&self) -> Option<time::Duration> {
+        self.rpc_timeout_ms.map(|ms| time::Duration::milliseconds(ms as i64))
+    }
+
+    pub fn log_level(&self) -> Result<plugin::log::Level> {
+        match self.log.as_ref().and_then(|log| log.level.as_ref()) {
+            Some(level) => plugin::log::Level::parse(level),
+            None => Ok(plugin::log::Level::Debug),
+        }
+    }
+
+    pub fn logger(&self) -> Result<plugin::log::Logger> {
+        let level = try!(self.log_level());
+        match self.log.as_ref().and_then(|log| log.file.as_ref()) {
+            Some(file) => plugin::log::Logger::file(level, Path::new(file)),
+            None => Ok(plugin::log::Logger::stdout(level)),
+        }
+    }
+}
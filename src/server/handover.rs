@@ -0,0 +1,341 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Hands a running server over to a new process without disconnecting any client.
+//!
+//! The old server listens on a handover socket (see 'Config::handover_socket'). A new server
+//! started with 'Server::take_over' connects to it and receives the listening sockets and all
+//! client connections as file descriptors (SCM_RIGHTS), together with a JSON description of
+//! everything needed to continue: data read or queued for writing but not yet processed, the
+//! registered RPCs, the running RPCs, the buffers and the pids of the supervised plugins.
+//!
+//! Once the new server is up, it confirms the handover. Only then the old server lets go of the
+//! socket files and the pidfile and closes the connection, after which the new server binds the
+//! handover socket for the next restart. If the new server goes away without confirming, the old
+//! one cleans up as if it was shut down.
+//!
+//! In-process clients (i.e. the built-in plugins) do not survive: the new server starts its own.
+//! Calls they made are cancelled, calls they were handling are run again by the new ones.
+
+use ::error::{Error, Result};
+use ::plugin;
+use ::rpc;
+use ::server::plugin_core;
+use ::server::plugin_manager;
+use libc;
+use serde_json;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use unix_socket::UnixStream;
+
+/// Sent by the new server to ask for the handover. If the connection is closed instead, the old
+/// server stops waiting for a handover.
+pub const REQUEST: u8 = b'h';
+
+/// Sent by the new server once it is running.
+pub const CONFIRM: u8 = b'c';
+
+// The kernel refuses to pass more than 253 descriptors in one message.
+const MAX_FDS_PER_MESSAGE: usize = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ListenerAddress {
+    Unix(String),
+    Tcp(String),
+}
+
+impl ListenerAddress {
+    /// The socket path or IP address. Used to identify listeners in the config.
+    pub fn name(&self) -> &str {
+        match *self {
+            ListenerAddress::Unix(ref path) => path,
+            ListenerAddress::Tcp(ref address) => address,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionState {
+    pub serial: u64,
+    pub tcp: bool,
+    pub listener: String,
+    /// Received, but not yet a full message.
+    pub unread: Vec<u8>,
+    /// Queued for the client, but not yet written.
+    pub unwritten: Vec<u8>,
+}
+
+/// A registered RPC. Clients are identified by their serial only, since the new server assigns
+/// new tokens.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiEntry {
+    pub name: String,
+    pub client: u64,
+    pub priority: u16,
+    pub execution: Option<plugin_core::Execution>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunningRpcState {
+    pub caller: u64,
+    /// None if a built-in plugin was handling the call. It is dispatched again then.
+    pub callee: Option<u64>,
+    pub rpc_call: rpc::Call,
+    /// The caller does not wait for the result anymore.
+    pub detached: bool,
+    /// What the caller streamed, if it is still kept for the next handler.
+    pub partials: Option<Vec<rpc::CallPartial>>,
+    /// Streamed values were not kept, so the call cannot be handed on.
+    pub partials_lost: bool,
+}
+
+/// The name a client gave itself in 'core.handshake'.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientName {
+    pub client: u64,
+    pub name: String,
+}
+
+/// The thread pool size a client announced through 'core.set_thread_pool_size'.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ThreadPool {
+    pub client: u64,
+    pub size: usize,
+}
+
+/// A prefix a client subscribed to through 'core.subscribe'. Held back notifications are delivered
+/// before the handover.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Subscription {
+    pub client: u64,
+    pub prefix: String,
+    pub coalesce: Option<plugin_core::Coalesce>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SwiboeState {
+    pub client_names: Vec<ClientName>,
+    pub thread_pools: Vec<ThreadPool>,
+    pub subscriptions: Vec<Subscription>,
+    pub api_table: Vec<ApiEntry>,
+    pub running_rpcs: Vec<RunningRpcState>,
+}
+
+/// What the IpcBridge gives up. 'fds' contains the listeners first, then the connections, in the
+/// same order as their descriptions.
+pub struct BridgeState {
+    pub listeners: Vec<ListenerAddress>,
+    pub connections: Vec<ConnectionState>,
+    pub fds: Vec<RawFd>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct State {
+    pub listeners: Vec<ListenerAddress>,
+    pub connections: Vec<ConnectionState>,
+    pub swiboe: SwiboeState,
+    pub buffers: Option<plugin::buffer::Snapshot>,
+    pub plugins: Vec<plugin_manager::RunningPlugin>,
+}
+
+/// A State and the file descriptors belonging to it.
+pub struct Received {
+    pub state: State,
+    pub fds: Vec<RawFd>,
+    /// To 'confirm' the handover on.
+    pub stream: UnixStream,
+}
+
+fn cmsg_align(len: usize) -> usize {
+    let align = mem::size_of::<usize>();
+    (len + align - 1) & !(align - 1)
+}
+
+fn cmsg_len(data_len: usize) -> usize {
+    cmsg_align(mem::size_of::<libc::cmsghdr>()) + data_len
+}
+
+fn cmsg_space(data_len: usize) -> usize {
+    cmsg_align(mem::size_of::<libc::cmsghdr>()) + cmsg_align(data_len)
+}
+
+// Sends 'fds' in as few messages as possible. Every message carries a single byte of payload,
+// since ancillary data cannot be sent on its own.
+fn send_fds(socket: RawFd, fds: &[RawFd]) -> io::Result<()> {
+    for chunk in fds.chunks(MAX_FDS_PER_MESSAGE) {
+        let data_len = chunk.len() * mem::size_of::<RawFd>();
+        let mut control = vec![0u8; cmsg_space(data_len)];
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        unsafe {
+            let mut message: libc::msghdr = mem::zeroed();
+            message.msg_iov = &mut iov;
+            message.msg_iovlen = 1;
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            message.msg_controllen = control.len() as _;
+
+            let header = control.as_mut_ptr() as *mut libc::cmsghdr;
+            (*header).cmsg_len = cmsg_len(data_len) as _;
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            let data = control.as_mut_ptr().offset(cmsg_len(0) as isize) as *mut RawFd;
+            ptr::copy_nonoverlapping(chunk.as_ptr(), data, chunk.len());
+
+            if libc::sendmsg(socket, &message, 0) != 1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Received descriptors must not leak into the plugins we start. Linux marks them close-on-exec
+// while receiving them, elsewhere it is done afterwards.
+#[cfg(target_os = "linux")]
+const RECEIVE_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECEIVE_FLAGS: libc::c_int = 0;
+
+#[cfg(target_os = "linux")]
+fn set_cloexec(_: RawFd) {
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_cloexec(fd: RawFd) {
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+}
+
+fn receive_fds(socket: RawFd, count: usize) -> io::Result<Vec<RawFd>> {
+    let mut fds = Vec::with_capacity(count);
+    while fds.len() < count {
+        let mut control = vec![0u8; cmsg_space(MAX_FDS_PER_MESSAGE * mem::size_of::<RawFd>())];
+        let mut byte = [0u8; 1];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+            iov_len: 1,
+        };
+        unsafe {
+            let mut message: libc::msghdr = mem::zeroed();
+            message.msg_iov = &mut iov;
+            message.msg_iovlen = 1;
+            message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            message.msg_controllen = control.len() as _;
+
+            match libc::recvmsg(socket, &mut message, RECEIVE_FLAGS) {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               "Handover ended before all sockets were received.")),
+                1 => (),
+                _ => return Err(io::Error::last_os_error()),
+            }
+            // Every message we get carries descriptors, anything else means we are out of step.
+            let header = control.as_ptr() as *const libc::cmsghdr;
+            if message.msg_controllen as usize == 0 ||
+                (*header).cmsg_level != libc::SOL_SOCKET || (*header).cmsg_type != libc::SCM_RIGHTS {
+                close_fds(&fds);
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Handover message without sockets."));
+            }
+            let num_fds = ((*header).cmsg_len as usize - cmsg_len(0)) / mem::size_of::<RawFd>();
+            let data = control.as_ptr().offset(cmsg_len(0) as isize) as *const RawFd;
+            for index in 0..num_fds {
+                let fd = *data.offset(index as isize);
+                set_cloexec(fd);
+                fds.push(fd);
+            }
+        }
+    }
+    Ok(fds)
+}
+
+fn encode_length(len: usize) -> [u8; 4] {
+    [(len >> 0) as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]
+}
+
+fn decode_length(buf: &[u8; 4]) -> usize {
+    ((buf[3] as usize) << 24) |
+    ((buf[2] as usize) << 16) |
+    ((buf[1] as usize) <<  8) |
+    ((buf[0] as usize) <<  0)
+}
+
+// Returns false if the connection was closed or something else than 'expected' came in.
+fn wait_for(stream: &mut UnixStream, expected: u8) -> Result<bool> {
+    let mut byte = [0u8; 1];
+    match stream.read(&mut byte) {
+        Ok(1) if byte[0] == expected => Ok(true),
+        Ok(_) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Waits for the handover request of a new server on 'stream'. Returns false if the connection
+/// was closed without one.
+pub fn wait_for_request(stream: &mut UnixStream) -> Result<bool> {
+    wait_for(stream, REQUEST)
+}
+
+/// Waits for the new server to confirm that it is running. Returns false if it went away.
+pub fn wait_for_confirmation(stream: &mut UnixStream) -> Result<bool> {
+    wait_for(stream, CONFIRM)
+}
+
+/// Sends 'state' and 'fds' to the new server. The file descriptors stay open in this process,
+/// closing them is up to the caller.
+pub fn send(stream: &mut UnixStream, state: &State, fds: &[RawFd]) -> Result<()> {
+    let buffer = try!(serde_json::to_vec(state));
+    try!(stream.write_all(&encode_length(buffer.len())));
+    try!(stream.write_all(&buffer));
+    try!(send_fds(stream.as_raw_fd(), fds));
+    Ok(())
+}
+
+/// Connects to the server listening on 'handover_socket' and takes over its state.
+pub fn receive(handover_socket: &Path) -> Result<Received> {
+    let mut stream = try!(UnixStream::connect(handover_socket));
+    try!(stream.write_all(&[REQUEST]));
+
+    let mut size_buf = [0u8; 4];
+    try!(stream.read_exact(&mut size_buf));
+    let mut buffer = vec![0u8; decode_length(&size_buf)];
+    try!(stream.read_exact(&mut buffer));
+    let state: State = try!(serde_json::from_slice(&buffer));
+
+    let fds = try!(receive_fds(stream.as_raw_fd(), state.listeners.len() + state.connections.len()));
+    if fds.len() != state.listeners.len() + state.connections.len() {
+        close_fds(&fds);
+        return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidData, "Received more sockets than announced.")));
+    }
+    Ok(Received {
+        state: state,
+        fds: fds,
+        stream: stream,
+    })
+}
+
+/// Tells the old server that we are running and waits until it let go of the socket files, the
+/// pidfile and the handover socket.
+pub fn confirm(stream: &mut UnixStream) -> Result<()> {
+    try!(stream.write_all(&[CONFIRM]));
+    // The old server closes the connection once it is done.
+    let mut rest = Vec::new();
+    try!(stream.read_to_end(&mut rest));
+    Ok(())
+}
+
+pub fn close_fds(fds: &[RawFd]) {
+    for fd in fds {
+        unsafe {
+            libc::close(*fd);
+        }
+    }
+}
@@ -4,19 +4,27 @@
 
 use ::ipc;
 use ::{Error, Result};
+use ::server::handover;
 use ::server::swiboe;
 use mio::tcp::{TcpListener, TcpStream};
 use mio::unix::{UnixListener, UnixStream};
 use mio;
+use libc;
+use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::net;
-use std::path::Path;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use threadpool::ThreadPool;
+use unix_socket;
 
-// Number of threads to use for handling IO.
-const NUM_THREADS: usize = 4;
+// Token used in the ClientIds of in-process clients. They never go through the event loop.
+pub const IN_PROCESS: mio::Token = mio::Token(::std::usize::MAX);
 
 #[derive(PartialEq, Eq, Clone, Copy, Hash, Debug)]
 pub struct ClientId {
@@ -28,7 +36,7 @@ pub struct ClientId {
 // socket, we need to clone them. Since we store them in slab (which means the trait cannot be
 // sized), we have to return boxes too.
 #[doc(hidden)]
-pub trait MioStream: Send + io::Read + io::Write + mio::Evented {
+pub trait MioStream: Send + io::Read + io::Write + mio::Evented + AsRawFd {
     fn try_clone(&self) -> io::Result<Box<MioStream>>;
 }
 
@@ -50,74 +58,332 @@ struct Connection<T: io::Read + io::Write> {
     reader: Option<ipc::Reader<T>>,
     writer: Arc<Mutex<ipc::Writer<T>>>,
     client_id: ClientId,
+    tcp: bool,
+    // Name of the listener through which the client connected.
+    listener: String,
+}
+
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Option<Box<MioStream>>> {
+        Ok(match *self {
+            Listener::Unix(ref listener) => {
+                try!(listener.accept()).map(|stream| Box::new(stream) as Box<MioStream>)
+            },
+            Listener::Tcp(ref listener) => {
+                try!(listener.accept()).map(|(stream, _)| Box::new(stream) as Box<MioStream>)
+            },
+        })
+    }
+
+    fn evented(&self) -> &mio::Evented {
+        match *self {
+            Listener::Unix(ref listener) => listener,
+            Listener::Tcp(ref listener) => listener,
+        }
+    }
+
+    fn is_tcp(&self) -> bool {
+        match *self {
+            Listener::Unix(_) => false,
+            Listener::Tcp(_) => true,
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Unix(ref listener) => listener.as_raw_fd(),
+            Listener::Tcp(ref listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+// The descriptors we hand over are duplicates, so that they stay valid after the IpcBridge has
+// been dropped.
+fn duplicate_fd(fd: RawFd) -> Result<RawFd> {
+    match unsafe { libc::dup(fd) } {
+        -1 => Err(Error::Io(io::Error::last_os_error())),
+        fd => Ok(fd),
+    }
+}
+
+// The process on the other end of a unix domain socket. Used to notice when a plugin we did not
+// start ourselves (one adopted during a handover) goes away.
+#[cfg(target_os = "linux")]
+fn peer_pid(fd: RawFd) -> Option<u32> {
+    unsafe {
+        let mut credentials: libc::ucred = ::std::mem::zeroed();
+        let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED,
+                                      &mut credentials as *mut _ as *mut libc::c_void, &mut len);
+        if result == 0 && credentials.pid > 0 {
+            Some(credentials.pid as u32)
+        } else {
+            None
+        }
+    }
+}
+
+// Other systems have no common way to ask. Adopted plugins going away are then not noticed, but
+// they are restarted with the server.
+#[cfg(not(target_os = "linux"))]
+fn peer_pid(_: RawFd) -> Option<u32> {
+    None
 }
 
 pub struct IpcBridge {
-    unix_listener: UnixListener,
-    tcp_listeners: Vec<TcpListener>,
+    // The listener at index 'i' is registered with mio::Token(i). All tokens after the listeners
+    // belong to connections.
+    listeners: Vec<Listener>,
+    listener_addresses: Vec<handover::ListenerAddress>,
     connections: mio::util::Slab<Connection<Box<MioStream>>>,
     commands: swiboe::SenderTo,
-    first_client_token: usize,
     next_serial: u64,
     thread_pool: ThreadPool,
+    // Set while preparing a handover. No new connections are accepted and no more messages read.
+    reading_stopped: bool,
+    // Answered once all readers are back from the thread pool after reading was stopped.
+    reading_stopped_reply: Option<mpsc::Sender<()>>,
+}
+
+/// A socket file left behind by a crashed server makes binding fail. We probe the socket: if
+/// nobody accepts connections on it anymore, it is stale and gets removed. If another server is
+/// still listening, this is an error.
+pub fn remove_stale_socket(socket_name: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(socket_name) {
+        Ok(metadata) => metadata,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} exists and is not a socket.", socket_name))));
+    }
+
+    match unix_socket::UnixStream::connect(socket_name) {
+        Ok(_) => Err(Error::Io(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("Another server is listening on {:?}.", socket_name)))),
+        Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+            println!("Removing stale socket {:?}.", socket_name);
+            try!(fs::remove_file(socket_name));
+            Ok(())
+        },
+        Err(err) => Err(err.into()),
+    }
 }
 
-const UNIX_LISTENER: mio::Token = mio::Token(0);
+// Setting up the listeners failed half way, so the socket files of the ones already bound must
+// not be left behind.
+fn remove_socket_files(listener_addresses: &[handover::ListenerAddress]) {
+    for address in listener_addresses {
+        if let handover::ListenerAddress::Unix(ref path) = *address {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
 
 impl IpcBridge {
     pub fn new(event_loop: &mut mio::EventLoop<Self>,
-               socket_name: &Path,
-               tcp_addresses: &Vec<String>,
-               server_commands: swiboe::SenderTo) -> Self {
-        let unix_listener = UnixListener::bind(socket_name).unwrap();
-        event_loop.register(
-            &unix_listener,
-            UNIX_LISTENER,
-            mio::EventSet::readable(),
-            mio::PollOpt::level()).unwrap();
-
-        let mut first_client_token = 1;
-        let tcp_listeners: Vec<_> =
-            tcp_addresses.iter()
-            .map(|addr| {
-                let addr = net::SocketAddr::from_str(addr).unwrap();
-                let server = TcpListener::bind(&addr).unwrap();
-                event_loop.register(
-                    &server,
-                    mio::Token(first_client_token),
-                    mio::EventSet::readable(),
-                    mio::PollOpt::level()).unwrap();
-                first_client_token += 1;
-                server
-            }).collect();
+               unix_sockets: &[PathBuf],
+               tcp_addresses: &[String],
+               num_threads: usize,
+               server_commands: swiboe::SenderTo) -> Result<Self> {
+        let mut listeners = Vec::new();
+        let mut listener_addresses = Vec::new();
+        for socket_name in unix_sockets {
+            let listener = remove_stale_socket(socket_name)
+                .and_then(|()| UnixListener::bind(socket_name).map_err(Error::from));
+            match listener {
+                Ok(listener) => listeners.push(Listener::Unix(listener)),
+                Err(err) => {
+                    remove_socket_files(&listener_addresses);
+                    return Err(err);
+                },
+            }
+            listener_addresses.push(handover::ListenerAddress::Unix(
+                    socket_name.to_string_lossy().into_owned()));
+        }
+        for addr in tcp_addresses {
+            let listener = net::SocketAddr::from_str(addr)
+                .map_err(|_| Error::InvalidConfig(format!("Invalid IP address '{}'.", addr)))
+                .and_then(|socket_addr| TcpListener::bind(&socket_addr).map_err(Error::from));
+            match listener {
+                Ok(listener) => listeners.push(Listener::Tcp(listener)),
+                Err(err) => {
+                    remove_socket_files(&listener_addresses);
+                    return Err(err);
+                },
+            }
+            listener_addresses.push(handover::ListenerAddress::Tcp(addr.clone()));
+        }
+
+        let ipc_bridge = IpcBridge::with_listeners(
+            listeners, listener_addresses, 1, num_threads, server_commands);
+        if let Err(err) = ipc_bridge.register_listeners(event_loop) {
+            remove_socket_files(&ipc_bridge.listener_addresses);
+            return Err(err);
+        }
+        Ok(ipc_bridge)
+    }
+
+    /// Continues the work of the IpcBridge of another server process, see 'server::handover'.
+    /// Returns the new ClientIds of all handed over connections, keyed by their serial.
+    pub fn from_handover(event_loop: &mut mio::EventLoop<Self>,
+                         state: handover::BridgeState,
+                         num_threads: usize,
+                         server_commands: swiboe::SenderTo)
+                         -> Result<(Self, HashMap<u64, ClientId>)> {
+        let handover::BridgeState { listeners: listener_addresses, connections, fds } = state;
+        let (listener_fds, connection_fds) = fds.split_at(listener_addresses.len());
+
+        let listeners = listener_addresses.iter().zip(listener_fds).map(|(address, fd)| {
+            unsafe {
+                match *address {
+                    handover::ListenerAddress::Unix(_) => Listener::Unix(UnixListener::from_raw_fd(*fd)),
+                    handover::ListenerAddress::Tcp(_) => Listener::Tcp(TcpListener::from_raw_fd(*fd)),
+                }
+            }
+        }).collect();
+        let next_serial = connections.iter().map(|connection| connection.serial + 1).max().unwrap_or(1);
 
+        let mut ipc_bridge = IpcBridge::with_listeners(
+            listeners, listener_addresses, next_serial, num_threads, server_commands);
+        try!(ipc_bridge.register_listeners(event_loop));
+
+        let mut clients = HashMap::new();
+        for (connection, fd) in connections.into_iter().zip(connection_fds) {
+            let stream = unsafe {
+                if connection.tcp {
+                    Box::new(TcpStream::from_raw_fd(*fd)) as Box<MioStream>
+                } else {
+                    Box::new(UnixStream::from_raw_fd(*fd)) as Box<MioStream>
+                }
+            };
+            let client_id = ipc_bridge.add_connection(
+                event_loop, stream, connection.tcp, connection.listener, connection.serial,
+                connection.unread, connection.unwritten);
+            clients.insert(connection.serial, client_id);
+        }
+        Ok((ipc_bridge, clients))
+    }
+
+    fn with_listeners(listeners: Vec<Listener>,
+                      listener_addresses: Vec<handover::ListenerAddress>,
+                      next_serial: u64,
+                      num_threads: usize,
+                      server_commands: swiboe::SenderTo) -> Self {
+        let first_client_token = listeners.len();
         IpcBridge {
-            unix_listener: unix_listener,
-            tcp_listeners: tcp_listeners,
-            first_client_token: first_client_token,
+            listeners: listeners,
+            listener_addresses: listener_addresses,
             connections: mio::util::Slab::new_starting_at(mio::Token(first_client_token), 1024),
             commands: server_commands,
-            next_serial: 1,
-            thread_pool: ThreadPool::new(NUM_THREADS),
+            next_serial: next_serial,
+            thread_pool: ThreadPool::new(num_threads),
+            reading_stopped: false,
+            reading_stopped_reply: None,
         }
     }
 
-    fn new_client<T: MioStream + 'static>(&mut self, event_loop: &mut mio::EventLoop<Self>, stream: Box<T>) {
-        // NOCOM(#sirver): can this be done in Some(token)?
+    fn register_listeners(&self, event_loop: &mut mio::EventLoop<Self>) -> Result<()> {
+        for (index, listener) in self.listeners.iter().enumerate() {
+            try!(event_loop.register(
+                listener.evented(),
+                mio::Token(index),
+                mio::EventSet::readable(),
+                mio::PollOpt::level()));
+        }
+        Ok(())
+    }
+
+    fn new_client(&mut self, event_loop: &mut mio::EventLoop<Self>, stream: Box<MioStream>, listener_index: usize) {
         let serial = self.next_serial;
-        let commands = self.commands.clone();
         self.next_serial += 1;
+        let tcp = self.listeners[listener_index].is_tcp();
+        let listener = self.listener_addresses[listener_index].name().to_string();
+        self.add_connection(event_loop, stream, tcp, listener, serial, Vec::new(), Vec::new());
+    }
+
+    fn stop_reading(&mut self, event_loop: &mut mio::EventLoop<Self>, reply: mpsc::Sender<()>) {
+        self.reading_stopped = true;
+        for listener in &self.listeners {
+            // We are going away anyways, so failing to deregister is harmless.
+            let _ = event_loop.deregister(listener.evented());
+        }
+        self.reading_stopped_reply = Some(reply);
+        self.reply_if_reading_stopped();
+    }
+
+    fn reply_if_reading_stopped(&mut self) {
+        if self.connections.iter().any(|conn| conn.reader.is_none()) {
+            return;
+        }
+        if let Some(reply) = self.reading_stopped_reply.take() {
+            let _ = reply.send(());
+        }
+    }
+
+    fn hand_over(&mut self) -> Result<handover::BridgeState> {
+        let mut fds = Vec::new();
+        for listener in &self.listeners {
+            fds.push(try!(duplicate_fd(listener.as_raw_fd())));
+        }
+
+        let mut connections = Vec::new();
+        for conn in self.connections.iter() {
+            let reader = conn.reader.as_ref().expect("Handover while still reading.");
+            // Taking the unwritten data makes sure that a write still pending in the thread pool
+            // does not send it a second time.
+            let unwritten = conn.writer.lock().unwrap().take_unwritten();
+            fds.push(try!(duplicate_fd(reader.socket.as_raw_fd())));
+            connections.push(handover::ConnectionState {
+                serial: conn.client_id.serial,
+                tcp: conn.tcp,
+                listener: conn.listener.clone(),
+                unread: reader.unread().to_vec(),
+                unwritten: unwritten,
+            });
+        }
+
+        Ok(handover::BridgeState {
+            listeners: self.listener_addresses.clone(),
+            connections: connections,
+            fds: fds,
+        })
+    }
+
+    fn add_connection(&mut self,
+                      event_loop: &mut mio::EventLoop<Self>,
+                      stream: Box<MioStream>,
+                      tcp: bool,
+                      listener: String,
+                      serial: u64,
+                      unread: Vec<u8>,
+                      unwritten: Vec<u8>) -> ClientId {
+        // NOCOM(#sirver): can this be done in Some(token)?
+        let commands = self.commands.clone();
+        let pid = if tcp { None } else { peer_pid(stream.as_raw_fd()) };
         match self.connections.insert_with(|token| {
             let client_id = ClientId {
                 serial: serial,
                 token: token,
             };
+            let mut writer = ipc::Writer::new(stream.try_clone().unwrap());
+            writer.queue_raw(unwritten);
             let connection = Connection {
-                writer: Arc::new(Mutex::new(ipc::Writer::new(stream.try_clone().unwrap()))),
-                reader: Some(ipc::Reader::new(stream)),
+                writer: Arc::new(Mutex::new(writer)),
+                reader: Some(ipc::Reader::with_buffer(stream, unread)),
                 client_id: client_id,
+                tcp: tcp,
+                listener: listener.clone(),
             };
-            commands.send(swiboe::Command::ClientConnected(client_id)).expect("ClientConnected");
+            commands.send(swiboe::Command::ClientConnected(client_id, listener, pid)).expect("ClientConnected");
             connection
         }) {
             Some(token) => {
@@ -129,20 +395,45 @@ impl IpcBridge {
                     mio::EventSet::readable(),
                     mio::PollOpt::level() | mio::PollOpt::oneshot()).unwrap();
 
-                // We have nothing to write right now, but we still need to register the socket
-                // once for writing. Otherwise reregister will fail later on.
+                // We might have nothing to write right now, but we still need to register the
+                // socket once for writing. Otherwise reregister will fail later on.
                 let writer = conn.writer.lock().unwrap();
                 event_loop.register(
                     &*writer.socket,
                     conn.client_id.token,
                     mio::EventSet::writable(),
                     mio::PollOpt::level() | mio::PollOpt::oneshot()).unwrap();
+                conn.client_id
             },
             None => {
                 // If we fail to insert, `conn` will go out of scope and be dropped.
                 panic!("Failed to insert connection into slab");
             }
-        };
+        }
+    }
+
+    fn disconnect(&mut self, event_loop: &mut mio::EventLoop<Self>, client_id: ClientId) {
+        let is_current = self.connections.get(client_id.token)
+            .map_or(false, |conn| conn.client_id == client_id);
+        if !is_current {
+            return;
+        }
+        let conn = self.connections.remove(client_id.token).unwrap();
+        // Errors do not matter here, we are dropping the connection anyways.
+        let writer = conn.writer.lock().unwrap();
+        let _ = event_loop.deregister(&*writer.socket);
+        if let Some(ref reader) = conn.reader {
+            let _ = event_loop.deregister(&*reader.socket);
+        }
+        // A read or write might still be running in the thread pool and keep the socket open, so
+        // we make sure the client sees the disconnect right away.
+        unsafe {
+            libc::shutdown(writer.socket.as_raw_fd(), libc::SHUT_RDWR);
+        }
+        self.commands.send(swiboe::Command::ClientDisconnected(client_id)).expect("ClientDisconnected");
+        if self.reading_stopped {
+            self.reply_if_reading_stopped();
+        }
     }
 
     fn reregister_for_writing(&mut self, token: mio::Token, event_loop: &mut mio::EventLoop<Self>) -> Result<()> {
@@ -161,8 +452,15 @@ impl IpcBridge {
 pub enum Command {
     Quit,
     SendData(ClientId, ipc::Message),
-    ReRegisterForReading(mio::Token, ipc::Reader<Box<MioStream>>),
+    ReRegisterForReading(ClientId, ipc::Reader<Box<MioStream>>),
     ReRegisterForWriting(mio::Token),
+    // Drops the connection, e.g. because the client stopped answering pings.
+    Disconnect(ClientId),
+    // First step of a handover: stop accepting and reading. Replies once no read is in flight
+    // anymore. Writing continues, so that the server can still answer what it already received.
+    StopReading(mpsc::Sender<()>),
+    // Second step: give up all sockets and shut down.
+    HandOver(mpsc::Sender<Result<handover::BridgeState>>),
 }
 
 impl mio::Handler for IpcBridge {
@@ -181,7 +479,13 @@ impl mio::Handler for IpcBridge {
                         } else {
                             // println!("Server -> {:?}: {:#?}", receiver, message);
                             let mut writer = conn.writer.lock().unwrap();
-                            writer.queue_message(&message);
+                            match message {
+                                // The client might not be on our host and could not take it.
+                                ipc::Message::RpcResponseBlob(ref response_blob) if conn.tcp => {
+                                    writer.queue_message(&ipc::Message::RpcResponse(response_blob.take()));
+                                },
+                                ref message => writer.queue_message(message),
+                            }
                             Ok(())
                         }
                     });
@@ -193,40 +497,50 @@ impl mio::Handler for IpcBridge {
                     },
                 };
             }
-            Command::ReRegisterForReading(token, reader) => {
+            Command::ReRegisterForReading(client_id, reader) => {
+                let token = client_id.token;
+                // The connection might have been dropped and its token reused in the meantime.
                 if let Some(conn) = self.connections.get_mut(token) {
-                    conn.reader = Some(reader);
-                    event_loop.reregister(
-                        &*conn.reader.as_ref().unwrap().socket,
-                        token,
-                        mio::EventSet::readable(),
-                        mio::PollOpt::level() | mio::PollOpt::oneshot()).unwrap();
+                    if conn.client_id == client_id {
+                        conn.reader = Some(reader);
+                        if !self.reading_stopped {
+                            event_loop.reregister(
+                                &*conn.reader.as_ref().unwrap().socket,
+                                token,
+                                mio::EventSet::readable(),
+                                mio::PollOpt::level() | mio::PollOpt::oneshot()).unwrap();
+                        }
+                    }
+                }
+                if self.reading_stopped {
+                    self.reply_if_reading_stopped();
                 }
             },
             Command::ReRegisterForWriting(token) => {
                 self.reregister_for_writing(token, event_loop).expect("reregister_for_writing");
             },
+            Command::Disconnect(client_id) => self.disconnect(event_loop, client_id),
+            Command::StopReading(reply) => self.stop_reading(event_loop, reply),
+            Command::HandOver(reply) => {
+                let _ = reply.send(self.hand_over());
+                event_loop.shutdown();
+            },
         }
     }
 
     fn ready(&mut self, event_loop: &mut mio::EventLoop<Self>, token: mio::Token, events: mio::EventSet) {
         match token {
-            UNIX_LISTENER => {
-                // Unix domain socket connection.
-                if let Some(stream) = self.unix_listener.accept().expect("UNIX_LISTENER::accept") {
-                    self.new_client(event_loop, Box::new(stream));
+            mio::Token(index) if index < self.listeners.len() => {
+                if self.reading_stopped {
+                    return;
                 }
-
-            },
-            mio::Token(some_token) if some_token < self.first_client_token => {
-                // TCP connection
-                if let Some((stream, _)) = self.tcp_listeners[some_token - 1].accept().expect("TCP listener::accept") {
-                    self.new_client(event_loop, Box::new(stream));
+                if let Some(stream) = self.listeners[index].accept().expect("Listener::accept") {
+                    self.new_client(event_loop, stream, index);
                 }
             },
             client_token => {
                 // println!("#sirver client_token: {:?},events: {:?}", client_token, events);
-                if events.is_readable() {
+                if events.is_readable() && !self.reading_stopped {
                     if let Some(conn) = self.connections.get_mut(token) {
                         let mut reader = conn.reader.take().unwrap();
                         let commands = self.commands.clone();
@@ -235,34 +549,28 @@ impl mio::Handler for IpcBridge {
                         self.thread_pool.execute(move || {
                             loop {
                                 match reader.try_read_message() {
-                                    // NOCOM(#sirver): should disconnect instead of panic.
-                                    Err(err) => panic!("Error while reading: {}", err),
+                                    // The socket might have been shut down under us, e.g. because
+                                    // the client stopped answering pings. Either way the
+                                    // connection is useless now.
+                                    Err(_) => {
+                                        let _ = event_loop_sender.send(Command::Disconnect(client_id));
+                                        return;
+                                    },
                                     Ok(None) => break,
                                     Ok(Some(message)) => {
                                         // println!("{:?} -> Server: {:#?}", client_id, message);
-                                        match message {
-                                            // NOCOM(#sirver): pack them together in one message?
-                                            // NOCOM(#hrapp): that can actually also fail since the
-                                            // thread_pool does not wait for its thread to
-                                            // terminate on deletion.
-                                            ipc::Message::RpcCall(rpc_call) => {
-                                                commands.send(swiboe::Command::RpcCall(
-                                                        client_id, rpc_call)).expect("RpcCall");
-                                            },
-                                            ipc::Message::RpcResponse(rpc_response) => {
-                                                commands.send(swiboe::Command::RpcResponse(rpc_response)).expect("RpcResponse");
-                                            },
-                                            ipc::Message::RpcCancel(rpc_cancel) => {
-                                                commands.send(swiboe::Command::RpcCancel(rpc_cancel)).expect("RpcCancel");
-                                            },
-                                        }
+                                        // NOCOM(#hrapp): that can actually also fail since the
+                                        // thread_pool does not wait for its thread to
+                                        // terminate on deletion.
+                                        commands.send(swiboe::Command::from_message(
+                                                client_id, message)).expect("from_message");
                                     }
                                 }
                             }
                             // The ipc_bridge might have been shut down in the meantime, so ignore send
                             // errors.
                             // println!("#sirver read token: {:#?}", token);
-                            let _ = event_loop_sender.send(Command::ReRegisterForReading(token, reader));
+                            let _ = event_loop_sender.send(Command::ReRegisterForReading(client_id, reader));
                         });
                     }
                 }
@@ -270,12 +578,14 @@ impl mio::Handler for IpcBridge {
                 if events.is_writable() {
                     if let Some(conn) = self.connections.get_mut(token) {
                         let writer = conn.writer.clone();
+                        let client_id = conn.client_id;
                         let event_loop_sender = event_loop.channel();
                         self.thread_pool.execute(move || {
                             let mut writer = writer.lock().expect("writer");
                             match writer.try_write() {
-                                // NOCOM(#sirver): should disconnect instead of panic.
-                                Err(err) => panic!("Error while writing: {}", err),
+                                Err(_) => {
+                                    let _ = event_loop_sender.send(Command::Disconnect(client_id));
+                                },
                                 Ok(ipc::WriterState::AllWritten) => (),
                                 Ok(ipc::WriterState::MoreToWrite) => {
                                     // println!("#sirver write token: {:?}", token);
@@ -293,6 +603,9 @@ impl mio::Handler for IpcBridge {
                         self.commands.send(
                             swiboe::Command::ClientDisconnected(connection.client_id)).expect("ClientDisconnected");
                     }
+                    if self.reading_stopped {
+                        self.reply_if_reading_stopped();
+                    }
                     return;
                 }
             }
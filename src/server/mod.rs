@@ -3,21 +3,41 @@
 // in the project root for license information.
 
 use ::client;
-use ::error::Result;
+use ::error::{Error, Result};
+use ::ipc;
 use ::plugin;
+use libc;
 use mio;
 use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, channel};
+use std::sync::{Arc, RwLock};
 use std::thread;
+use unix_socket;
 
 // NOCOM(#sirver): when a client disconnects and we still try to call one of it's rpcs, we never
 // get an error back - this will effectively interrupt the rpc call stack.
 // NOCOM(#sirver): document everything.
 
+/// Can be sent to other threads to bring down a running server, for example from a signal
+/// handler. The thread owning the Server will return from 'Server::wait_for_shutdown'.
+pub struct ShutdownHandle {
+    commands: swiboe::SenderTo,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        // The server might already be shutting down, so we ignore send errors.
+        let _ = self.commands.send(swiboe::Command::Quit);
+    }
+}
+
 pub struct Server {
-    unix_domain_socket_name: PathBuf,
-    tcp_addresses: Vec<String>,
+    unix_domain_socket_names: Vec<PathBuf>,
+    pidfile: Option<PathBuf>,
     commands: swiboe::SenderTo,
     ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
     swiboe_thread: Option<thread::JoinHandle<()>>,
@@ -25,47 +45,365 @@ pub struct Server {
     buffer_plugin: Option<plugin::buffer::Plugin>,
     list_files_plugin: Option<plugin::list_files::Plugin>,
     log_plugin: Option<plugin::log::Plugin>,
+    plugin_manager_client: Option<client::Client>,
+    plugin_manager_commands: plugin_manager::SenderTo,
+    plugin_manager_thread: Option<thread::JoinHandle<()>>,
+    next_in_process_serial: u64,
+    handover_socket: Option<PathBuf>,
+    handover_thread: Option<thread::JoinHandle<()>>,
+    // Set while the sockets and the pidfile belong to another server process: once a new server
+    // confirmed that it took over, or while we are taking over and have not confirmed yet.
+    handed_over: Arc<AtomicBool>,
+    prometheus_address: Option<SocketAddr>,
+    prometheus_thread: Option<thread::JoinHandle<()>>,
+    prometheus_stopping: Arc<AtomicBool>,
+}
+
+// After a handover, the old server might still be serving stats for a moment while it shuts down.
+fn bind_prometheus(address: SocketAddr, taking_over: bool) -> Result<TcpListener> {
+    let mut attempts = 0;
+    loop {
+        match TcpListener::bind(address) {
+            Ok(listener) => return Ok(listener),
+            Err(ref err) if taking_over && err.kind() == io::ErrorKind::AddrInUse && attempts < 40 => {
+                attempts += 1;
+                thread::sleep(::std::time::Duration::from_millis(50));
+            },
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+// Answers every connection with the current stats in the Prometheus text format. The request
+// itself is not looked at.
+fn serve_prometheus(listener: TcpListener, commands: swiboe::SenderTo, stopping: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let (tx, rx) = channel();
+        if commands.send(swiboe::Command::Stats(tx)).is_err() {
+            return;
+        }
+        let body = match rx.recv() {
+            Ok(stats) => stats.to_prometheus(),
+            Err(_) => return,
+        };
+        let _ = stream.set_read_timeout(Some(::std::time::Duration::from_millis(500)));
+        let mut request = [0u8; 4096];
+        let _ = stream.read(&mut request);
+        let _ = write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                Content-Length: {}\r\n\r\n{}", body.len(), body);
+    }
+}
+
+// Everything the handover thread needs to collect the state of the server.
+struct HandoverContext {
+    socket_name: PathBuf,
+    commands: swiboe::SenderTo,
+    ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
+    plugin_manager_commands: plugin_manager::SenderTo,
+    buffers: Option<Arc<RwLock<plugin::buffer::BuffersManager>>>,
+    handed_over: Arc<AtomicBool>,
+}
+
+impl HandoverContext {
+    fn serve(&self, listener: unix_socket::UnixListener) {
+        let mut stream = match listener.incoming().next() {
+            Some(Ok(stream)) => stream,
+            Some(Err(err)) => {
+                println!("Could not accept handover connection: {}", err);
+                return;
+            },
+            None => return,
+        };
+        match handover::wait_for_request(&mut stream) {
+            Ok(true) => (),
+            // Woken up because the server is shutting down.
+            Ok(false) | Err(_) => return,
+        }
+
+        if let Err(err) = self.hand_over(&mut stream) {
+            // There is no way back: we might already have given up parts of our state. Since the
+            // new server did not confirm, our shutdown removes the sockets and the pidfile.
+            println!("Handover failed: {}", err);
+            let _ = self.plugin_manager_commands.send(plugin_manager::Command::Quit);
+            let _ = self.ipc_bridge_commands.send(ipc_bridge::Command::Quit);
+            let _ = self.commands.send(swiboe::Command::Quit);
+        }
+    }
+
+    fn hand_over(&self, stream: &mut unix_socket::UnixStream) -> Result<()> {
+        let (tx, rx) = channel();
+        try!(self.ipc_bridge_commands.send(ipc_bridge::Command::StopReading(tx)));
+        try!(rx.recv());
+
+        let (tx, rx) = channel();
+        try!(self.commands.send(swiboe::Command::Snapshot(tx)));
+        let swiboe_state = try!(rx.recv());
+
+        let buffers = self.buffers.as_ref().map(|buffers| buffers.read().unwrap().snapshot());
+
+        let (tx, rx) = channel();
+        try!(self.plugin_manager_commands.send(plugin_manager::Command::Detach(tx)));
+        let plugins = try!(rx.recv());
+
+        let (tx, rx) = channel();
+        try!(self.ipc_bridge_commands.send(ipc_bridge::Command::HandOver(tx)));
+        let bridge_state = try!(try!(rx.recv()));
+
+        let state = handover::State {
+            listeners: bridge_state.listeners,
+            connections: bridge_state.connections,
+            swiboe: swiboe_state,
+            buffers: buffers,
+            plugins: plugins,
+        };
+        let result = handover::send(stream, &state, &bridge_state.fds);
+        handover::close_fds(&bridge_state.fds);
+        try!(result);
+
+        if !try!(handover::wait_for_confirmation(stream)) {
+            return Err(Error::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof, "New server went away without confirming.")));
+        }
+        self.handed_over.store(true, Ordering::SeqCst);
+        // The new server binds the handover socket itself for the next restart, once we closed
+        // the connection.
+        remove_file_if_exists(&self.socket_name);
+        Ok(())
+    }
 }
 
 impl Server {
     pub fn launch(unix_domain_socket_name: &Path, tcp_addresses: &[&str]) -> Result<Self> {
+        Server::launch_with_plugins(unix_domain_socket_name, tcp_addresses, None)
+    }
+
+    /// Like 'launch', but also supervises the external plugins described by the manifests in
+    /// 'plugin_directory'.
+    pub fn launch_with_plugins(unix_domain_socket_name: &Path,
+                               tcp_addresses: &[&str],
+                               plugin_directory: Option<&Path>) -> Result<Self> {
+        Server::launch_with_config(&config::Config {
+            unix_sockets: Some(vec![unix_domain_socket_name.to_string_lossy().into_owned()]),
+            tcp_addresses: Some(tcp_addresses.iter().map(|slice| slice.to_string()).collect()),
+            plugin_directory: plugin_directory.map(|directory| directory.to_string_lossy().into_owned()),
+            .. config::Config::default()
+        })
+    }
+
+    pub fn launch_with_config(config: &config::Config) -> Result<Self> {
+        try!(config.validate());
+        Server::start(config, None)
+    }
+
+    /// Takes over all clients of the server listening on the 'handover_socket' of 'config'
+    /// without disconnecting them. The old server exits once it handed everything over.
+    pub fn take_over(config: &config::Config) -> Result<Self> {
+        try!(config.validate());
+        let handover_socket = try!(config.handover_socket().ok_or(
+                Error::InvalidConfig("Taking over a server requires a handover_socket.".into())));
+        let received = try!(handover::receive(&handover_socket));
+        Server::start(config, Some(received))
+    }
+
+    fn start(config: &config::Config, handover: Option<handover::Received>) -> Result<Self> {
+        let taking_over = handover.is_some();
+        let manifests = match config.plugin_directory() {
+            Some(directory) => try!(plugin_manager::load_manifests(&directory)),
+            None => Vec::new(),
+        };
+
         let (tx, rx) = channel();
+        let (plugin_manager_tx, plugin_manager_rx) = channel();
 
         let mut event_loop = mio::EventLoop::new().expect("Could not create an event loop.");
 
+        let (mut ipc_bridge, unix_sockets, buffers, adopted_plugins, handover_stream) = match handover {
+            None => {
+                let ipc_bridge = try!(ipc_bridge::IpcBridge::new(
+                    &mut event_loop, &config.unix_sockets(), &config.tcp_addresses(),
+                    config.ipc_threads(), tx.clone()));
+                (ipc_bridge, config.unix_sockets(), None, Vec::new(), None)
+            },
+            Some(handover::Received { state, fds, stream }) => {
+                // The listeners of the old server win over the configured ones.
+                let unix_sockets = state.listeners.iter()
+                    .filter_map(|address| match *address {
+                        handover::ListenerAddress::Unix(ref path) => Some(PathBuf::from(path)),
+                        handover::ListenerAddress::Tcp(_) => None,
+                    })
+                    .collect();
+                let (ipc_bridge, clients) = try!(ipc_bridge::IpcBridge::from_handover(
+                    &mut event_loop,
+                    handover::BridgeState {
+                        listeners: state.listeners,
+                        connections: state.connections,
+                        fds: fds,
+                    },
+                    config.ipc_threads(), tx.clone()));
+                try!(tx.send(swiboe::Command::Restore(state.swiboe, clients)));
+                (ipc_bridge, unix_sockets, state.buffers, state.plugins, Some(stream))
+            },
+        };
+
         let mut server = Server {
-            unix_domain_socket_name: unix_domain_socket_name.to_path_buf(),
-            tcp_addresses: tcp_addresses.iter().map(|slice| slice.to_string()).collect(),
+            unix_domain_socket_names: unix_sockets,
+            pidfile: None,
             commands: tx.clone(),
             ipc_bridge_commands: event_loop.channel(),
             buffer_plugin: None,
             list_files_plugin: None,
             log_plugin: None,
+            plugin_manager_client: None,
+            plugin_manager_commands: plugin_manager_tx.clone(),
+            plugin_manager_thread: None,
+            next_in_process_serial: 1,
             swiboe_thread: None,
             event_loop_thread: None,
+            handover_socket: None,
+            handover_thread: None,
+            handed_over: Arc::new(AtomicBool::new(taking_over)),
+            prometheus_address: None,
+            prometheus_thread: None,
+            prometheus_stopping: Arc::new(AtomicBool::new(false)),
         };
 
-        server.swiboe_thread = Some(swiboe::spawn(event_loop.channel(), tx.clone(), rx));
-
-        let mut ipc_bridge = ipc_bridge::IpcBridge::new(
-            &mut event_loop, &server.unix_domain_socket_name, &server.tcp_addresses,
-            server.commands.clone());
+        server.swiboe_thread = Some(swiboe::spawn(
+                event_loop.channel(), tx, rx, plugin_manager_tx, config));
 
         server.event_loop_thread = Some(thread::spawn(move || {
             event_loop.run(&mut ipc_bridge).expect("Could not start event_loop.");
         }));
 
-        server.buffer_plugin = Some(try!(plugin::buffer::Plugin::new(
-                    try!(client::Client::connect_unix(&server.unix_domain_socket_name)))));
-        server.list_files_plugin = Some(try!(plugin::list_files::Plugin::new(
-                    try!(client::Client::connect_unix(&server.unix_domain_socket_name)))));
-        server.log_plugin = Some(try!(plugin::log::Plugin::new(
-                    try!(client::Client::connect_unix(&server.unix_domain_socket_name)))));
+        if let Err(err) = server.start_services(
+            config, manifests, &adopted_plugins, buffers, plugin_manager_rx, handover_stream) {
+            // Also removes the sockets and the pidfile, unless they still belong to the old server.
+            server.shutdown();
+            return Err(err);
+        }
         Ok(server)
     }
 
+    // Starts everything that runs on top of the IpcBridge and the Handler. When taking over,
+    // 'handover_stream' is the connection to the old server, which is confirmed once the plugins
+    // run.
+    fn start_services(&mut self,
+                      config: &config::Config,
+                      manifests: Vec<plugin_manager::Manifest>,
+                      adopted_plugins: &[plugin_manager::RunningPlugin],
+                      buffers: Option<plugin::buffer::Snapshot>,
+                      plugin_manager_rx: mpsc::Receiver<plugin_manager::Command>,
+                      handover_stream: Option<unix_socket::UnixStream>) -> Result<()> {
+        let taking_over = handover_stream.is_some();
+        if config.builtin_plugin_enabled("buffer") {
+            let buffer_plugin = try!(plugin::buffer::Plugin::new(try!(self.connect_in_process())));
+            if let Some(snapshot) = buffers {
+                buffer_plugin.buffers().write().unwrap().restore(snapshot);
+            }
+            self.buffer_plugin = Some(buffer_plugin);
+        }
+        if config.builtin_plugin_enabled("list_files") {
+            self.list_files_plugin = Some(try!(plugin::list_files::Plugin::new(
+                        try!(self.connect_in_process()))));
+        }
+        if config.builtin_plugin_enabled("log") {
+            self.log_plugin = Some(try!(plugin::log::Plugin::with_logger(
+                        try!(self.connect_in_process()), try!(config.logger()))));
+        }
+
+        let plugin_manager_client = try!(self.connect_in_process());
+        self.plugin_manager_thread = Some(try!(plugin_manager::spawn(
+                    manifests, adopted_plugins, &self.unix_domain_socket_names[0],
+                    try!(plugin_manager_client.clone()), self.plugin_manager_commands.clone(),
+                    plugin_manager_rx)));
+        self.plugin_manager_client = Some(plugin_manager_client);
+
+        if let Some(mut stream) = handover_stream {
+            try!(self.commands.send(swiboe::Command::DispatchRestoredCalls));
+            try!(handover::confirm(&mut stream));
+            self.handed_over.store(false, Ordering::SeqCst);
+        }
+
+        if let Some(address) = config.prometheus_address() {
+            let listener = try!(bind_prometheus(address, taking_over));
+            self.prometheus_address = Some(try!(listener.local_addr()));
+            let commands = self.commands.clone();
+            let stopping = self.prometheus_stopping.clone();
+            self.prometheus_thread = Some(thread::spawn(
+                    move || serve_prometheus(listener, commands, stopping)));
+        }
+
+        if let Some(pidfile) = config.pidfile() {
+            let mut file = try!(fs::File::create(&pidfile));
+            self.pidfile = Some(pidfile);
+            try!(writeln!(file, "{}", unsafe { libc::getpid() }));
+        }
+
+        if let Some(socket_name) = config.handover_socket() {
+            try!(ipc_bridge::remove_stale_socket(&socket_name));
+            let listener = try!(unix_socket::UnixListener::bind(&socket_name));
+            self.handover_socket = Some(socket_name.clone());
+            let context = HandoverContext {
+                socket_name: socket_name,
+                commands: self.commands.clone(),
+                ipc_bridge_commands: self.ipc_bridge_commands.clone(),
+                plugin_manager_commands: self.plugin_manager_commands.clone(),
+                buffers: self.buffer_plugin.as_ref().map(|plugin| plugin.buffers()),
+                handed_over: self.handed_over.clone(),
+            };
+            self.handover_thread = Some(thread::spawn(move || context.serve(listener)));
+        }
+        Ok(())
+    }
+
+    /// Connects a client that lives in the same process as the server. It behaves exactly like a
+    /// client connected through a socket, but messages are passed through channels and are never
+    /// serialized.
+    pub fn connect_in_process(&mut self) -> Result<client::Client> {
+        let client_id = ipc_bridge::ClientId {
+            serial: self.next_in_process_serial,
+            token: ipc_bridge::IN_PROCESS,
+        };
+        self.next_in_process_serial += 1;
+
+        let (tx, rx) = mpsc::channel();
+        try!(self.commands.send(swiboe::Command::InProcessClientConnected(client_id, tx)));
+
+        let commands = self.commands.clone();
+        let outgoing = Box::new(move |message: ipc::Message| {
+            try!(commands.send(swiboe::Command::from_message(client_id, message)));
+            Ok(())
+        });
+
+        let commands = self.commands.clone();
+        let shutdown_func = Box::new(move || {
+            // The server might already be gone, so we ignore send errors.
+            let _ = commands.send(swiboe::Command::ClientDisconnected(client_id));
+        });
+        Ok(client::Client::connect_in_process(rx, outgoing, shutdown_func))
+    }
+
+    /// The address stats are served on for Prometheus. Useful if the configured port was 0.
+    pub fn prometheus_address(&self) -> Option<SocketAddr> {
+        self.prometheus_address
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            commands: self.commands.clone(),
+        }
+    }
+
     pub fn shutdown(&mut self) {
         // Any of the threads might have already panicked. So we ignore send errors.
+        let _ = self.plugin_manager_commands.send(plugin_manager::Command::Quit);
+        self.wait_for_plugin_manager_thread_to_shut_down();
         let _ = self.ipc_bridge_commands.send(ipc_bridge::Command::Quit);
         self.wait_for_event_loop_thread_to_shut_down();
         let _ = self.commands.send(swiboe::Command::Quit);
@@ -80,22 +418,85 @@ impl Server {
         }
     }
 
+    fn wait_for_plugin_manager_thread_to_shut_down(&mut self) {
+        if let Some(thread) = self.plugin_manager_thread.take() {
+            thread.join().expect("Could not join plugin_manager_thread.");
+        }
+    }
+
     fn wait_for_swiboe_thread_to_shut_down(&mut self) {
         if let Some(thread) = self.swiboe_thread.take() {
             thread.join().expect("Could not join swiboe_thread.");
         }
     }
 
+    fn wait_for_handover_thread_to_shut_down(&mut self) {
+        if let Some(thread) = self.handover_thread.take() {
+            if !self.handed_over.load(Ordering::SeqCst) {
+                // Wake up the thread waiting for a new server. It gives up once we disconnect.
+                if let Some(ref socket_name) = self.handover_socket {
+                    let _ = unix_socket::UnixStream::connect(socket_name);
+                }
+            }
+            thread.join().expect("Could not join handover_thread.");
+        }
+    }
+
+    fn wait_for_prometheus_thread_to_shut_down(&mut self) {
+        if let Some(thread) = self.prometheus_thread.take() {
+            self.prometheus_stopping.store(true, Ordering::SeqCst);
+            // Wake up the thread waiting for connections.
+            if let Some(address) = self.prometheus_address {
+                let _ = TcpStream::connect(address);
+            }
+            thread.join().expect("Could not join prometheus_thread.");
+        }
+    }
+
     pub fn wait_for_shutdown(&mut self) {
         self.wait_for_event_loop_thread_to_shut_down();
         self.wait_for_swiboe_thread_to_shut_down();
+        let _ = self.plugin_manager_commands.send(plugin_manager::Command::Quit);
+        self.wait_for_plugin_manager_thread_to_shut_down();
+        self.wait_for_handover_thread_to_shut_down();
+        self.wait_for_prometheus_thread_to_shut_down();
+
+        if self.handed_over.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ref handover_socket) = self.handover_socket {
+            remove_file_if_exists(handover_socket);
+        }
+        for socket_name in &self.unix_domain_socket_names {
+            remove_file_if_exists(socket_name);
+        }
+        if let Some(ref pidfile) = self.pidfile {
+            remove_file_if_exists(pidfile);
+        }
+    }
+}
 
-        fs::remove_file(&self.unix_domain_socket_name).expect(
-            &format!("Could not remove socket {:?}", self.unix_domain_socket_name));
+// Shutdown might be triggered a second time, or somebody else cleaned up already. Neither is a
+// reason to crash.
+fn remove_file_if_exists(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => println!("Could not remove {:?}: {}", path, err),
     }
 }
 
 mod api_table;
+mod subscriptions;
+pub mod call_tree;
 mod ipc_bridge;
+pub mod config;
+pub mod handover;
+pub mod permissions;
+pub mod quota;
+pub mod signals;
+pub mod stats;
 mod swiboe;
+pub mod tracer;
+pub mod plugin_manager;
 pub mod plugin_core; // NOCOM being a private mod
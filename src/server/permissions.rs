@@ -0,0 +1,125 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Restricts which RPCs a client may call and register, and which names it may claim in
+//! 'core.handshake'. Rules are part of the server config:
+//!
+//! ```json
+//! "permissions": [
+//!     { "listener": "127.0.0.1:12345", "call": { "deny": ["core"] }, "register": { "allow": [] },
+//!       "handshake": { "deny": ["*"] } },
+//!     { "client": "lua_keymap", "call": { "allow": ["buffer", "gui"] } }
+//! ]
+//! ```
+//!
+//! A rule applies to a client if its 'client' is the name the client sent in 'core.handshake', or
+//! its 'listener' is the unix socket path or IP address the client connected through
+//! ("in_process" for the built-in plugins). Entries in the lists are namespaces: "buffer" covers
+//! "buffer.open" and everything else below it, "*" covers everything. A function is denied if any
+//! applying rule denies it, or if an applying rule has an 'allow' list that does not cover it.
+//! Clients without any applying rule may do everything. Sending a notification counts as calling
+//! its topic, refused notifications are dropped.
+//!
+//! A client can only do its handshake once, and only with a name the rules that apply to it
+//! before the handshake permit in 'handshake'. Otherwise, handshake names are not authenticated,
+//! so untrusted clients should be restricted by the listener they have to use.
+
+use ::error::{Error, Result};
+
+pub const IN_PROCESS_LISTENER: &'static str = "in_process";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NamespaceFilter {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+fn covers(namespace: &str, function: &str) -> bool {
+    namespace == "*" || function == namespace ||
+        (function.starts_with(namespace) && function[namespace.len()..].starts_with("."))
+}
+
+impl NamespaceFilter {
+    fn permits(&self, function: &str) -> bool {
+        if let Some(ref deny) = self.deny {
+            if deny.iter().any(|namespace| covers(namespace, function)) {
+                return false;
+            }
+        }
+        match self.allow {
+            Some(ref allow) => allow.iter().any(|namespace| covers(namespace, function)),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PermissionRule {
+    /// The handshake name of the clients this rule applies to.
+    pub client: Option<String>,
+    /// The listener of the clients this rule applies to.
+    pub listener: Option<String>,
+    /// RPCs the clients may call.
+    pub call: Option<NamespaceFilter>,
+    /// RPCs the clients may register.
+    pub register: Option<NamespaceFilter>,
+    /// Names the clients may claim in 'core.handshake'.
+    pub handshake: Option<NamespaceFilter>,
+}
+
+impl PermissionRule {
+    pub fn validate(&self) -> Result<()> {
+        if self.client.is_none() && self.listener.is_none() {
+            return Err(Error::InvalidConfig(
+                    "Every permission rule needs a 'client' or a 'listener'.".into()));
+        }
+        Ok(())
+    }
+
+    fn applies_to(&self, name: Option<&str>, listener: &str) -> bool {
+        let client_matches = match (self.client.as_ref(), name) {
+            (Some(client), Some(name)) => client == name,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let listener_matches = match self.listener {
+            Some(ref rule_listener) => rule_listener == listener,
+            None => true,
+        };
+        client_matches && listener_matches
+    }
+}
+
+pub struct Permissions {
+    rules: Vec<PermissionRule>,
+}
+
+impl Permissions {
+    pub fn new(rules: Vec<PermissionRule>) -> Self {
+        Permissions {
+            rules: rules,
+        }
+    }
+
+    fn permits<F>(&self, name: Option<&str>, listener: &str, function: &str, filter: F) -> bool
+        where F: Fn(&PermissionRule) -> Option<&NamespaceFilter> {
+        self.rules.iter()
+            .filter(|rule| rule.applies_to(name, listener))
+            .filter_map(|rule| filter(rule))
+            .all(|filter| filter.permits(function))
+    }
+
+    pub fn may_call(&self, name: Option<&str>, listener: &str, function: &str) -> bool {
+        self.permits(name, listener, function, |rule| rule.call.as_ref())
+    }
+
+    pub fn may_register(&self, name: Option<&str>, listener: &str, function: &str) -> bool {
+        self.permits(name, listener, function, |rule| rule.register.as_ref())
+    }
+
+    /// Whether a client without a name yet may take 'claimed' as its name.
+    pub fn may_claim(&self, listener: &str, claimed: &str) -> bool {
+        self.permits(None, listener, claimed, |rule| rule.handshake.as_ref())
+    }
+}
@@ -4,28 +4,153 @@
 
 use ::rpc;
 use ::server::ipc_bridge;
+use ::server::plugin_manager;
 use ::server::swiboe;
 use serde_json;
+use time;
+
+/// Where a client runs the calls of an RPC. The server needs to know to tell whether nested
+/// calls wait on each other, see 'rpc::ErrorKind::Deadlock'.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Execution {
+    /// On the thread pool of the client, next to the calls of its other RPCs. See
+    /// 'Client::set_thread_pool_size'.
+    Concurrent,
+    /// One call after the other, on a thread of its own. Other RPCs are not blocked by it.
+    Serial,
+    /// Each call on a new thread, for calls that run for a long time.
+    Dedicated,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NewRpcRequest {
     pub priority: u16,
     pub name: String,
+    /// None if the client does not tell. Calls to it are then never reported as deadlocks.
+    pub execution: Option<Execution>,
+}
+
+/// Arguments of 'core.set_thread_pool_size'.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThreadPoolRequest {
+    /// The number of calls of RPCs with Execution::Concurrent the client runs at the same time.
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HandshakeRequest {
+    /// The name permission rules refer to, see 'server::permissions'.
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoalesceMode {
+    /// Only the last notification per key is delivered.
+    Latest,
+    /// All notifications are delivered.
+    Batch,
+}
+
+/// How the server thins out the notifications of a subscription, see 'server::subscriptions'.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Coalesce {
+    pub mode: CoalesceMode,
+    pub window_ms: u64,
+    /// For Latest: notifications with different values of this field of their args are kept
+    /// apart. Without it, the topic is the key.
+    pub key: Option<String>,
+}
+
+/// Arguments of 'core.subscribe' and 'core.unsubscribe'.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeRequest {
+    /// Notifications whose topic starts with this are delivered.
+    pub prefix: String,
+    /// Only for 'core.subscribe'. Subscribing to the same prefix again replaces it.
+    pub coalesce: Option<Coalesce>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ClientStatus {
+    pub serial: u64,
+    pub name: Option<String>,
+    pub listener: String,
+    /// RPCs called by this client that are still running.
+    pub in_flight: usize,
+    pub calls: u64,
+    /// Calls refused because a rate limit was exceeded.
+    pub rejected: u64,
+}
+
+/// Returned by 'core.clients'.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ClientsResponse {
+    pub clients: Vec<ClientStatus>,
+}
+
+/// Answers a core call that is handled outside of the server's dispatch thread.
+pub struct Reply {
+    commands: swiboe::SenderTo,
+    caller: ipc_bridge::ClientId,
+    rpc_call: rpc::Call,
+    received: time::SteadyTime,
+}
+
+impl Reply {
+    pub fn send(self, result: rpc::Result) {
+        // The server might be shutting down, then nobody waits for the answer anymore.
+        let _ = self.commands.send(
+            swiboe::Command::CoreResult(self.caller, self.rpc_call, self.received, result));
+    }
 }
 
 pub struct CorePlugin {
     commands: swiboe::SenderTo,
+    plugin_manager: plugin_manager::SenderTo,
 }
 
 impl CorePlugin {
-    pub fn new(commands: swiboe::SenderTo) -> Self {
+    pub fn new(commands: swiboe::SenderTo, plugin_manager: plugin_manager::SenderTo) -> Self {
         CorePlugin {
             commands: commands,
+            plugin_manager: plugin_manager,
         }
     }
 
-    pub fn call(&self, caller: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
-        match &rpc_call.function as &str {
+    // Forwards a request to the plugin manager, which answers the call through the Reply once it
+    // got to it.
+    fn ask_plugin_manager<F>(&self, reply: Reply, command: F) -> Option<rpc::Result>
+        where F: FnOnce(Reply) -> plugin_manager::Command {
+        if self.plugin_manager.send(command(reply)).is_err() {
+            return Some(rpc::Result::Err(rpc::Error {
+                kind: rpc::ErrorKind::Io,
+                message: Some("The plugin manager is not running anymore.".into()),
+                details: Some(serde_json::to_value(&"plugin_manager_gone")),
+            }));
+        }
+        None
+    }
+
+    fn plugin_request(&self, rpc_call: &rpc::Call) -> Result<String, rpc::Result> {
+        match serde_json::from_value::<plugin_manager::PluginRequest>(rpc_call.args.clone()) {
+            Ok(request) => Ok(request.name),
+            Err(err) => Err(rpc::Result::Err(err.into())),
+        }
+    }
+
+    fn reply(&self, caller: ipc_bridge::ClientId, rpc_call: &rpc::Call, received: time::SteadyTime) -> Reply {
+        Reply {
+            commands: self.commands.clone(),
+            caller: caller,
+            rpc_call: rpc_call.clone(),
+            received: received,
+        }
+    }
+
+    /// Handles 'rpc_call'. Returns None if the result is sent later through a Reply.
+    pub fn call(&self, caller: ipc_bridge::ClientId, rpc_call: &rpc::Call, received: time::SteadyTime)
+        -> Option<rpc::Result> {
+        let result = match &rpc_call.function as &str {
             "core.exit" => {
                 self.commands.send(swiboe::Command::Quit).unwrap();
                 rpc::Result::success(())
@@ -34,16 +159,34 @@ impl CorePlugin {
             "core.new_rpc" => {
                 let args: NewRpcRequest = match serde_json::from_value(rpc_call.args.clone()) {
                     Ok(args) => args,
-                    // NOCOM(#sirver): report errors somehow?
-                    Err(_) => panic!("Invalid arguments"),
+                    Err(err) => return Some(rpc::Result::Err(err.into())),
                 };
 
                 self.commands.send(
-                    swiboe::Command::NewRpc(caller, args.name, args.priority)).unwrap();
+                    swiboe::Command::NewRpc(caller, args.name, args.priority, args.execution)).unwrap();
                 rpc::Result::success(())
             },
+            "core.plugins.list" => {
+                let reply = self.reply(caller, rpc_call, received);
+                return self.ask_plugin_manager(reply, |reply| plugin_manager::Command::List(reply));
+            },
+            "core.plugins.start" => match self.plugin_request(rpc_call) {
+                Ok(name) => {
+                    let reply = self.reply(caller, rpc_call, received);
+                    return self.ask_plugin_manager(reply, |reply| plugin_manager::Command::Start(name, reply));
+                },
+                Err(result) => result,
+            },
+            "core.plugins.stop" => match self.plugin_request(rpc_call) {
+                Ok(name) => {
+                    let reply = self.reply(caller, rpc_call, received);
+                    return self.ask_plugin_manager(reply, |reply| plugin_manager::Command::Stop(name, reply));
+                },
+                Err(result) => result,
+            },
             // NOCOM(#sirver): this should not panic, but return an error.
             _ => panic!("{} was called, but is not a core function.", rpc_call.function),
-        }
+        };
+        Some(result)
     }
 }
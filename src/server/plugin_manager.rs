@@ -0,0 +1,540 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Supervises external plugin processes. Plugins are described by JSON manifests in a directory,
+//! for example:
+//!
+//! ```json
+//! {
+//!     "name": "fuzzy_finder",
+//!     "command": "/usr/local/bin/swiboe_fuzzy_finder",
+//!     "args": ["--verbose"],
+//!     "env": { "RUST_BACKTRACE": "1" },
+//!     "restart": "on_failure",
+//!     "autostart": true,
+//!     "rpcs": ["fuzzy_finder.query"]
+//! }
+//! ```
+//!
+//! Every plugin gets the path of the server's socket in the SWIBOE_SOCKET environment variable.
+
+use ::client;
+use ::error::{Error, Result};
+use ::rpc;
+use ::server::plugin_core;
+use ::spinner;
+use libc;
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use time;
+
+/// The environment variable that contains the socket a plugin should connect to.
+pub const SOCKET_ENVIRONMENT_VARIABLE: &'static str = "SWIBOE_SOCKET";
+
+// Delay before the first restart of a crashed plugin. Doubles with every crash in a row.
+const INITIAL_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 30000;
+
+// A plugin that ran for longer than this before it crashed is restarted without backoff.
+const STABLE_RUNTIME_SECONDS: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    fn parse(policy: &str) -> Result<Self> {
+        match policy {
+            "never" => Ok(RestartPolicy::Never),
+            "on_failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            other => Err(Error::InvalidConfig(format!("Unknown restart policy '{}'.", other))),
+        }
+    }
+
+    fn should_restart(&self, success: bool) -> bool {
+        match *self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !success,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub name: String,
+    pub command: String,
+    pub args: Option<Vec<String>>,
+    pub env: Option<HashMap<String, String>>,
+    /// One of "never" (the default), "on_failure" or "always".
+    pub restart: Option<String>,
+    /// Start the plugin when the server starts. Defaults to true.
+    pub autostart: Option<bool>,
+    /// The RPCs this plugin provides. Informational only, the plugin still has to register them.
+    pub rpcs: Option<Vec<String>>,
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut file = try!(fs::File::open(path));
+        let mut content = String::new();
+        try!(file.read_to_string(&mut content));
+        let manifest: Manifest = try!(serde_json::from_str(&content));
+        try!(manifest.restart_policy());
+        Ok(manifest)
+    }
+
+    pub fn restart_policy(&self) -> Result<RestartPolicy> {
+        match self.restart {
+            Some(ref policy) => RestartPolicy::parse(policy),
+            None => Ok(RestartPolicy::Never),
+        }
+    }
+}
+
+/// Reads all manifests, i.e. all files ending in '.json', in 'directory'.
+pub fn load_manifests(directory: &Path) -> Result<Vec<Manifest>> {
+    let mut manifests = Vec::new();
+    for entry in try!(fs::read_dir(directory)) {
+        let path = try!(entry).path();
+        if path.extension().map_or(false, |ext| ext == "json") {
+            manifests.push(try!(Manifest::from_file(&path)));
+        }
+    }
+    Ok(manifests)
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PluginRequest {
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PluginStatus {
+    pub name: String,
+    /// One of "running", "stopping", "restarting" or "stopped".
+    pub state: String,
+    pub pid: Option<u32>,
+    pub restarts: u32,
+    pub rpcs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ListResponse {
+    pub plugins: Vec<PluginStatus>,
+}
+
+/// Published as 'on.plugin.crashed' when a plugin process exits without being asked to and
+/// without success, or disappears without an exit code.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PluginCrashed {
+    pub name: String,
+    pub exit_code: Option<i32>,
+    pub will_restart: bool,
+}
+
+/// Published as 'on.plugin.restarted' when a plugin that exited by itself has been started again.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct PluginRestarted {
+    pub name: String,
+    pub restarts: u32,
+}
+
+/// A plugin process that keeps running while the server is handed over to a new process.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RunningPlugin {
+    pub name: String,
+    pub pid: u32,
+    pub restarts: u32,
+}
+
+pub enum Command {
+    Quit,
+    // Quit without stopping the plugins, so that a new server can adopt them. Replies with the
+    // running plugins.
+    Detach(mpsc::Sender<Vec<RunningPlugin>>),
+    List(plugin_core::Reply),
+    Start(String, plugin_core::Reply),
+    Stop(String, plugin_core::Reply),
+    // The process of the given generation has exited. Carries its exit code, if any.
+    Exited(String, u64, Option<i32>),
+    // The backoff for the given generation has passed.
+    Restart(String, u64),
+    // The last connection of the given process to the server is gone.
+    Disconnected(u32),
+}
+
+pub type SenderTo = mpsc::Sender<Command>;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Stopped,
+    Running(u32),
+    Stopping(u32),
+    Restarting,
+}
+
+struct Plugin {
+    manifest: Manifest,
+    policy: RestartPolicy,
+    state: State,
+    // Increased whenever a process is spawned or a pending restart is aborted, so that we can
+    // ignore notifications about processes and timers we no longer care about.
+    generation: u64,
+    restarts: u32,
+    backoff_ms: u64,
+    started: time::SteadyTime,
+    // Started by a previous server process. It is not our child, so we cannot wait for it and
+    // consider it gone once it disconnects.
+    adopted: bool,
+}
+
+impl Plugin {
+    fn status(&self) -> PluginStatus {
+        let (state, pid) = match self.state {
+            State::Stopped => ("stopped", None),
+            State::Running(pid) => ("running", Some(pid)),
+            State::Stopping(pid) => ("stopping", Some(pid)),
+            State::Restarting => ("restarting", None),
+        };
+        PluginStatus {
+            name: self.manifest.name.clone(),
+            state: state.into(),
+            pid: pid,
+            restarts: self.restarts,
+            rpcs: self.manifest.rpcs.clone().unwrap_or(Vec::new()),
+        }
+    }
+}
+
+struct Receiver {
+    commands: mpsc::Receiver<Command>,
+}
+
+impl spinner::Receiver<Command> for Receiver {
+    fn recv(&mut self) -> Result<Command> {
+        match self.commands.recv() {
+            Ok(value) => Ok(value),
+            Err(_) => Err(Error::Disconnected),
+        }
+    }
+}
+
+struct Handler {
+    plugins: HashMap<String, Plugin>,
+    socket_name: PathBuf,
+    commands: SenderTo,
+    client: client::ThinClient,
+}
+
+fn plugin_error(details: &str) -> rpc::Result {
+    let message = match details {
+        "unknown_plugin" => "No plugin with this name is configured.",
+        "already_running" => "The plugin is already running.",
+        "spawn_failed" => "The plugin could not be started.",
+        "not_running" => "The plugin is not running.",
+        _ => "The plugin cannot do this.",
+    };
+    rpc::Result::Err(rpc::Error {
+        kind: rpc::ErrorKind::InvalidArgs,
+        message: Some(message.into()),
+        details: Some(serde_json::to_value(&details)),
+    })
+}
+
+fn kill(pid: u32) {
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+impl Handler {
+    fn spawn_process(&mut self, name: &str) -> Result<()> {
+        let plugin = self.plugins.get_mut(name).unwrap();
+
+        let mut command = process::Command::new(&plugin.manifest.command);
+        if let Some(ref args) = plugin.manifest.args {
+            command.args(args);
+        }
+        if let Some(ref env) = plugin.manifest.env {
+            for (key, value) in env {
+                command.env(key, value);
+            }
+        }
+        command.env(SOCKET_ENVIRONMENT_VARIABLE, &self.socket_name);
+        let mut child = try!(command.spawn());
+
+        plugin.generation += 1;
+        plugin.state = State::Running(child.id());
+        plugin.started = time::SteadyTime::now();
+        plugin.adopted = false;
+
+        let generation = plugin.generation;
+        let commands = self.commands.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            let exit_code = match child.wait() {
+                Ok(status) => status.code(),
+                Err(_) => None,
+            };
+            // The plugin manager might have been shut down in the meantime, so ignore errors.
+            let _ = commands.send(Command::Exited(name, generation, exit_code));
+        });
+        Ok(())
+    }
+
+    // Takes over the supervision of a plugin that has been started by a previous server process.
+    fn adopt(&mut self, running_plugin: &RunningPlugin) {
+        let plugin = match self.plugins.get_mut(&running_plugin.name) {
+            Some(plugin) => plugin,
+            None => {
+                println!("Adopted plugin {} has no manifest, stopping it.", running_plugin.name);
+                kill(running_plugin.pid);
+                return;
+            }
+        };
+        plugin.generation += 1;
+        plugin.state = State::Running(running_plugin.pid);
+        plugin.restarts = running_plugin.restarts;
+        plugin.started = time::SteadyTime::now();
+        plugin.adopted = true;
+    }
+
+    // An adopted plugin that lost its connection is treated as exited. It is stopped, in case it
+    // is still around, so that a restart does not leave two of them running.
+    fn on_disconnected(&mut self, pid: u32) {
+        let found = self.plugins.values()
+            .filter(|plugin| plugin.adopted)
+            .filter_map(|plugin| match plugin.state {
+                State::Running(running) if running == pid => {
+                    Some((plugin.manifest.name.clone(), plugin.generation, true))
+                },
+                State::Stopping(stopping) if stopping == pid => {
+                    Some((plugin.manifest.name.clone(), plugin.generation, false))
+                },
+                _ => None,
+            })
+            .next();
+        if let Some((name, generation, running)) = found {
+            if running {
+                kill(pid);
+            }
+            // We cannot learn the exit code of a process that is not our child.
+            self.on_exited(&name, generation, None);
+        }
+    }
+
+    fn schedule_restart(&mut self, name: &str) {
+        let plugin = self.plugins.get_mut(name).unwrap();
+        if time::SteadyTime::now() - plugin.started >
+            time::Duration::seconds(STABLE_RUNTIME_SECONDS) {
+            plugin.backoff_ms = INITIAL_BACKOFF_MS;
+        }
+        let delay = plugin.backoff_ms;
+        plugin.backoff_ms = ::std::cmp::min(plugin.backoff_ms * 2, MAX_BACKOFF_MS);
+        plugin.state = State::Restarting;
+
+        let generation = plugin.generation;
+        let commands = self.commands.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            thread::sleep(::std::time::Duration::from_millis(delay));
+            let _ = commands.send(Command::Restart(name, generation));
+        });
+    }
+
+    fn start(&mut self, name: &str) -> rpc::Result {
+        match self.plugins.get_mut(name) {
+            None => return plugin_error("unknown_plugin"),
+            Some(plugin) => {
+                if plugin.state != State::Stopped {
+                    return plugin_error("already_running");
+                }
+                plugin.backoff_ms = INITIAL_BACKOFF_MS;
+            }
+        }
+        match self.spawn_process(name) {
+            Ok(()) => rpc::Result::success(()),
+            Err(Error::Io(err)) => rpc::Result::Err(err.into()),
+            Err(_) => plugin_error("spawn_failed"),
+        }
+    }
+
+    fn stop(&mut self, name: &str) -> rpc::Result {
+        let plugin = match self.plugins.get_mut(name) {
+            None => return plugin_error("unknown_plugin"),
+            Some(plugin) => plugin,
+        };
+        plugin.state = match plugin.state {
+            State::Running(pid) => {
+                kill(pid);
+                State::Stopping(pid)
+            },
+            State::Restarting => {
+                // Make sure the pending restart is ignored.
+                plugin.generation += 1;
+                State::Stopped
+            },
+            State::Stopping(_) | State::Stopped => return plugin_error("not_running"),
+        };
+        rpc::Result::success(())
+    }
+
+    fn on_exited(&mut self, name: &str, generation: u64, exit_code: Option<i32>) {
+        let will_restart = match self.plugins.get_mut(name) {
+            Some(plugin) if plugin.generation == generation => {
+                if let State::Stopping(_) = plugin.state {
+                    plugin.state = State::Stopped;
+                    return;
+                }
+                plugin.state = State::Stopped;
+                plugin.policy.should_restart(exit_code == Some(0))
+            },
+            _ => return,
+        };
+
+        // A plugin that is done and says so did not crash.
+        if exit_code != Some(0) {
+            let _ = self.client.notify("on.plugin.crashed", &PluginCrashed {
+                name: name.to_string(),
+                exit_code: exit_code,
+                will_restart: will_restart,
+            });
+        }
+
+        if will_restart {
+            self.schedule_restart(name);
+        }
+    }
+
+    fn on_restart(&mut self, name: &str, generation: u64) {
+        let restarts = match self.plugins.get_mut(name) {
+            Some(plugin) if plugin.generation == generation &&
+                plugin.state == State::Restarting => {
+                plugin.restarts += 1;
+                plugin.restarts
+            },
+            _ => return,
+        };
+
+        if let Err(err) = self.spawn_process(name) {
+            println!("Restarting plugin {} failed: {:?}", name, err);
+            self.schedule_restart(name);
+            return;
+        }
+
+        let _ = self.client.notify("on.plugin.restarted", &PluginRestarted {
+            name: name.to_string(),
+            restarts: restarts,
+        });
+    }
+}
+
+impl spinner::Handler<Command> for Handler {
+    fn handle(&mut self, command: Command) -> Result<spinner::Command> {
+        match command {
+            Command::Quit => {
+                for plugin in self.plugins.values() {
+                    if let State::Running(pid) = plugin.state {
+                        kill(pid);
+                    }
+                }
+                return Ok(spinner::Command::Quit);
+            },
+            Command::Detach(reply) => {
+                let running = self.plugins.values()
+                    .filter_map(|plugin| match plugin.state {
+                        State::Running(pid) => Some(RunningPlugin {
+                            name: plugin.manifest.name.clone(),
+                            pid: pid,
+                            restarts: plugin.restarts,
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+                let _ = reply.send(running);
+                return Ok(spinner::Command::Quit);
+            },
+            Command::List(reply) => {
+                let mut plugins: Vec<_> = self.plugins.values().map(|plugin| plugin.status()).collect();
+                plugins.sort_by(|a, b| a.name.cmp(&b.name));
+                reply.send(rpc::Result::success(ListResponse {
+                    plugins: plugins,
+                }));
+            },
+            Command::Start(name, reply) => {
+                reply.send(self.start(&name));
+            },
+            Command::Stop(name, reply) => {
+                reply.send(self.stop(&name));
+            },
+            Command::Exited(name, generation, exit_code) => {
+                self.on_exited(&name, generation, exit_code);
+            },
+            Command::Restart(name, generation) => {
+                self.on_restart(&name, generation);
+            },
+            Command::Disconnected(pid) => {
+                self.on_disconnected(pid);
+            },
+        }
+        Ok(spinner::Command::Continue)
+    }
+}
+
+/// Starts the plugin manager thread. Plugins with 'autostart' are started right away, unless they
+/// are in 'adopted', i.e. still running from before a server handover.
+pub fn spawn(manifests: Vec<Manifest>,
+             adopted: &[RunningPlugin],
+             socket_name: &Path,
+             client: client::ThinClient,
+             tx: SenderTo,
+             rx: mpsc::Receiver<Command>) -> Result<thread::JoinHandle<()>> {
+    let mut handler = Handler {
+        plugins: HashMap::new(),
+        socket_name: socket_name.to_path_buf(),
+        commands: tx,
+        client: client,
+    };
+
+    let mut autostart = Vec::new();
+    for manifest in manifests {
+        let is_adopted = adopted.iter().any(|plugin| plugin.name == manifest.name);
+        if manifest.autostart.unwrap_or(true) && !is_adopted {
+            autostart.push(manifest.name.clone());
+        }
+        handler.plugins.insert(manifest.name.clone(), Plugin {
+            policy: try!(manifest.restart_policy()),
+            manifest: manifest,
+            state: State::Stopped,
+            generation: 0,
+            restarts: 0,
+            backoff_ms: INITIAL_BACKOFF_MS,
+            started: time::SteadyTime::now(),
+            adopted: false,
+        });
+    }
+
+    for running_plugin in adopted {
+        handler.adopt(running_plugin);
+    }
+
+    for name in autostart {
+        if let Err(err) = handler.spawn_process(&name) {
+            println!("Starting plugin {} failed: {:?}", name, err);
+        }
+    }
+
+    Ok(spinner::spawn(Receiver { commands: rx }, handler))
+}
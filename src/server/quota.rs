@@ -0,0 +1,157 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Limits how many RPCs a client may have running at the same time and how many calls it may
+//! make per second. Rules are part of the server config:
+//!
+//! ```json
+//! "rate_limits": [
+//!     { "max_in_flight": 100, "calls_per_second": 1000 },
+//!     { "client": "lua_keymap", "calls_per_second": 50 }
+//! ]
+//! ```
+//!
+//! 'client' and 'listener' select clients like in 'server::permissions'. A rule with neither
+//! applies to all clients connected through a socket, the built-in plugins are only limited by
+//! rules naming the "in_process" listener. If several rules apply, the lowest limits win.
+//!
+//! Notifications count as calls, but not as running ones. Refused notifications are dropped.
+
+use ::server::permissions::IN_PROCESS_LISTENER;
+use std::cmp;
+use time;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RateLimitRule {
+    pub client: Option<String>,
+    pub listener: Option<String>,
+    /// RPCs that may be running at the same time. Calls to 'core.' functions do not count.
+    pub max_in_flight: Option<usize>,
+    /// Sustained calls per second. Bursts of up to this many calls are allowed.
+    pub calls_per_second: Option<u32>,
+}
+
+impl RateLimitRule {
+    fn applies_to(&self, name: Option<&str>, listener: &str) -> bool {
+        if self.client.is_none() && self.listener.is_none() {
+            return listener != IN_PROCESS_LISTENER;
+        }
+        let client_matches = match (self.client.as_ref(), name) {
+            (Some(client), Some(name)) => client == name,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let listener_matches = match self.listener {
+            Some(ref rule_listener) => rule_listener == listener,
+            None => true,
+        };
+        client_matches && listener_matches
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_in_flight: Option<usize>,
+    pub calls_per_second: Option<u32>,
+}
+
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+pub struct RateLimits {
+    rules: Vec<RateLimitRule>,
+}
+
+impl RateLimits {
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        RateLimits {
+            rules: rules,
+        }
+    }
+
+    pub fn limits_for(&self, name: Option<&str>, listener: &str) -> Limits {
+        self.rules.iter()
+            .filter(|rule| rule.applies_to(name, listener))
+            .fold(Limits::default(), |limits, rule| Limits {
+                max_in_flight: lowest(limits.max_in_flight, rule.max_in_flight),
+                calls_per_second: lowest(limits.calls_per_second, rule.calls_per_second),
+            })
+    }
+}
+
+/// Why a call was refused.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Exceeded {
+    InFlight,
+    CallsPerSecond,
+}
+
+impl Exceeded {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Exceeded::InFlight => "max_in_flight",
+            Exceeded::CallsPerSecond => "calls_per_second",
+        }
+    }
+
+    /// Explains the refusal to humans.
+    pub fn message(&self) -> &'static str {
+        match *self {
+            Exceeded::InFlight => "Too many calls of this client are running.",
+            Exceeded::CallsPerSecond => "This client makes too many calls per second.",
+        }
+    }
+}
+
+/// What a single client used so far.
+pub struct Usage {
+    pub calls: u64,
+    pub rejected: u64,
+    // Token bucket for 'calls_per_second'.
+    tokens: f64,
+    last_refill: time::SteadyTime,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Usage {
+            calls: 0,
+            rejected: 0,
+            tokens: ::std::f64::MAX,
+            last_refill: time::SteadyTime::now(),
+        }
+    }
+
+    /// Accounts for a new call. 'in_flight' is the number of RPCs of this client that are already
+    /// running, or None if the call is not subject to the in-flight limit.
+    pub fn admit(&mut self, limits: &Limits, in_flight: Option<usize>) -> Result<(), Exceeded> {
+        if let (Some(max_in_flight), Some(in_flight)) = (limits.max_in_flight, in_flight) {
+            if in_flight >= max_in_flight {
+                self.rejected += 1;
+                return Err(Exceeded::InFlight);
+            }
+        }
+
+        if let Some(calls_per_second) = limits.calls_per_second {
+            let now = time::SteadyTime::now();
+            let elapsed = (now - self.last_refill).num_microseconds().unwrap_or(::std::i64::MAX);
+            let capacity = calls_per_second as f64;
+            self.tokens = (self.tokens + elapsed as f64 / 1e6 * capacity).min(capacity);
+            self.last_refill = now;
+            if self.tokens < 1. {
+                self.rejected += 1;
+                return Err(Exceeded::CallsPerSecond);
+            }
+            self.tokens -= 1.;
+        }
+
+        self.calls += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Turns SIGINT and SIGTERM into an orderly server shutdown.
+
+use ::server::ShutdownHandle;
+use libc;
+use std::mem;
+use std::ptr;
+use std::thread;
+
+pub struct TerminationSignals {
+    signals: libc::sigset_t,
+}
+
+/// Blocks SIGINT and SIGTERM for the calling thread and all threads it spawns afterwards. This
+/// must be called before any other thread is started, otherwise that thread might still receive
+/// the signals and the process dies without cleaning up.
+pub fn block_termination_signals() -> TerminationSignals {
+    unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        TerminationSignals {
+            signals: signals,
+        }
+    }
+}
+
+/// Spawns a thread that waits for one of the blocked signals and then shuts the server down.
+pub fn shutdown_on_signal(signals: TerminationSignals, handle: ShutdownHandle) {
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe {
+            libc::sigwait(&signals.signals, &mut signal);
+        }
+        println!("Received signal {}, shutting down.", signal);
+        handle.shutdown();
+    });
+}
@@ -0,0 +1,202 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Per-function call counts, error counts, in-flight gauges and latency histograms. Latency is
+//! measured in the server from receiving the call to passing the last response on to the caller.
+//! The numbers are returned by 'core.stats' and, if 'prometheus_address' is configured, served in
+//! the Prometheus text format on that address.
+//!
+//! Only functions that are registered when they are called get numbers of their own. Calls to
+//! everything else end up in UNKNOWN_FUNCTION, so that clients cannot grow the stats at will.
+//! A call answered with NotHandled did not fail and is not counted as an error.
+
+use ::rpc;
+use std::collections::HashMap;
+use std::fmt::Write;
+use time;
+
+/// Collects the calls to functions nobody registered.
+pub const UNKNOWN_FUNCTION: &'static str = "<unknown>";
+
+/// Upper bounds of the latency buckets in microseconds. A last bucket without bound catches the
+/// rest.
+pub const BUCKET_BOUNDS_US: [u64; 14] = [
+    50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000, 500000, 1000000];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bucket {
+    /// None for the last bucket.
+    pub le_us: Option<u64>,
+    /// Calls that took longer than the previous bucket's bound and at most 'le_us'.
+    pub count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub buckets: Vec<Bucket>,
+    pub sum_us: u64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        let mut buckets: Vec<_> = BUCKET_BOUNDS_US.iter()
+            .map(|bound| Bucket { le_us: Some(*bound), count: 0 })
+            .collect();
+        buckets.push(Bucket { le_us: None, count: 0 });
+        Histogram {
+            buckets: buckets,
+            sum_us: 0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, latency_us: u64) {
+        for bucket in &mut self.buckets {
+            if bucket.le_us.map(|bound| latency_us <= bound).unwrap_or(true) {
+                bucket.count += 1;
+                break;
+            }
+        }
+        self.sum_us += latency_us;
+        self.count += 1;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionStats {
+    pub function: String,
+    pub calls: u64,
+    /// Calls answered with an error, including refused and timed out ones, see 'is_error'.
+    pub errors: u64,
+    pub in_flight: usize,
+    pub latency: Histogram,
+}
+
+/// Returned by 'core.stats'.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatsResponse {
+    pub uptime_ms: i64,
+    pub functions: Vec<FunctionStats>,
+}
+
+/// Whether 'result' counts as an error.
+pub fn is_error(result: &rpc::Result) -> bool {
+    match *result {
+        rpc::Result::Err(_) => true,
+        rpc::Result::Ok(_) | rpc::Result::NotHandled => false,
+    }
+}
+
+pub struct Stats {
+    started: time::SteadyTime,
+    functions: HashMap<String, FunctionStats>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            started: time::SteadyTime::now(),
+            functions: HashMap::new(),
+        }
+    }
+
+    fn entry(&mut self, function: &str) -> &mut FunctionStats {
+        if !self.functions.contains_key(function) {
+            self.functions.insert(function.into(), FunctionStats {
+                function: function.into(),
+                calls: 0,
+                errors: 0,
+                in_flight: 0,
+                latency: Histogram::new(),
+            });
+        }
+        self.functions.get_mut(function).unwrap()
+    }
+
+    pub fn call_started(&mut self, function: &str) {
+        self.entry(function).calls += 1;
+    }
+
+    /// 'started' is when the call was received.
+    pub fn call_finished(&mut self, function: &str, started: time::SteadyTime, error: bool) {
+        let latency_us = (time::SteadyTime::now() - started).num_microseconds().unwrap_or(0);
+        let entry = self.entry(function);
+        if error {
+            entry.errors += 1;
+        }
+        entry.latency.add(if latency_us < 0 { 0 } else { latency_us as u64 });
+    }
+
+    /// 'in_flight' are the running calls per function.
+    pub fn snapshot(&self, in_flight: &HashMap<String, usize>) -> StatsResponse {
+        let mut functions: Vec<_> = self.functions.values().cloned().map(|mut entry| {
+            entry.in_flight = in_flight.get(&entry.function).cloned().unwrap_or(0);
+            entry
+        }).collect();
+        functions.sort_by(|a, b| a.function.cmp(&b.function));
+        StatsResponse {
+            uptime_ms: (time::SteadyTime::now() - self.started).num_milliseconds(),
+            functions: functions,
+        }
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+impl StatsResponse {
+    /// Formats the stats for Prometheus, see
+    /// https://prometheus.io/docs/instrumenting/exposition_formats/.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP swiboe_uptime_seconds Time since the server started.");
+        let _ = writeln!(out, "# TYPE swiboe_uptime_seconds gauge");
+        let _ = writeln!(out, "swiboe_uptime_seconds {}", self.uptime_ms as f64 / 1e3);
+
+        let _ = writeln!(out, "# HELP swiboe_rpc_calls_total RPC calls received.");
+        let _ = writeln!(out, "# TYPE swiboe_rpc_calls_total counter");
+        for entry in &self.functions {
+            let _ = writeln!(out, "swiboe_rpc_calls_total{{function=\"{}\"}} {}",
+                             escape_label(&entry.function), entry.calls);
+        }
+
+        let _ = writeln!(out, "# HELP swiboe_rpc_errors_total RPC calls answered with an error.");
+        let _ = writeln!(out, "# TYPE swiboe_rpc_errors_total counter");
+        for entry in &self.functions {
+            let _ = writeln!(out, "swiboe_rpc_errors_total{{function=\"{}\"}} {}",
+                             escape_label(&entry.function), entry.errors);
+        }
+
+        let _ = writeln!(out, "# HELP swiboe_rpc_in_flight RPC calls that are still running.");
+        let _ = writeln!(out, "# TYPE swiboe_rpc_in_flight gauge");
+        for entry in &self.functions {
+            let _ = writeln!(out, "swiboe_rpc_in_flight{{function=\"{}\"}} {}",
+                             escape_label(&entry.function), entry.in_flight);
+        }
+
+        let _ = writeln!(out, "# HELP swiboe_rpc_latency_seconds Time from call to last response.");
+        let _ = writeln!(out, "# TYPE swiboe_rpc_latency_seconds histogram");
+        for entry in &self.functions {
+            let function = escape_label(&entry.function);
+            // Prometheus buckets are cumulative.
+            let mut count = 0;
+            for bucket in &entry.latency.buckets {
+                count += bucket.count;
+                let le = match bucket.le_us {
+                    Some(bound) => format!("{}", bound as f64 / 1e6),
+                    None => "+Inf".into(),
+                };
+                let _ = writeln!(out, "swiboe_rpc_latency_seconds_bucket{{function=\"{}\",le=\"{}\"}} {}",
+                                 function, le, count);
+            }
+            let _ = writeln!(out, "swiboe_rpc_latency_seconds_sum{{function=\"{}\"}} {}",
+                             function, entry.latency.sum_us as f64 / 1e6);
+            let _ = writeln!(out, "swiboe_rpc_latency_seconds_count{{function=\"{}\"}} {}",
+                             function, entry.latency.count);
+        }
+        out
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! The topic prefixes a client subscribed to through 'core.subscribe'. A subscription can ask to
+//! get its notifications coalesced, so that a slow subscriber is not flooded by events fired at
+//! keystroke rate:
+//!
+//! ```json
+//! { "prefix": "on.cursor.", "coalesce": { "mode": { "Latest": [] }, "window_ms": 50, "key": "cursor_id" } }
+//! ```
+//!
+//! The first notification held back opens a window of 'window_ms'. When it is over, everything
+//! held back is delivered in one message. Latest only keeps the last notification per topic and
+//! value of the field 'key' of the args, Batch keeps all of them. Windows are closed on the
+//! server's tick, so they might last up to one tick longer. A handover closes them right away.
+
+use ::rpc;
+use ::server::plugin_core::{Coalesce, CoalesceMode};
+use serde_json;
+use std::mem;
+use time;
+
+pub struct Subscription {
+    pub prefix: String,
+    pub coalesce: Option<Coalesce>,
+    // Held back notifications, oldest first.
+    pending: Vec<rpc::Notification>,
+    // When the current window is over. None if nothing is held back.
+    due: Option<time::SteadyTime>,
+}
+
+// What 'CoalesceMode::Latest' keeps one notification for.
+fn key_of<'a>(notification: &'a rpc::Notification, field: &Option<String>) -> (&'a str, Option<&'a serde_json::Value>) {
+    let value = field.as_ref().and_then(|field| notification.args.find(field));
+    (&notification.topic, value)
+}
+
+impl Subscription {
+    pub fn new(prefix: String, coalesce: Option<Coalesce>) -> Self {
+        Subscription {
+            prefix: prefix,
+            coalesce: coalesce,
+            pending: Vec::new(),
+            due: None,
+        }
+    }
+
+    /// Changes how notifications are coalesced from now on. Held back notifications are still
+    /// delivered when their window is over.
+    pub fn set_coalesce(&mut self, coalesce: Option<Coalesce>) {
+        self.coalesce = coalesce;
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        topic.starts_with(&self.prefix as &str)
+    }
+
+    /// Takes 'notification', which must match. Returns the notifications to deliver right away.
+    pub fn push(&mut self, notification: rpc::Notification, now: time::SteadyTime) -> Option<Vec<rpc::Notification>> {
+        let coalesce = match self.coalesce {
+            Some(ref coalesce) => coalesce,
+            None => return Some(vec![notification]),
+        };
+
+        if coalesce.mode == CoalesceMode::Latest {
+            let key = key_of(&notification, &coalesce.key);
+            self.pending.retain(|pending| key_of(pending, &coalesce.key) != key);
+        }
+        self.pending.push(notification);
+        if self.due.is_none() {
+            self.due = Some(now + time::Duration::milliseconds(coalesce.window_ms as i64));
+        }
+        None
+    }
+
+    /// Returns the held back notifications once their window is over.
+    pub fn poll(&mut self, now: time::SteadyTime) -> Option<Vec<rpc::Notification>> {
+        match self.due {
+            Some(due) if due <= now => self.flush(),
+            _ => None,
+        }
+    }
+
+    /// Returns the held back notifications without waiting for their window to be over.
+    pub fn flush(&mut self) -> Option<Vec<rpc::Notification>> {
+        match self.due.take() {
+            Some(_) => Some(mem::replace(&mut self.pending, Vec::new())),
+            None => None,
+        }
+    }
+}
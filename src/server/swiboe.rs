@@ -5,27 +5,86 @@
 use ::error::{Error, Result};
 use ::ipc;
 use ::server::api_table;
+use ::server::call_tree;
+use ::server::config;
+use ::server::handover;
 use ::server::ipc_bridge;
+use ::server::permissions;
 use ::server::plugin_core;
+use ::server::plugin_manager;
+use ::server::quota;
+use ::server::stats;
+use ::server::subscriptions;
+use ::server::tracer;
 use ::spinner;
 use ::rpc;
 use mio;
-use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
+use serde_json;
+use std::collections::HashMap;
 use std::sync::mpsc;
 use std::thread;
+use time;
 
 const CORE_FUNCTIONS_PREFIX: &'static str = "core.";
 
+// How often the Handler gets a Tick to take care of timeouts, heartbeats and coalesced
+// notifications, once one of them is in use.
+const TICK_INTERVAL_MS: u64 = 50;
+
+// How many values streamed by the caller are kept to hand a call to the next handler, see
+// 'RunningRpc::partials'.
+const MAX_KEPT_PARTIALS: usize = 1000;
+
 pub enum Command {
     Quit,
-    NewRpc(ipc_bridge::ClientId, String, u16),
+    Tick,
+    NewRpc(ipc_bridge::ClientId, String, u16, Option<plugin_core::Execution>),
     RpcCall(ipc_bridge::ClientId, rpc::Call),
-    RpcResponse(rpc::Response),
-    RpcCancel(rpc::Cancel),
-    ClientConnected(ipc_bridge::ClientId),
+    RpcResponse(ipc_bridge::ClientId, rpc::Response),
+    RpcResponseBlob(ipc_bridge::ClientId, rpc::ResponseBlob),
+    RpcCancel(ipc_bridge::ClientId, rpc::Cancel),
+    RpcDetach(ipc_bridge::ClientId, rpc::Detach),
+    RpcCallPartial(ipc_bridge::ClientId, rpc::CallPartial),
+    Notification(ipc_bridge::ClientId, rpc::Notification),
+    Notifications(ipc_bridge::ClientId, rpc::Notifications),
+    Ping(ipc_bridge::ClientId),
+    Pong(ipc_bridge::ClientId),
+    // Carries the name of the listener the client connected through and the pid of the client
+    // process, if it can be known.
+    ClientConnected(ipc_bridge::ClientId, String, Option<u32>),
+    InProcessClientConnected(ipc_bridge::ClientId, mpsc::Sender<ipc::Message>),
     ClientDisconnected(ipc_bridge::ClientId),
     SendDataFailed(ipc_bridge::ClientId, ipc::Message, Error),
+    // Describes the state for a handover and quits. Must only be sent after the IpcBridge stopped
+    // reading, so that the state no longer changes.
+    Snapshot(mpsc::Sender<handover::SwiboeState>),
+    // Continues with the state of a previous server. The ClientIds are keyed by serial and have
+    // already been announced through ClientConnected.
+    Restore(handover::SwiboeState, HashMap<u64, ipc_bridge::ClientId>),
+    // Sent once the built-in plugins of this server are registered. Hands the restored calls the
+    // built-in plugins of the previous server did not finish to them.
+    DispatchRestoredCalls,
+    Stats(mpsc::Sender<stats::StatsResponse>),
+    // The result of a core call that was not handled right away, see 'plugin_core::Reply'.
+    CoreResult(ipc_bridge::ClientId, rpc::Call, time::SteadyTime, rpc::Result),
+}
+
+impl Command {
+    /// Wraps a 'message' received from 'client_id' into the matching Command.
+    pub fn from_message(client_id: ipc_bridge::ClientId, message: ipc::Message) -> Self {
+        match message {
+            ipc::Message::RpcCall(rpc_call) => Command::RpcCall(client_id, rpc_call),
+            ipc::Message::RpcResponse(rpc_response) => Command::RpcResponse(client_id, rpc_response),
+            ipc::Message::RpcResponseBlob(response_blob) => Command::RpcResponseBlob(client_id, response_blob),
+            ipc::Message::RpcCancel(rpc_cancel) => Command::RpcCancel(client_id, rpc_cancel),
+            ipc::Message::RpcDetach(rpc_detach) => Command::RpcDetach(client_id, rpc_detach),
+            ipc::Message::RpcCallPartial(partial) => Command::RpcCallPartial(client_id, partial),
+            ipc::Message::Notification(notification) => Command::Notification(client_id, notification),
+            ipc::Message::Notifications(notifications) => Command::Notifications(client_id, notifications),
+            ipc::Message::Ping => Command::Ping(client_id),
+            ipc::Message::Pong => Command::Pong(client_id),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -33,6 +92,49 @@ struct RunningRpc {
     caller: ipc_bridge::ClientId,
     callee: ipc_bridge::ClientId,
     rpc_call: rpc::Call,
+    started: time::SteadyTime,
+    // Everything the caller streamed so far. Handed to the next handler if the current one does
+    // not handle the call. Once the handler answered, it handles the call and nothing is
+    // kept anymore. Neither is it for a caller that streams more than MAX_KEPT_PARTIALS values
+    // before the handler answered.
+    partials: Option<Vec<rpc::CallPartial>>,
+    // Set if streamed values were not kept. The call cannot be handed to another handler then.
+    partials_lost: bool,
+    // Set once the caller does not wait for the result anymore.
+    detached: bool,
+}
+
+struct ClientInfo {
+    listener: String,
+    pid: Option<u32>,
+    // Set through 'core.handshake'.
+    name: Option<String>,
+    usage: quota::Usage,
+    last_ping: time::SteadyTime,
+    last_pong: time::SteadyTime,
+    // Set through 'core.subscribe', one per prefix.
+    subscriptions: Vec<subscriptions::Subscription>,
+    // RPCs made by this client that are still running. Calls to 'core.' functions do not count.
+    in_flight: usize,
+    // Set through 'core.set_thread_pool_size'.
+    thread_pool_size: Option<usize>,
+}
+
+impl ClientInfo {
+    fn new(listener: String, pid: Option<u32>) -> Self {
+        let now = time::SteadyTime::now();
+        ClientInfo {
+            listener: listener,
+            pid: pid,
+            name: None,
+            usage: quota::Usage::new(),
+            last_ping: now,
+            last_pong: now,
+            subscriptions: Vec::new(),
+            in_flight: 0,
+            thread_pool_size: None,
+        }
+    }
 }
 
 pub type SenderTo = mpsc::Sender<Command>;
@@ -58,92 +160,823 @@ impl spinner::Receiver<Command> for Receiver {
     }
 }
 
+// Delivers messages to clients. Clients connected through a socket are served by the IpcBridge,
+// in-process clients get their messages directly through a channel.
+struct Router {
+    ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
+    in_process_clients: HashMap<ipc_bridge::ClientId, mpsc::Sender<ipc::Message>>,
+    commands: SenderTo,
+    tracer: tracer::Tracer,
+}
+
+impl Router {
+    fn send(&mut self, receiver: ipc_bridge::ClientId, message: ipc::Message) -> Result<()> {
+        self.tracer.record(tracer::Direction::Out, receiver, &message);
+        match self.in_process_clients.get(&receiver) {
+            Some(sender) => {
+                if let Err(mpsc::SendError(message)) = sender.send(message) {
+                    try!(self.commands.send(
+                            Command::SendDataFailed(receiver, message, Error::Disconnected)));
+                }
+                Ok(())
+            },
+            None => {
+                try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(receiver, message)));
+                Ok(())
+            },
+        }
+    }
+}
+
 pub struct Handler {
     api_table: api_table::ApiTable,
-    clients: HashSet<ipc_bridge::ClientId>,
-    ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
+    clients: HashMap<ipc_bridge::ClientId, ClientInfo>,
+    router: Router,
     running_rpcs: HashMap<String, RunningRpc>,
     plugin_core: plugin_core::CorePlugin,
+    rpc_timeout: Option<time::Duration>,
+    permissions: permissions::Permissions,
+    rate_limits: quota::RateLimits,
+    heartbeat: Option<config::Heartbeat>,
+    stats: stats::Stats,
+    call_trees: call_tree::CallTrees,
+    // Whether a thread is sending us Ticks. Only started once something needs them.
+    ticking: bool,
+    // Told about processes that lost their last connection, to notice adopted plugins exiting.
+    plugin_manager: plugin_manager::SenderTo,
+    // Calls from a handover that were running in a built-in plugin of the old server, see
+    // 'Command::DispatchRestoredCalls'.
+    restored_calls: Vec<(ipc_bridge::ClientId, handover::RunningRpcState)>,
 }
 
 impl Handler {
-    pub fn new(ipc_bridge_commands: mio::Sender<ipc_bridge::Command>, commands_sender: SenderTo) -> Self {
-        Handler {
+    pub fn new(ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
+               commands_sender: SenderTo,
+               plugin_manager: plugin_manager::SenderTo,
+               config: &config::Config) -> Self {
+        let mut handler = Handler {
             api_table: api_table::ApiTable::new(),
-            clients: HashSet::new(),
+            clients: HashMap::new(),
             running_rpcs: HashMap::new(),
-            ipc_bridge_commands: ipc_bridge_commands,
-            plugin_core: plugin_core::CorePlugin::new(commands_sender),
+            router: Router {
+                ipc_bridge_commands: ipc_bridge_commands,
+                in_process_clients: HashMap::new(),
+                commands: commands_sender.clone(),
+                tracer: config.tracer(),
+            },
+            plugin_core: plugin_core::CorePlugin::new(commands_sender, plugin_manager.clone()),
+            rpc_timeout: config.rpc_timeout(),
+            permissions: config.permissions(),
+            rate_limits: config.rate_limits(),
+            heartbeat: config.heartbeat(),
+            stats: stats::Stats::new(),
+            call_trees: call_tree::CallTrees::new(call_tree::DEFAULT_MAX_TRACES),
+            ticking: false,
+            plugin_manager: plugin_manager,
+            restored_calls: Vec::new(),
+        };
+        if handler.rpc_timeout.is_some() || handler.heartbeat.is_some() {
+            handler.start_ticking();
+        }
+        handler
+    }
+
+    fn start_ticking(&mut self) {
+        if self.ticking {
+            return;
+        }
+        self.ticking = true;
+        let ticker = self.router.commands.clone();
+        thread::spawn(move || {
+            // Stops once the Handler is gone.
+            loop {
+                thread::sleep(::std::time::Duration::from_millis(TICK_INTERVAL_MS));
+                if ticker.send(Command::Tick).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn insert_running_rpc(&mut self, context: String, running_rpc: RunningRpc) {
+        if let Some(info) = self.clients.get_mut(&running_rpc.caller) {
+            info.in_flight += 1;
+        }
+        self.running_rpcs.insert(context, running_rpc);
+    }
+
+    fn remove_running_rpc(&mut self, context: &str) -> Option<RunningRpc> {
+        let running_rpc = self.running_rpcs.remove(context);
+        if let Some(ref running_rpc) = running_rpc {
+            if let Some(info) = self.clients.get_mut(&running_rpc.caller) {
+                info.in_flight -= 1;
+            }
         }
+        running_rpc
+    }
+
+    // Accounts for a call or notification and returns the error to answer with if 'client_id' is
+    // over its limits. Only calls that keep running count against 'max_in_flight'.
+    fn check_rate_limits(&mut self, client_id: ipc_bridge::ClientId, counts_in_flight: bool) -> Option<rpc::Error> {
+        let info = match self.clients.get_mut(&client_id) {
+            Some(info) => info,
+            None => return None,
+        };
+        let in_flight = if counts_in_flight { Some(info.in_flight) } else { None };
+        let limits = self.rate_limits.limits_for(
+            info.name.as_ref().map(|name| name as &str), &info.listener);
+        match info.usage.admit(&limits, in_flight) {
+            Ok(()) => None,
+            Err(exceeded) => Some(rpc::Error {
+                kind: rpc::ErrorKind::RateLimited,
+                message: Some(exceeded.message().into()),
+                details: Some(serde_json::to_value(&exceeded.description())),
+            }),
+        }
+    }
+
+    fn clients_status(&self) -> rpc::Result {
+        let mut clients: Vec<_> = self.clients.iter().map(|(client_id, info)| {
+            plugin_core::ClientStatus {
+                serial: client_id.serial,
+                name: info.name.clone(),
+                listener: info.listener.clone(),
+                in_flight: info.in_flight,
+                calls: info.usage.calls,
+                rejected: info.usage.rejected,
+            }
+        }).collect();
+        clients.sort_by(|a, b| (&a.listener, a.serial).cmp(&(&b.listener, b.serial)));
+        rpc::Result::success(plugin_core::ClientsResponse {
+            clients: clients,
+        })
     }
 
-    fn on_rpc_cancel(&mut self, rpc_cancel: rpc::Cancel) -> Result<()> {
-        // NOCOM(#sirver): only the original caller can cancel, really.
-        // Simply drop this message for unknown RPC
-        if let Some(running_rpc) = self.running_rpcs.get(&rpc_cancel.context) {
-            try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                running_rpc.callee,
-                ipc::Message::RpcCancel(rpc_cancel)
-                )));
+    // Returns the error to answer with if 'client_id' may not do 'rpc_call'. Registering an RPC
+    // is a call to 'core.new_rpc', so that permission is checked here as well. A registration we
+    // cannot make sense of is refused, since we cannot tell what would be registered.
+    fn check_permissions(&self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> Option<rpc::Error> {
+        let (name, listener) = self.identity(client_id);
+
+        let denied = |details: String| Some(rpc::Error {
+            kind: rpc::ErrorKind::PermissionDenied,
+            message: Some(format!("This client may not {}.", details)),
+            details: Some(serde_json::to_value(&details)),
+        });
+
+        if !self.permissions.may_call(name, listener, &rpc_call.function) {
+            return denied(format!("call {}", rpc_call.function));
+        }
+        if rpc_call.function == "core.new_rpc" {
+            match serde_json::from_value::<plugin_core::NewRpcRequest>(rpc_call.args.clone()) {
+                Ok(request) => {
+                    if !self.permissions.may_register(name, listener, &request.name) {
+                        return denied(format!("register {}", request.name));
+                    }
+                },
+                Err(err) => return Some(err.into()),
+            }
+        }
+        None
+    }
+
+    // Notifications are checked like calls to their topic. Nobody waits for an answer, so refused
+    // ones are dropped.
+    fn admit_notification(&mut self, client_id: ipc_bridge::ClientId, topic: &str) -> bool {
+        let permitted = {
+            let (name, listener) = self.identity(client_id);
+            self.permissions.may_call(name, listener, topic)
+        };
+        permitted && self.check_rate_limits(client_id, false).is_none()
+    }
+
+    // The handshake name and listener permission and rate limit rules refer to.
+    fn identity(&self, client_id: ipc_bridge::ClientId) -> (Option<&str>, &str) {
+        match self.clients.get(&client_id) {
+            Some(info) => (info.name.as_ref().map(|name| name as &str), &info.listener as &str),
+            None => (None, ""),
+        }
+    }
+
+    // The function the stats of a call to 'function' are kept under. Calls of running RPCs are
+    // always kept under their function, since they were registered when the call came in.
+    fn stats_name<'a>(&self, function: &'a String) -> &'a str {
+        if function.starts_with(CORE_FUNCTIONS_PREFIX) || self.api_table.get_first(function).is_some() {
+            function
+        } else {
+            stats::UNKNOWN_FUNCTION
+        }
+    }
+
+    fn stats_response(&self) -> stats::StatsResponse {
+        let mut in_flight = HashMap::new();
+        for running_rpc in self.running_rpcs.values() {
+            *in_flight.entry(running_rpc.rpc_call.function.clone()).or_insert(0) += 1;
+        }
+        self.stats.snapshot(&in_flight)
+    }
+
+    fn call_trees_response(&self, rpc_call: &rpc::Call) -> rpc::Result {
+        let request: call_tree::CallTreesRequest = match serde_json::from_value(rpc_call.args.clone()) {
+            Ok(request) => request,
+            Err(err) => return rpc::Result::Err(err.into()),
+        };
+        let running: Vec<_> = self.running_rpcs.values().map(|running_rpc| call_tree::RunningCall {
+            rpc_call: &running_rpc.rpc_call,
+            caller: running_rpc.caller.serial,
+            started: running_rpc.started,
+        }).collect();
+        rpc::Result::success(self.call_trees.report(&request, &running))
+    }
+
+    // Records the message behind 'command' if it was received from a client.
+    fn trace_incoming(&mut self, command: &Command) {
+        if !self.router.tracer.is_enabled() {
+            return;
+        }
+        let (client_id, message) = match *command {
+            Command::RpcCall(client_id, ref rpc_call) => (client_id, ipc::Message::RpcCall(rpc_call.clone())),
+            Command::RpcResponse(client_id, ref rpc_response) => {
+                (client_id, ipc::Message::RpcResponse(rpc_response.clone()))
+            },
+            Command::RpcResponseBlob(client_id, ref response_blob) => {
+                (client_id, ipc::Message::RpcResponseBlob(response_blob.clone()))
+            },
+            Command::RpcCancel(client_id, ref rpc_cancel) => {
+                (client_id, ipc::Message::RpcCancel(rpc_cancel.clone()))
+            },
+            Command::RpcDetach(client_id, ref rpc_detach) => {
+                (client_id, ipc::Message::RpcDetach(rpc_detach.clone()))
+            },
+            Command::RpcCallPartial(client_id, ref partial) => {
+                (client_id, ipc::Message::RpcCallPartial(partial.clone()))
+            },
+            Command::Notification(client_id, ref notification) => {
+                (client_id, ipc::Message::Notification(notification.clone()))
+            },
+            Command::Notifications(client_id, ref notifications) => {
+                (client_id, ipc::Message::Notifications(notifications.clone()))
+            },
+            Command::Ping(client_id) => (client_id, ipc::Message::Ping),
+            Command::Pong(client_id) => (client_id, ipc::Message::Pong),
+            _ => return,
+        };
+        self.router.tracer.record(tracer::Direction::In, client_id, &message);
+    }
+
+    fn set_thread_pool_size(&mut self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
+        let request: plugin_core::ThreadPoolRequest = match serde_json::from_value(rpc_call.args.clone()) {
+            Ok(request) => request,
+            Err(err) => return rpc::Result::Err(err.into()),
+        };
+        if let Some(info) = self.clients.get_mut(&client_id) {
+            info.thread_pool_size = Some(request.size);
+        }
+        rpc::Result::success(())
+    }
+
+    // A client names itself only once: the name decides which rules apply to it, so it must not be
+    // able to switch to the name of a more trusted client later on.
+    fn handshake(&mut self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
+        let request: plugin_core::HandshakeRequest = match serde_json::from_value(rpc_call.args.clone()) {
+            Ok(request) => request,
+            Err(err) => return rpc::Result::Err(err.into()),
+        };
+        let info = match self.clients.get_mut(&client_id) {
+            Some(info) => info,
+            None => return rpc::Result::success(()),
+        };
+        if let Some(ref name) = info.name {
+            return rpc::Result::Err(rpc::Error {
+                kind: rpc::ErrorKind::PermissionDenied,
+                message: Some(format!("This client already did its handshake as {}.", name)),
+                details: Some(serde_json::to_value(&"handshake again")),
+            });
+        }
+        if !self.permissions.may_claim(&info.listener, &request.name) {
+            return rpc::Result::Err(rpc::Error {
+                kind: rpc::ErrorKind::PermissionDenied,
+                message: Some(format!("This client may not call itself {}.", request.name)),
+                details: Some(serde_json::to_value(&format!("handshake as {}", request.name))),
+            });
+        }
+        self.router.tracer.set_name(client_id, request.name.clone());
+        info.name = Some(request.name);
+        rpc::Result::success(())
+    }
+
+    fn subscribe(&mut self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
+        let request: plugin_core::SubscribeRequest = match serde_json::from_value(rpc_call.args.clone()) {
+            Ok(request) => request,
+            Err(err) => return rpc::Result::Err(err.into()),
+        };
+        let coalesced = request.coalesce.is_some();
+        {
+            let info = match self.clients.get_mut(&client_id) {
+                Some(info) => info,
+                None => return rpc::Result::success(()),
+            };
+            let existing = info.subscriptions.iter().position(|subscription| subscription.prefix == request.prefix);
+            match existing {
+                Some(index) => info.subscriptions[index].set_coalesce(request.coalesce),
+                None => info.subscriptions.push(subscriptions::Subscription::new(request.prefix, request.coalesce)),
+            }
+        }
+        // Held back notifications are delivered on a Tick.
+        if coalesced {
+            self.start_ticking();
+        }
+        rpc::Result::success(())
+    }
+
+    fn unsubscribe(&mut self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
+        let request: plugin_core::SubscribeRequest = match serde_json::from_value(rpc_call.args.clone()) {
+            Ok(request) => request,
+            Err(err) => return rpc::Result::Err(err.into()),
+        };
+        if let Some(info) = self.clients.get_mut(&client_id) {
+            info.subscriptions.retain(|subscription| subscription.prefix != request.prefix);
+        }
+        rpc::Result::success(())
+    }
+
+    // Hands 'notification' to every subscription to a prefix of its topic. Coalesced ones hold it
+    // back.
+    fn on_notification(&mut self, notification: rpc::Notification) -> Result<()> {
+        let now = time::SteadyTime::now();
+        let mut deliveries = Vec::new();
+        for (client_id, info) in self.clients.iter_mut() {
+            for subscription in info.subscriptions.iter_mut() {
+                if !subscription.matches(&notification.topic) {
+                    continue;
+                }
+                if let Some(notifications) = subscription.push(notification.clone(), now) {
+                    deliveries.push((*client_id, subscription.prefix.clone(), notifications));
+                }
+            }
+        }
+        self.deliver(deliveries)
+    }
+
+    // Delivers the notifications held back by coalesced subscriptions whose window is over, or
+    // all of them if 'early'.
+    fn flush_notifications(&mut self, early: bool) -> Result<()> {
+        let now = time::SteadyTime::now();
+        let mut deliveries = Vec::new();
+        for (client_id, info) in self.clients.iter_mut() {
+            for subscription in info.subscriptions.iter_mut() {
+                let notifications = if early { subscription.flush() } else { subscription.poll(now) };
+                if let Some(notifications) = notifications {
+                    deliveries.push((*client_id, subscription.prefix.clone(), notifications));
+                }
+            }
+        }
+        self.deliver(deliveries)
+    }
+
+    fn deliver(&mut self, deliveries: Vec<(ipc_bridge::ClientId, String, Vec<rpc::Notification>)>) -> Result<()> {
+        for (client_id, prefix, notifications) in deliveries {
+            try!(self.router.send(client_id, ipc::Message::Notifications(rpc::Notifications {
+                prefix: prefix,
+                notifications: notifications,
+            })));
         }
         Ok(())
     }
 
-    fn on_rpc_response(&mut self, rpc_response: rpc::Response) -> Result<()> {
-        let mut running_rpc = match self.running_rpcs.entry(rpc_response.context.clone()) {
-            Entry::Occupied(running_rpc) => running_rpc,
-            Entry::Vacant(_) => {
-                // Unknown RPC. We simply drop this message.
-                return Ok(());
+    fn trace(&mut self, client_id: ipc_bridge::ClientId, rpc_call: &rpc::Call) -> rpc::Result {
+        let was_enabled = self.router.tracer.is_enabled();
+        let result = match serde_json::from_value::<tracer::TraceRequest>(rpc_call.args.clone()) {
+            Ok(request) => self.router.tracer.configure(request),
+            Err(err) => rpc::Result::Err(err.into()),
+        };
+        if !was_enabled && self.router.tracer.is_enabled() {
+            // So that its response is attributed to 'core.trace' as well.
+            self.router.tracer.record(tracer::Direction::In, client_id, &ipc::Message::RpcCall(rpc_call.clone()));
+        }
+        result
+    }
+
+    fn on_tick(&mut self) -> Result<()> {
+        try!(self.check_heartbeats());
+        try!(self.flush_notifications(false));
+        self.time_out_rpcs()
+    }
+
+    // Pings clients connected through a socket and disconnects the ones that stopped answering.
+    // In-process clients cannot get lost.
+    fn check_heartbeats(&mut self) -> Result<()> {
+        let heartbeat = match self.heartbeat {
+            Some(heartbeat) => heartbeat,
+            None => return Ok(()),
+        };
+
+        let now = time::SteadyTime::now();
+        let mut to_ping = Vec::new();
+        for (client_id, info) in self.clients.iter_mut() {
+            if client_id.token == ipc_bridge::IN_PROCESS {
+                continue;
             }
+            if now - info.last_pong > heartbeat.timeout {
+                // The IpcBridge tells us through ClientDisconnected once it is gone.
+                try!(self.router.ipc_bridge_commands.send(ipc_bridge::Command::Disconnect(*client_id)));
+                // Do not ask again on the next tick.
+                info.last_pong = now;
+            } else if now - info.last_ping >= heartbeat.interval {
+                info.last_ping = now;
+                to_ping.push(*client_id);
+            }
+        }
+        for client_id in to_ping {
+            try!(self.router.send(client_id, ipc::Message::Ping));
+        }
+        Ok(())
+    }
+
+    // Answers all RPCs that are running for longer than 'rpc_timeout' with a Timeout error and
+    // cancels them on the callee's side.
+    fn time_out_rpcs(&mut self) -> Result<()> {
+        let timeout = match self.rpc_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
         };
 
+        let now = time::SteadyTime::now();
+        let expired: Vec<_> = self.running_rpcs.iter()
+            .filter(|&(_, running_rpc)| now - running_rpc.started > timeout)
+            .map(|(context, _)| context.clone())
+            .collect();
+
+        for context in expired {
+            let running_rpc = self.remove_running_rpc(&context).unwrap();
+            self.stats.call_finished(&running_rpc.rpc_call.function, running_rpc.started, true);
+            self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                     running_rpc.started, call_tree::Outcome::TimedOut);
+            try!(self.router.send(
+                    running_rpc.callee,
+                    ipc::Message::RpcCancel(rpc::Cancel {
+                        context: context.clone(),
+                    })));
+            try!(self.router.send(
+                    running_rpc.caller,
+                    ipc::Message::RpcResponse(rpc::Response {
+                        context: context,
+                        kind: rpc::ResponseKind::Last(rpc::Result::Err(rpc::Error {
+                            kind: rpc::ErrorKind::Timeout,
+                            message: Some("The call ran longer than the server allows.".into()),
+                            details: None,
+                        })),
+                    })));
+        }
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> Result<handover::SwiboeState> {
+        let is_in_process = |client_id: &ipc_bridge::ClientId| client_id.token == ipc_bridge::IN_PROCESS;
+
+        let client_names = self.clients.iter()
+            .filter(|&(client_id, _)| !is_in_process(client_id))
+            .filter_map(|(client_id, info)| info.name.as_ref().map(|name| handover::ClientName {
+                client: client_id.serial,
+                name: name.clone(),
+            }))
+            .collect();
+
+        let thread_pools = self.clients.iter()
+            .filter(|&(client_id, _)| !is_in_process(client_id))
+            .filter_map(|(client_id, info)| info.thread_pool_size.map(|size| handover::ThreadPool {
+                client: client_id.serial,
+                size: size,
+            }))
+            .collect();
+
+        // The new server starts without held back notifications, so they are delivered early
+        // while the IpcBridge is still writing.
+        try!(self.flush_notifications(true));
+        let mut subscriptions = Vec::new();
+        for (client_id, info) in &self.clients {
+            if is_in_process(client_id) {
+                continue;
+            }
+            for subscription in &info.subscriptions {
+                subscriptions.push(handover::Subscription {
+                    client: client_id.serial,
+                    prefix: subscription.prefix.clone(),
+                    coalesce: subscription.coalesce.clone(),
+                });
+            }
+        }
+
+        let api_table = self.api_table.entries().into_iter()
+            .filter(|&(_, info)| !is_in_process(&info.client_id))
+            .map(|(name, info)| handover::ApiEntry {
+                name: name.clone(),
+                client: info.client_id.serial,
+                priority: info.priority,
+                execution: info.execution,
+            })
+            .collect();
+
+        // In-process clients do not survive the handover. Nobody is waiting for the calls they
+        // made anymore, so these are cancelled while the IpcBridge is still writing. The calls
+        // they handle are run again by the built-in plugins of the new server.
+        let mut running_rpcs = Vec::new();
+        for (context, running_rpc) in self.running_rpcs.drain() {
+            if is_in_process(&running_rpc.caller) {
+                if !is_in_process(&running_rpc.callee) {
+                    try!(self.router.send(
+                            running_rpc.callee,
+                            ipc::Message::RpcCancel(rpc::Cancel {
+                                context: context,
+                            })));
+                }
+                continue;
+            }
+            let callee = if is_in_process(&running_rpc.callee) {
+                None
+            } else {
+                Some(running_rpc.callee.serial)
+            };
+            running_rpcs.push(handover::RunningRpcState {
+                caller: running_rpc.caller.serial,
+                callee: callee,
+                rpc_call: running_rpc.rpc_call,
+                detached: running_rpc.detached,
+                partials: running_rpc.partials,
+                partials_lost: running_rpc.partials_lost,
+            });
+        }
+
+        Ok(handover::SwiboeState {
+            client_names: client_names,
+            thread_pools: thread_pools,
+            subscriptions: subscriptions,
+            api_table: api_table,
+            running_rpcs: running_rpcs,
+        })
+    }
+
+    fn restore(&mut self, state: handover::SwiboeState, clients: HashMap<u64, ipc_bridge::ClientId>) {
+        for client_name in state.client_names {
+            if let Some(client_id) = clients.get(&client_name.client) {
+                if let Some(info) = self.clients.get_mut(client_id) {
+                    self.router.tracer.set_name(*client_id, client_name.name.clone());
+                    info.name = Some(client_name.name);
+                }
+            }
+        }
+
+        for thread_pool in state.thread_pools {
+            if let Some(client_id) = clients.get(&thread_pool.client) {
+                if let Some(info) = self.clients.get_mut(client_id) {
+                    info.thread_pool_size = Some(thread_pool.size);
+                }
+            }
+        }
+
+        let mut coalesced = false;
+        for subscription in state.subscriptions {
+            if let Some(client_id) = clients.get(&subscription.client) {
+                if let Some(info) = self.clients.get_mut(client_id) {
+                    coalesced |= subscription.coalesce.is_some();
+                    info.subscriptions.push(subscriptions::Subscription::new(
+                            subscription.prefix, subscription.coalesce));
+                }
+            }
+        }
+
+        if coalesced {
+            self.start_ticking();
+        }
+
+        for entry in state.api_table {
+            if let Some(client_id) = clients.get(&entry.client) {
+                self.api_table.register(entry.name, api_table::ApiInfo {
+                    client_id: *client_id,
+                    priority: entry.priority,
+                    execution: entry.execution,
+                });
+            }
+        }
+
+        let now = time::SteadyTime::now();
+        for running_rpc in state.running_rpcs {
+            let caller = match clients.get(&running_rpc.caller) {
+                Some(caller) => *caller,
+                None => continue,
+            };
+            let callee = match running_rpc.callee {
+                Some(serial) => match clients.get(&serial) {
+                    Some(callee) => *callee,
+                    None => continue,
+                },
+                None => {
+                    self.restored_calls.push((caller, running_rpc));
+                    continue;
+                },
+            };
+            self.insert_running_rpc(running_rpc.rpc_call.context.clone(), RunningRpc {
+                caller: caller,
+                callee: callee,
+                rpc_call: running_rpc.rpc_call,
+                started: now,
+                partials: running_rpc.partials,
+                partials_lost: running_rpc.partials_lost,
+                detached: running_rpc.detached,
+            });
+        }
+    }
+
+    // Hands 'rpc_call' to the first handler registered for it.
+    fn dispatch(&mut self,
+                caller: ipc_bridge::ClientId,
+                rpc_call: rpc::Call,
+                received: time::SteadyTime) -> Result<()> {
+        match self.api_table.get_first(&rpc_call.function).map(|info| (info.client_id, info.execution)) {
+            Some((callee, execution)) => {
+                if let Some(cycle) = self.wait_for_cycle(&rpc_call, callee, execution) {
+                    return self.answer_with_error(caller, &rpc_call, received, deadlock_error(cycle));
+                }
+                // NOCOM(#sirver): eventually, when we keep proper track of our rpc calls, this should be
+                // able to move again.
+                self.insert_running_rpc(rpc_call.context.clone(), RunningRpc {
+                    caller: caller,
+                    callee: callee,
+                    rpc_call: rpc_call.clone(),
+                    started: received,
+                    partials: Some(Vec::new()),
+                    partials_lost: false,
+                    detached: false,
+                });
+                // NOCOM(#sirver): we ignore timeouts.
+                self.router.send(callee, ipc::Message::RpcCall(rpc_call))
+            },
+            None => {
+                self.answer_with_error(caller, &rpc_call, received, rpc::Error {
+                    kind: rpc::ErrorKind::UnknownRpc,
+                    message: Some(format!("Nobody registered {}.", rpc_call.function)),
+                    details: None,
+                })
+            }
+        }
+    }
+
+    // Only the caller can cancel. It does not wait for an answer anymore, so the call is over for
+    // us. Whatever the handler still sends is dropped.
+    fn on_rpc_cancel(&mut self, client_id: ipc_bridge::ClientId, rpc_cancel: rpc::Cancel) -> Result<()> {
+        let is_caller = self.running_rpcs.get(&rpc_cancel.context)
+            .map_or(false, |running_rpc| running_rpc.caller == client_id);
+        if !is_caller {
+            return Ok(());
+        }
+        let running_rpc = self.remove_running_rpc(&rpc_cancel.context).unwrap();
+        self.stats.call_finished(&running_rpc.rpc_call.function, running_rpc.started, false);
+        self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                 running_rpc.started, call_tree::Outcome::Cancelled);
+        self.router.send(running_rpc.callee, ipc::Message::RpcCancel(rpc_cancel))
+    }
+
+    fn on_rpc_detach(&mut self, client_id: ipc_bridge::ClientId, rpc_detach: rpc::Detach) {
+        // Only the caller can stop waiting.
+        if let Some(running_rpc) = self.running_rpcs.get_mut(&rpc_detach.context) {
+            if running_rpc.caller == client_id {
+                running_rpc.detached = true;
+            }
+        }
+    }
+
+    fn on_rpc_call_partial(&mut self, client_id: ipc_bridge::ClientId, partial: rpc::CallPartial) -> Result<()> {
+        // Only the caller streams to the handler. Partials for unknown RPCs are dropped.
+        let callee = match self.running_rpcs.get_mut(&partial.context) {
+            Some(running_rpc) => {
+                if running_rpc.caller != client_id {
+                    return Ok(());
+                }
+                let keep = running_rpc.partials.as_ref().map_or(false, |partials| partials.len() < MAX_KEPT_PARTIALS);
+                if keep {
+                    running_rpc.partials.as_mut().unwrap().push(partial.clone());
+                } else {
+                    running_rpc.partials = None;
+                    running_rpc.partials_lost = true;
+                }
+                running_rpc.callee
+            },
+            None => return Ok(()),
+        };
+        self.router.send(callee, ipc::Message::RpcCallPartial(partial))
+    }
+
+    // Like a successful last response in 'on_rpc_response'. The Blob is passed on untouched, the
+    // IpcBridge takes the result out for callers that cannot.
+    fn on_rpc_response_blob(&mut self, response_blob: rpc::ResponseBlob) -> Result<()> {
+        let running_rpc = match self.remove_running_rpc(&response_blob.context) {
+            Some(running_rpc) => running_rpc,
+            None => {
+                // Unknown RPC, nobody is going to take it.
+                let _ = response_blob.blob.discard();
+                return Ok(());
+            },
+        };
+        self.stats.call_finished(&running_rpc.rpc_call.function, running_rpc.started, false);
+        self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                 running_rpc.started, call_tree::Outcome::Ok);
+        self.router.send(running_rpc.caller, ipc::Message::RpcResponseBlob(response_blob))
+    }
+
+    fn on_rpc_response(&mut self, rpc_response: rpc::Response) -> Result<()> {
+        if !self.running_rpcs.contains_key(&rpc_response.context) {
+            // Unknown RPC. We simply drop this message.
+            return Ok(());
+        }
+
         match rpc_response.kind {
             rpc::ResponseKind::Partial(value) => {
-                let running_rpc = running_rpc.get();
-                try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                        running_rpc.caller,
+                let caller = {
+                    // The handler took the call, nobody else needs what the caller streamed.
+                    let running_rpc = self.running_rpcs.get_mut(&rpc_response.context).unwrap();
+                    if let Some(partials) = running_rpc.partials.take() {
+                        running_rpc.partials_lost |= !partials.is_empty();
+                    }
+                    running_rpc.caller
+                };
+                try!(self.router.send(
+                        caller,
                         ipc::Message::RpcResponse(rpc::Response {
-                            context: running_rpc.rpc_call.context.clone(),
+                            context: rpc_response.context,
                             kind: rpc::ResponseKind::Partial(value),
-                        }))));
+                        })));
             },
             rpc::ResponseKind::Last(result) => match result {
                 rpc::Result::Ok(_) | rpc::Result::Err(_) => {
-                    let running_rpc = running_rpc.remove();
-                    try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
+                    let running_rpc = self.remove_running_rpc(&rpc_response.context).unwrap();
+                    self.stats.call_finished(
+                        &running_rpc.rpc_call.function, running_rpc.started, stats::is_error(&result));
+                    self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                             running_rpc.started, call_tree::Outcome::from_result(&result));
+                    try!(self.router.send(
                             running_rpc.caller,
                             ipc::Message::RpcResponse(rpc::Response {
                                 context: running_rpc.rpc_call.context,
                                 kind: rpc::ResponseKind::Last(
                                     result
                                 ),
-                            }))));
+                            })));
                 },
                 rpc::Result::NotHandled => {
                     // TODO(sirver): If a new function has been registered or been deleted since we
                     // last saw this context, this might skip a handler or call one twice. We need
                     // a better way to keep track where we are in the list of handlers.
-                    let running_rpc = running_rpc.get_mut();
+                    let next = {
+                        let running_rpc = &self.running_rpcs[&rpc_response.context];
+                        self.api_table.get_next(&running_rpc.rpc_call.function, &running_rpc.callee)
+                            .map(|info| (info.client_id, info.execution))
+                    };
 
                     // NOCOM(#sirver): quite some code duplication with RpcCall
-                    match self.api_table.get_next(&running_rpc.rpc_call.function, &running_rpc.callee) {
-                        Some(info) => {
+                    match next {
+                        Some(_) if self.running_rpcs[&rpc_response.context].partials_lost => {
+                            let running_rpc = self.remove_running_rpc(&rpc_response.context).unwrap();
+                            try!(self.answer_with_error(running_rpc.caller, &running_rpc.rpc_call,
+                                                        running_rpc.started, partials_lost_error()));
+                        },
+                        Some((client_id, execution)) => {
+                            let cycle = self.wait_for_cycle(
+                                &self.running_rpcs[&rpc_response.context].rpc_call, client_id, execution);
+                            if let Some(cycle) = cycle {
+                                let running_rpc = self.remove_running_rpc(&rpc_response.context).unwrap();
+                                try!(self.answer_with_error(running_rpc.caller, &running_rpc.rpc_call,
+                                                            running_rpc.started, deadlock_error(cycle)));
+                                return Ok(());
+                            }
+
+                            let running_rpc = self.running_rpcs.get_mut(&rpc_response.context).unwrap();
                             // NOCOM(#sirver): eventually, when we keep proper track of our rpc calls, this should be
                             // able to move again.
-                            try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                                    info.client_id,
+                            try!(self.router.send(
+                                    client_id,
                                     ipc::Message::RpcCall(running_rpc.rpc_call.clone())
-                                    )));
-                            running_rpc.callee = info.client_id;
+                                    ));
+                            let partials = running_rpc.partials.take().unwrap_or(Vec::new());
+                            for partial in &partials {
+                                try!(self.router.send(
+                                        client_id, ipc::Message::RpcCallPartial(partial.clone())));
+                            }
+                            running_rpc.partials = Some(partials);
+                            running_rpc.callee = client_id;
                         },
                         None => {
-                            try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
+                            // Nobody handled it, so the call is over.
+                            let running_rpc = self.remove_running_rpc(&rpc_response.context).unwrap();
+                            self.stats.call_finished(&running_rpc.rpc_call.function, running_rpc.started,
+                                                     stats::is_error(&rpc::Result::NotHandled));
+                            self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                                     running_rpc.started, call_tree::Outcome::NotHandled);
+                            try!(self.router.send(
                                     running_rpc.caller,
                                     ipc::Message::RpcResponse(rpc::Response {
-                                        context: running_rpc.rpc_call.context.clone(),
+                                        context: running_rpc.rpc_call.context,
                                         kind: rpc::ResponseKind::Last(rpc::Result::NotHandled),
-                                    }))));
+                                    })));
                         }
                     };
                     // NOCOM(#sirver): we ignore timeouts.
@@ -152,82 +985,224 @@ impl Handler {
         };
         Ok(())
     }
+
+    // If 'callee' is already running one of the calls that 'rpc_call' was made from, that handler
+    // waits for 'rpc_call' to finish. 'rpc_call' cannot start before it did if it needs what the
+    // handler holds: the thread of a Serial RPC when it is the same one, or the only thread of a
+    // client that announced a thread pool of 1. Callers waiting for a detached call are not waiting
+    // for anything, so the chain ends there. Returns the functions forming the cycle, outermost
+    // first.
+    fn wait_for_cycle(&self,
+                      rpc_call: &rpc::Call,
+                      callee: ipc_bridge::ClientId,
+                      execution: Option<plugin_core::Execution>) -> Option<Vec<String>> {
+        let single_thread = self.clients.get(&callee)
+            .map(|info| info.thread_pool_size == Some(1))
+            .unwrap_or(false);
+        let mut chain = vec![rpc_call.function.clone()];
+        let mut child = self.running_rpcs.get(&rpc_call.context);
+        let mut parent = rpc_call.parent.as_ref();
+        // Parent links come from the clients, so they might loop.
+        while chain.len() <= self.running_rpcs.len() {
+            if child.map(|child| child.detached).unwrap_or(false) {
+                return None;
+            }
+            let running_rpc = match parent.and_then(|context| self.running_rpcs.get(context)) {
+                Some(running_rpc) => running_rpc,
+                None => return None,
+            };
+            chain.push(running_rpc.rpc_call.function.clone());
+            if running_rpc.callee == callee {
+                let blocked = match execution {
+                    Some(plugin_core::Execution::Serial) => {
+                        running_rpc.rpc_call.function == rpc_call.function
+                    },
+                    Some(plugin_core::Execution::Concurrent) => {
+                        let running_on_pool = self.api_table.get(&running_rpc.rpc_call.function, &callee)
+                            .map(|info| info.execution == Some(plugin_core::Execution::Concurrent))
+                            .unwrap_or(false);
+                        single_thread && running_on_pool
+                    },
+                    Some(plugin_core::Execution::Dedicated) | None => false,
+                };
+                if blocked {
+                    chain.reverse();
+                    return Some(chain);
+                }
+            }
+            child = Some(running_rpc);
+            parent = running_rpc.rpc_call.parent.as_ref();
+        }
+        None
+    }
+
+    fn finish_core_call(&mut self,
+                        caller: ipc_bridge::ClientId,
+                        rpc_call: &rpc::Call,
+                        received: time::SteadyTime,
+                        result: rpc::Result) -> Result<()> {
+        self.stats.call_finished(&rpc_call.function, received, stats::is_error(&result));
+        self.call_trees.finished(&rpc_call, caller.serial, received,
+                                 call_tree::Outcome::from_result(&result));
+        self.router.send(
+            caller,
+            ipc::Message::RpcResponse(rpc::Response {
+                context: rpc_call.context.clone(),
+                kind: rpc::ResponseKind::Last(result),
+            }))
+    }
+
+    // Answers 'rpc_call' right away with 'error'.
+    fn answer_with_error(&mut self,
+                         caller: ipc_bridge::ClientId,
+                         rpc_call: &rpc::Call,
+                         started: time::SteadyTime,
+                         error: rpc::Error) -> Result<()> {
+        // Happens right after the call came in, so the function is registered as it was then.
+        let function = self.stats_name(&rpc_call.function).to_string();
+        self.stats.call_finished(&function, started, true);
+        self.call_trees.finished(&rpc_call, caller.serial, started, call_tree::Outcome::Error);
+        self.router.send(
+            caller,
+            ipc::Message::RpcResponse(rpc::Response {
+                context: rpc_call.context.clone(),
+                kind: rpc::ResponseKind::Last(rpc::Result::Err(error)),
+            }))
+    }
+}
+
+fn partials_lost_error() -> rpc::Error {
+    rpc::Error {
+        kind: rpc::ErrorKind::Internal,
+        message: Some(format!("The handler did not handle the call, but the values streamed to it \
+                               were not kept for the next one (at most {} are).", MAX_KEPT_PARTIALS)),
+        details: None,
+    }
+}
+
+fn deadlock_error(cycle: Vec<String>) -> rpc::Error {
+    rpc::Error {
+        kind: rpc::ErrorKind::Deadlock,
+        message: Some(format!("The call would wait for itself: {}.", cycle.join(" -> "))),
+        details: Some(serde_json::to_value(&cycle)),
+    }
 }
 
 impl spinner::Handler<Command> for Handler {
     fn handle(&mut self, command: Command) -> Result<spinner::Command> {
+        self.trace_incoming(&command);
         match command {
-            Command::Quit => Ok(spinner::Command::Quit),
-            Command::NewRpc(client_id, name, priority) => {
+            Command::Quit => {
+                // Bring down the IpcBridge too, so that all clients see an orderly disconnect. It
+                // might already be gone, so we ignore errors.
+                let _ = self.router.ipc_bridge_commands.send(ipc_bridge::Command::Quit);
+                Ok(spinner::Command::Quit)
+            },
+            Command::Tick => {
+                try!(self.on_tick());
+                Ok(spinner::Command::Continue)
+            },
+            Command::NewRpc(client_id, name, priority, execution) => {
                 // NOCOM(#sirver): deny everything starting with 'core'
                 // NOCOM(#sirver): make sure the client_id is known.
                 // NOCOM(#sirver): make sure the client has not already registered this
                 // function.
                 self.api_table.register(name, api_table::ApiInfo {
                     client_id: client_id,
-                    priority: priority
+                    priority: priority,
+                    execution: execution,
                 });
                 Ok(spinner::Command::Continue)
             },
             Command::RpcCall(client_id, rpc_call) => {
                 // NOCOM(#sirver): make sure this is not already in running_rpcs.
                 // NOCOM(#sirver): function name might not be in there.
+                let received = time::SteadyTime::now();
+                let function = self.stats_name(&rpc_call.function).to_string();
+                self.stats.call_started(&function);
 
-                // Special case 'core.'. We handle them immediately.
-                if rpc_call.function.starts_with(CORE_FUNCTIONS_PREFIX) {
-                    let result = self.plugin_core.call(client_id, &rpc_call);
-                    try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                            client_id,
-                            ipc::Message::RpcResponse(rpc::Response {
-                                context: rpc_call.context.clone(),
-                                kind: rpc::ResponseKind::Last(result),
-                            }))));
-                } else {
-                    match self.api_table.get_first(&rpc_call.function) {
-                        Some(info) => {
-                            // NOCOM(#sirver): eventually, when we keep proper track of our rpc calls, this should be
-                            // able to move again.
-                            self.running_rpcs.insert(rpc_call.context.clone(), RunningRpc {
-                                caller: client_id,
-                                callee: info.client_id,
-                                rpc_call: rpc_call.clone(),
-                            });
-                            try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                                    info.client_id,
-                                    ipc::Message::RpcCall(rpc_call)
-                                    )));
-                            // NOCOM(#sirver): we ignore timeouts.
-                        },
-                        None => {
-                            try!(self.ipc_bridge_commands.send(ipc_bridge::Command::SendData(
-                                    client_id,
-                                    ipc::Message::RpcResponse(rpc::Response {
-                                        context: rpc_call.context.clone(),
-                                        kind: rpc::ResponseKind::Last(rpc::Result::Err(rpc::Error {
-                                            kind: rpc::ErrorKind::UnknownRpc,
-                                            details: None,
-                                        })),
-                                    }))));
-                        }
+                let refusal = match self.check_permissions(client_id, &rpc_call) {
+                    Some(error) => Some(error),
+                    None => {
+                        let counts_in_flight = !rpc_call.function.starts_with(CORE_FUNCTIONS_PREFIX);
+                        self.check_rate_limits(client_id, counts_in_flight)
+                    },
+                };
+                if let Some(error) = refusal {
+                    try!(self.answer_with_error(client_id, &rpc_call, received, error));
+                } else if rpc_call.function.starts_with(CORE_FUNCTIONS_PREFIX) {
+                    // Special case 'core.'. We handle them immediately. 'core.clients',
+                    // 'core.handshake', 'core.trace', 'core.stats', 'core.call_trees',
+                    // 'core.set_thread_pool_size' and the subscriptions need our bookkeeping, all
+                    // others are handled by the CorePlugin.
+                    let result = match &rpc_call.function as &str {
+                        "core.clients" => Some(self.clients_status()),
+                        "core.handshake" => Some(self.handshake(client_id, &rpc_call)),
+                        "core.subscribe" => Some(self.subscribe(client_id, &rpc_call)),
+                        "core.unsubscribe" => Some(self.unsubscribe(client_id, &rpc_call)),
+                        "core.trace" => Some(self.trace(client_id, &rpc_call)),
+                        "core.stats" => Some(rpc::Result::success(self.stats_response())),
+                        "core.call_trees" => Some(self.call_trees_response(&rpc_call)),
+                        "core.set_thread_pool_size" => Some(self.set_thread_pool_size(client_id, &rpc_call)),
+                        _ => self.plugin_core.call(client_id, &rpc_call, received),
+                    };
+                    // Otherwise the answer comes later through CoreResult.
+                    if let Some(result) = result {
+                        try!(self.finish_core_call(client_id, &rpc_call, received, result));
                     }
+                } else {
+                    try!(self.dispatch(client_id, rpc_call, received));
                 }
                 Ok(spinner::Command::Continue)
             },
-            Command::RpcResponse(rpc_response) => {
+            Command::RpcResponse(_, rpc_response) => {
                 try!(self.on_rpc_response(rpc_response));
                 Ok(spinner::Command::Continue)
             },
-            Command::RpcCancel(rpc_cancel) => {
-                try!(self.on_rpc_cancel(rpc_cancel));
+            Command::RpcResponseBlob(_, response_blob) => {
+                try!(self.on_rpc_response_blob(response_blob));
+                Ok(spinner::Command::Continue)
+            },
+            Command::RpcCancel(client_id, rpc_cancel) => {
+                try!(self.on_rpc_cancel(client_id, rpc_cancel));
+                Ok(spinner::Command::Continue)
+            },
+            Command::RpcDetach(client_id, rpc_detach) => {
+                self.on_rpc_detach(client_id, rpc_detach);
+                Ok(spinner::Command::Continue)
+            },
+            Command::RpcCallPartial(client_id, partial) => {
+                try!(self.on_rpc_call_partial(client_id, partial));
+                Ok(spinner::Command::Continue)
+            },
+            Command::Notification(client_id, notification) => {
+                if self.admit_notification(client_id, &notification.topic) {
+                    try!(self.on_notification(notification));
+                }
+                Ok(spinner::Command::Continue)
+            },
+            Command::Notifications(_, _) => {
+                // Only the server delivers these, a client sends single Notifications. There is
+                // nobody to answer, so they are dropped.
                 Ok(spinner::Command::Continue)
             },
             Command::SendDataFailed(client_id, msg, err) => {
                 let action = match msg {
-                    ipc::Message::RpcResponse(_) | ipc::Message::RpcCancel(_) => {
+                    ipc::Message::Ping | ipc::Message::Pong => "dropped the keepalive.",
+                    ipc::Message::Notification(_) | ipc::Message::Notifications(_) => {
+                        "dropped the Notification."
+                    },
+                    ipc::Message::RpcResponse(_) | ipc::Message::RpcCancel(_) |
+                    ipc::Message::RpcDetach(_) | ipc::Message::RpcCallPartial(_) => {
                         // NOCOM(#sirver): on a streaming rpc, this should also try to cancel
                         // the RPC.
                         "dropped the RpcResponse/RpcCall."
                     },
+                    ipc::Message::RpcResponseBlob(response_blob) => {
+                        // Nobody is going to take it.
+                        let _ = response_blob.blob.discard();
+                        "dropped the RpcResponse/RpcCall."
+                    },
                     ipc::Message::RpcCall(rpc_call) => {
                         try!(self.on_rpc_response(rpc::Response {
                             context: rpc_call.context,
@@ -239,26 +1214,95 @@ impl spinner::Handler<Command> for Handler {
                 println!("Sending to {:?} failed: {:?}, {}", client_id, err, action);
                 Ok(spinner::Command::Continue)
             },
-            Command::ClientConnected(client_id) => {
+            Command::Ping(client_id) => {
+                try!(self.router.send(client_id, ipc::Message::Pong));
+                Ok(spinner::Command::Continue)
+            },
+            Command::Pong(client_id) => {
+                if let Some(info) = self.clients.get_mut(&client_id) {
+                    info.last_pong = time::SteadyTime::now();
+                }
+                Ok(spinner::Command::Continue)
+            },
+            Command::Snapshot(reply) => {
+                let state = try!(self.snapshot());
+                let _ = reply.send(state);
+                // The IpcBridge shuts down by itself once it handed over its sockets.
+                Ok(spinner::Command::Quit)
+            },
+            Command::Restore(state, clients) => {
+                self.restore(state, clients);
+                Ok(spinner::Command::Continue)
+            },
+            Command::DispatchRestoredCalls => {
+                let now = time::SteadyTime::now();
+                for (caller, running_rpc) in ::std::mem::replace(&mut self.restored_calls, Vec::new()) {
+                    if running_rpc.partials_lost {
+                        try!(self.answer_with_error(caller, &running_rpc.rpc_call, now, partials_lost_error()));
+                        continue;
+                    }
+                    try!(self.dispatch(caller, running_rpc.rpc_call, now));
+                    for partial in running_rpc.partials.unwrap_or(Vec::new()) {
+                        try!(self.on_rpc_call_partial(caller, partial));
+                    }
+                }
+                Ok(spinner::Command::Continue)
+            },
+            Command::Stats(reply) => {
+                let _ = reply.send(self.stats_response());
+                Ok(spinner::Command::Continue)
+            },
+            Command::CoreResult(client_id, rpc_call, received, result) => {
+                try!(self.finish_core_call(client_id, &rpc_call, received, result));
+                Ok(spinner::Command::Continue)
+            },
+            Command::ClientConnected(client_id, listener, pid) => {
                 // NOCOM(#sirver): make sure client_id is not yet known.
-                self.clients.insert(client_id);
+                self.clients.insert(client_id, ClientInfo::new(listener, pid));
+                Ok(spinner::Command::Continue)
+            },
+            Command::InProcessClientConnected(client_id, sender) => {
+                self.clients.insert(client_id, ClientInfo::new(permissions::IN_PROCESS_LISTENER.into(), None));
+                self.router.in_process_clients.insert(client_id, sender);
                 Ok(spinner::Command::Continue)
             },
             Command::ClientDisconnected(client_id) => {
-                self.clients.remove(&client_id);
+                let pid = self.clients.remove(&client_id).and_then(|info| info.pid);
+                if let Some(pid) = pid {
+                    if !self.clients.values().any(|info| info.pid == Some(pid)) {
+                        // The plugin manager might already be gone.
+                        let _ = self.plugin_manager.send(plugin_manager::Command::Disconnected(pid));
+                    }
+                }
+                self.router.in_process_clients.remove(&client_id);
+                self.router.tracer.forget(&client_id);
 
-                // Kill all pending RPCs that have been requested by this client.
+                // Nobody waits for the calls this client made anymore, so their handlers are told
+                // to stop. The calls it was handling will never be answered by it, so their callers
+                // get an error instead.
                 let rpcs_to_remove: Vec<_> = self.running_rpcs.iter()
-                    .filter_map(|(context, running_rpc)| {
-                        if running_rpc.caller == client_id {
-                            Some(context.to_string())
-                        } else {
-                            None
-                        }
-                    })
+                    .filter(|&(_, running_rpc)| running_rpc.caller == client_id || running_rpc.callee == client_id)
+                    .map(|(context, _)| context.clone())
                     .collect();
                 for context in rpcs_to_remove {
-                    self.running_rpcs.remove(&context);
+                    let running_rpc = self.remove_running_rpc(&context).unwrap();
+                    if running_rpc.caller == client_id {
+                        self.stats.call_finished(&running_rpc.rpc_call.function, running_rpc.started, false);
+                        self.call_trees.finished(&running_rpc.rpc_call, running_rpc.caller.serial,
+                                                 running_rpc.started, call_tree::Outcome::Cancelled);
+                        if running_rpc.callee != client_id {
+                            try!(self.router.send(running_rpc.callee, ipc::Message::RpcCancel(rpc::Cancel {
+                                context: context,
+                            })));
+                        }
+                    } else {
+                        try!(self.answer_with_error(running_rpc.caller, &running_rpc.rpc_call, running_rpc.started,
+                                                    rpc::Error {
+                                                        kind: rpc::ErrorKind::Internal,
+                                                        message: Some("The client handling the call went away.".into()),
+                                                        details: None,
+                                                    }));
+                    }
                 }
 
                 self.api_table.deregister_by_client(&client_id);
@@ -268,8 +1312,12 @@ impl spinner::Handler<Command> for Handler {
     }
 }
 
-pub fn spawn(ipc_bridge_commands: mio::Sender<ipc_bridge::Command>, tx: SenderTo, rx: mpsc::Receiver<Command>) -> thread::JoinHandle<()> {
+pub fn spawn(ipc_bridge_commands: mio::Sender<ipc_bridge::Command>,
+             tx: SenderTo,
+             rx: mpsc::Receiver<Command>,
+             plugin_manager: plugin_manager::SenderTo,
+             config: &config::Config) -> thread::JoinHandle<()> {
     let recver = Receiver::new(rx);
-    let handler = Handler::new(ipc_bridge_commands, tx.clone());
+    let handler = Handler::new(ipc_bridge_commands, tx.clone(), plugin_manager, config);
     spinner::spawn(recver, handler)
 }
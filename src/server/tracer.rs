@@ -0,0 +1,351 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Records the messages passing through the server to a file, one JSON object per line:
+//!
+//! ```json
+//! {"time":"2016-03-01T10:12:01.123456789Z","direction":"in","client":3,"client_name":"lua_keymap","in_process":false,"function":"buffer.open","message":{"RpcCall":{...}}}
+//! ```
+//!
+//! 'direction' is "in" for messages the server received from 'client' and "out" for messages it
+//! sent to it. Tracing is turned on and off at runtime through 'core.trace', which can also
+//! restrict it to function prefixes and clients. Responses and cancels are attributed to the
+//! function of their call. Once the file grows beyond 'max_bytes', it is renamed to 'file.1'
+//! (shifting older ones up to 'file.<max_files>') and a new one is started.
+//!
+//! The file is written on a thread of its own, so that a slow disk does not hold up the server.
+//! Turning tracing off waits until everything recorded so far is written.
+
+use ::error::Result;
+use ::ipc;
+use ::rpc;
+use ::server::ipc_bridge;
+use serde_json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use time;
+
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn name(&self) -> &'static str {
+        match *self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub time: String,
+    pub direction: String,
+    pub client: u64,
+    pub client_name: Option<String>,
+    /// True for the built-in plugins.
+    pub in_process: bool,
+    pub function: Option<String>,
+    pub message: ipc::Message,
+}
+
+/// Arguments of 'core.trace'.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct TraceRequest {
+    pub enabled: bool,
+    /// Defaults to the file from the config.
+    pub file: Option<String>,
+    /// Only trace messages belonging to functions starting with one of these.
+    pub functions: Option<Vec<String>>,
+    /// Only trace messages of these clients, given by handshake name or serial.
+    pub clients: Option<Vec<String>>,
+}
+
+/// Returned by 'core.trace'.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct TraceResponse {
+    pub enabled: bool,
+    pub file: Option<String>,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: fs::File,
+    written: u64,
+}
+
+fn numbered(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self> {
+        let file = try!(fs::OpenOptions::new().create(true).append(true).open(path));
+        let written = try!(file.metadata()).len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_bytes: max_bytes,
+            max_files: max_files,
+            file: file,
+            written: written,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files > 0 {
+            let _ = fs::remove_file(numbered(&self.path, self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(numbered(&self.path, index), numbered(&self.path, index + 1));
+            }
+            try!(fs::rename(&self.path, numbered(&self.path, 1)));
+        }
+        self.file = try!(fs::OpenOptions::new().create(true).write(true).truncate(true).open(&self.path));
+        self.written = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.max_bytes {
+            try!(self.rotate());
+        }
+        try!(self.file.write_all(line));
+        try!(self.file.write_all(b"\n"));
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+// Writes the lines it gets to 'file' until the Tracer hangs up or writing fails.
+struct Writer {
+    lines: mpsc::Sender<Vec<u8>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Writer {
+    fn spawn(mut file: RotatingFile) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<u8>>();
+        let thread = thread::spawn(move || {
+            for line in rx.iter() {
+                if let Err(err) = file.write_line(&line) {
+                    // The Tracer notices that we are gone on its next record.
+                    println!("Writing the trace failed: {}. Tracing is turned off.", err);
+                    return;
+                }
+            }
+        });
+        Writer {
+            lines: tx,
+            thread: thread,
+        }
+    }
+
+    // Waits until everything sent so far is written.
+    fn stop(self) {
+        drop(self.lines);
+        let _ = self.thread.join();
+    }
+}
+
+// A call seen by the tracer and the clients it passed through.
+struct TracedCall {
+    function: String,
+    clients: Vec<ipc_bridge::ClientId>,
+}
+
+struct Filter {
+    functions: Option<Vec<String>>,
+    clients: Option<Vec<String>>,
+}
+
+impl Filter {
+    fn matches(&self, client: &ipc_bridge::ClientId, name: Option<&String>, function: Option<&String>) -> bool {
+        if let Some(ref functions) = self.functions {
+            match function {
+                Some(function) => if !functions.iter().any(|prefix| function.starts_with(prefix as &str)) {
+                    return false;
+                },
+                None => return false,
+            }
+        }
+        if let Some(ref clients) = self.clients {
+            let serial = client.serial.to_string();
+            if !clients.iter().any(|wanted| *wanted == serial || Some(wanted) == name) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+pub struct Tracer {
+    default_file: Option<PathBuf>,
+    max_bytes: u64,
+    max_files: usize,
+    writer: Option<Writer>,
+    filter: Filter,
+    // Handshake names, since the filter can refer to them.
+    names: HashMap<ipc_bridge::ClientId, String>,
+    // Every running call by context, to attribute responses and cancels to its function.
+    calls: HashMap<String, TracedCall>,
+}
+
+impl Tracer {
+    pub fn new(default_file: Option<PathBuf>, max_bytes: u64, max_files: usize) -> Self {
+        Tracer {
+            default_file: default_file,
+            max_bytes: max_bytes,
+            max_files: max_files,
+            writer: None,
+            filter: Filter {
+                functions: None,
+                clients: None,
+            },
+            names: HashMap::new(),
+            calls: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    fn stop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.stop();
+        }
+        self.calls.clear();
+    }
+
+    /// Handles 'core.trace'.
+    pub fn configure(&mut self, request: TraceRequest) -> rpc::Result {
+        if !request.enabled {
+            self.stop();
+            return rpc::Result::success(TraceResponse {
+                enabled: false,
+                file: None,
+            });
+        }
+
+        let path = match request.file.map(PathBuf::from).or(self.default_file.clone()) {
+            Some(path) => path,
+            None => return rpc::Result::Err(rpc::Error {
+                kind: rpc::ErrorKind::InvalidArgs,
+                message: Some("No trace file given and none configured.".into()),
+                details: Some(serde_json::to_value(&"no trace file given and none configured")),
+            }),
+        };
+        match RotatingFile::open(&path, self.max_bytes, self.max_files) {
+            Ok(file) => {
+                self.stop();
+                self.writer = Some(Writer::spawn(file));
+            },
+            Err(err) => return rpc::Result::Err(rpc::Error {
+                kind: rpc::ErrorKind::Io,
+                message: Some(format!("Opening {} failed: {}", path.display(), err)),
+                details: Some(serde_json::to_value(&format!("{}", err))),
+            }),
+        }
+        self.filter = Filter {
+            functions: request.functions,
+            clients: request.clients,
+        };
+        rpc::Result::success(TraceResponse {
+            enabled: true,
+            file: Some(path.to_string_lossy().into_owned()),
+        })
+    }
+
+    pub fn set_name(&mut self, client: ipc_bridge::ClientId, name: String) {
+        self.names.insert(client, name);
+    }
+
+    /// Called once 'client' disconnected. The calls it took part in will not be answered.
+    pub fn forget(&mut self, client: &ipc_bridge::ClientId) {
+        self.names.remove(client);
+        self.calls.retain(|_, call| !call.clients.contains(client));
+    }
+
+    // The function 'message' belongs to, if it is known.
+    fn function_of(&mut self, client: ipc_bridge::ClientId, message: &ipc::Message) -> Option<String> {
+        let context = match *message {
+            ipc::Message::RpcCall(ref rpc_call) => {
+                let call = self.calls.entry(rpc_call.context.clone()).or_insert(TracedCall {
+                    function: rpc_call.function.clone(),
+                    clients: Vec::new(),
+                });
+                call.clients.push(client);
+                return Some(rpc_call.function.clone());
+            },
+            ipc::Message::RpcResponse(ref response) => &response.context,
+            ipc::Message::RpcResponseBlob(ref response_blob) => &response_blob.context,
+            ipc::Message::RpcCancel(ref cancel) => &cancel.context,
+            ipc::Message::RpcDetach(ref detach) => &detach.context,
+            ipc::Message::RpcCallPartial(ref partial) => &partial.context,
+            ipc::Message::Notification(ref notification) => Some(notification.topic.clone()),
+            ipc::Message::Notifications(ref notifications) => Some(notifications.prefix.clone()),
+            ipc::Message::Ping | ipc::Message::Pong => return None,
+        };
+        self.calls.get(context).map(|call| call.function.clone())
+    }
+
+    pub fn record(&mut self, direction: Direction, client: ipc_bridge::ClientId, message: &ipc::Message) {
+        if !self.is_enabled() {
+            return;
+        }
+        let function = self.function_of(client, message);
+        if direction == Direction::Out {
+            // Every handler of a call answers with a Last, but only the one passed on to the
+            // caller ends the call. A cancelled call might never be answered.
+            match *message {
+                ipc::Message::RpcResponse(rpc::Response { ref context, kind: rpc::ResponseKind::Last(_) }) |
+                ipc::Message::RpcResponseBlob(rpc::ResponseBlob { ref context, .. }) |
+                ipc::Message::RpcCancel(rpc::Cancel { ref context }) => {
+                    self.calls.remove(context);
+                },
+                _ => (),
+            }
+        }
+
+        let name = self.names.get(&client).cloned();
+        if !self.filter.matches(&client, name.as_ref(), function.as_ref()) {
+            return;
+        }
+
+        let record = Record {
+            time: format!("{}", time::strftime("%Y-%m-%dT%H:%M:%S.%fZ", &time::now_utc()).unwrap()),
+            direction: direction.name().into(),
+            client: client.serial,
+            client_name: name,
+            in_process: client.token == ipc_bridge::IN_PROCESS,
+            function: function,
+            message: message.clone(),
+        };
+        let line = serde_json::to_vec(&record).expect("Record is serializable");
+        let result = self.writer.as_ref().unwrap().lines.send(line);
+        if result.is_err() {
+            // The writer thread gave up and said why.
+            self.stop();
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -3,31 +3,56 @@
 // in the project root for license information.
 
 use ::server::Server;
+use ::server::config;
+use std::env;
 use std::path::PathBuf;
 use tempdir::TempDir;
+use uuid::Uuid;
+
+pub mod replay;
+
+/// A path in the temporary directory that is not used by anything yet.
+pub fn temporary_path(suffix: &str) -> PathBuf {
+    let mut path = env::temp_dir();
+    path.push(format!("{}{}", Uuid::new_v4().to_string(), suffix));
+    path
+}
 
 pub struct TestHarness {
     server: Option<Server>,
     pub socket_name: PathBuf,
     pub temp_directory: TempDir,
+    /// What the server was launched with.
+    pub config: config::Config,
 }
 
 impl TestHarness {
     pub fn new() -> Self {
+        TestHarness::with_config(config::Config::default())
+    }
+
+    /// Like 'new', but launches the server with 'config'. It always listens on 'socket_name' only.
+    pub fn with_config(mut config: config::Config) -> Self {
         let temp_directory = TempDir::new("swiboe").unwrap();
 
         let mut socket_name = temp_directory.path().to_path_buf();
         socket_name.push("_socket");
 
-        let server = Server::launch(&socket_name, &[]).unwrap();
+        config.unix_sockets = Some(vec![socket_name.to_string_lossy().into_owned()]);
+        let server = Server::launch_with_config(&config).unwrap();
 
         TestHarness {
             server: Some(server),
             socket_name: socket_name,
             temp_directory: temp_directory,
+            config: config,
         }
     }
 
+    pub fn server(&mut self) -> &mut Server {
+        self.server.as_mut().unwrap()
+    }
+
     pub fn wait_for_shutdown(&mut self) {
         self.server.as_mut().unwrap().wait_for_shutdown();
     }
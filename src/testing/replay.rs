@@ -0,0 +1,301 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+//! Records the messages of a session and plays them back against a fresh server.
+//!
+//! A session is an unfiltered trace (see 'server::tracer'). Replaying it connects one socket per
+//! recorded client, sends everything the client sent in the recorded order and checks that the
+//! server answers each client with the recorded messages. The built-in plugins are not
+//! impersonated, the fresh server brings its own. Pings and pongs are not replayed.
+//!
+//! Calls made by the built-in plugins get new contexts in the replay. The first time one reaches
+//! an impersonated client, its recorded context is mapped to the new one, and all later messages
+//! are compared and sent with the new context. Clients that did their 'core.handshake' before
+//! the recording started do it again right after connecting, under their recorded name.
+
+use ::client::RpcCaller;
+use ::error::{Error, Result};
+use ::ipc;
+use ::rpc;
+use ::server::plugin_core::HandshakeRequest;
+use ::server::tracer;
+use ::testing::TestHarness;
+use serde_json;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+use unix_socket::UnixStream;
+use uuid::Uuid;
+
+/// How long to wait for a recorded message before reporting it as missing.
+const RECEIVE_TIMEOUT_MS: u64 = 2000;
+
+pub struct Session {
+    pub records: Vec<tracer::Record>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = try!(fs::File::open(path));
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            if line.is_empty() {
+                continue;
+            }
+            records.push(try!(serde_json::from_str(&line)));
+        }
+        Ok(Session {
+            records: records,
+        })
+    }
+}
+
+/// Starts recording everything that passes through the server 'client' is connected to.
+pub fn start_recording<C: RpcCaller>(client: &mut C, path: &Path) -> Result<()> {
+    configure_tracing(client, tracer::TraceRequest {
+        enabled: true,
+        file: Some(path.to_string_lossy().into_owned()),
+        functions: None,
+        clients: None,
+    })
+}
+
+pub fn stop_recording<C: RpcCaller>(client: &mut C) -> Result<()> {
+    configure_tracing(client, tracer::TraceRequest::default())
+}
+
+fn configure_tracing<C: RpcCaller>(client: &mut C, request: tracer::TraceRequest) -> Result<()> {
+    let mut rpc = try!(client.call("core.trace", &request));
+    match try!(rpc.wait()) {
+        ::rpc::Result::Ok(_) => Ok(()),
+        other => Err(Error::Io(::std::io::Error::new(
+                    ::std::io::ErrorKind::Other, format!("core.trace failed: {:?}", other)))),
+    }
+}
+
+/// A message the server sent differently, or not at all.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Index of the record in the session.
+    pub index: usize,
+    /// Serial of the client in the recorded session.
+    pub client: u64,
+    pub expected: ipc::Message,
+    /// None if nothing arrived in time.
+    pub actual: Option<ipc::Message>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "Record {} (client {}):", self.index, self.client));
+        try!(writeln!(f, "  expected: {}", serde_json::to_string(&self.expected).unwrap()));
+        match self.actual {
+            Some(ref actual) => writeln!(f, "  actual:   {}", serde_json::to_string(actual).unwrap()),
+            None => writeln!(f, "  actual:   nothing"),
+        }
+    }
+}
+
+struct Impersonation {
+    reader: ipc::Reader<UnixStream>,
+    writer: ipc::Writer<UnixStream>,
+    // Set once a message did not arrive. The stream might be in the middle of a message then, so
+    // nothing more is read from it.
+    lost: bool,
+}
+
+impl Impersonation {
+    fn connect(socket_name: &Path) -> Result<Self> {
+        let stream = try!(UnixStream::connect(socket_name));
+        try!(stream.set_read_timeout(Some(Duration::from_millis(RECEIVE_TIMEOUT_MS))));
+        Ok(Impersonation {
+            reader: ipc::Reader::new(try!(stream.try_clone())),
+            writer: ipc::Writer::new(stream),
+            lost: false,
+        })
+    }
+
+    // Does the 'core.handshake' the client did before the recording started.
+    fn handshake(&mut self, name: &str) -> Result<()> {
+        let context = Uuid::new_v4().to_hyphenated_string();
+        try!(self.writer.write_message(&ipc::Message::RpcCall(rpc::Call {
+            function: "core.handshake".into(),
+            context: context.clone(),
+            args: serde_json::to_value(&HandshakeRequest {
+                name: name.into(),
+            }),
+            parent: None,
+            trace_id: None,
+        })));
+        match self.receive() {
+            Some(ipc::Message::RpcResponse(ref response)) if response.context == context => Ok(()),
+            _ => Err(Error::Io(::std::io::Error::new(
+                        ::std::io::ErrorKind::InvalidData,
+                        format!("Handshake as '{}' was not answered.", name)))),
+        }
+    }
+
+    // The next message that is not a keepalive. Pings are answered on the way.
+    fn receive(&mut self) -> Option<ipc::Message> {
+        if self.lost {
+            return None;
+        }
+        loop {
+            match self.reader.read_message() {
+                Ok(ipc::Message::Ping) => {
+                    let _ = self.writer.write_message(&ipc::Message::Pong);
+                },
+                Ok(ipc::Message::Pong) => (),
+                Ok(message) => return Some(message),
+                Err(_) => {
+                    self.lost = true;
+                    return None;
+                },
+            }
+        }
+    }
+}
+
+fn is_replayed(record: &tracer::Record) -> bool {
+    if record.in_process {
+        return false;
+    }
+    // The recording itself is switched on and off through 'core.trace'.
+    if record.function.as_ref().map(|function| function == "core.trace").unwrap_or(false) {
+        return false;
+    }
+    match record.message {
+        ipc::Message::Ping | ipc::Message::Pong => false,
+        _ => true,
+    }
+}
+
+// Contexts of the recording mapped to the ones of the replay.
+struct Contexts {
+    replayed: HashMap<String, String>,
+}
+
+impl Contexts {
+    fn map(&self, context: &str) -> String {
+        self.replayed.get(context).cloned().unwrap_or(context.to_string())
+    }
+
+    // 'message' as it is sent in the replay.
+    fn translate(&self, message: &ipc::Message) -> ipc::Message {
+        match *message {
+            ipc::Message::RpcCall(ref rpc_call) => ipc::Message::RpcCall(rpc::Call {
+                function: rpc_call.function.clone(),
+                context: self.map(&rpc_call.context),
+                args: rpc_call.args.clone(),
+                parent: rpc_call.parent.as_ref().map(|parent| self.map(parent)),
+                trace_id: rpc_call.trace_id.as_ref().map(|trace_id| self.map(trace_id)),
+            }),
+            ipc::Message::RpcResponse(ref response) => ipc::Message::RpcResponse(rpc::Response {
+                context: self.map(&response.context),
+                kind: response.kind.clone(),
+            }),
+            // Refers to the same Blob, which is long gone by now.
+            ipc::Message::RpcResponseBlob(ref response_blob) => ipc::Message::RpcResponseBlob(rpc::ResponseBlob {
+                context: self.map(&response_blob.context),
+                blob: response_blob.blob.clone(),
+            }),
+            ipc::Message::RpcCancel(ref cancel) => ipc::Message::RpcCancel(rpc::Cancel {
+                context: self.map(&cancel.context),
+            }),
+            ipc::Message::RpcDetach(ref detach) => ipc::Message::RpcDetach(rpc::Detach {
+                context: self.map(&detach.context),
+            }),
+            ipc::Message::RpcCallPartial(ref partial) => ipc::Message::RpcCallPartial(rpc::CallPartial {
+                context: self.map(&partial.context),
+                value: partial.value.clone(),
+            }),
+            ref other => other.clone(),
+        }
+    }
+
+    // A call that reaches a client with a context we have not seen is a new call of a built-in
+    // plugin.
+    fn learn(&mut self, expected: &ipc::Message, actual: &ipc::Message) {
+        if let (&ipc::Message::RpcCall(ref expected), &ipc::Message::RpcCall(ref actual)) = (expected, actual) {
+            if expected.function != actual.function {
+                return;
+            }
+            let mut pairs = vec![(&expected.context, &actual.context)];
+            if let (Some(expected), Some(actual)) = (expected.trace_id.as_ref(), actual.trace_id.as_ref()) {
+                pairs.push((expected, actual));
+            }
+            if let (Some(expected), Some(actual)) = (expected.parent.as_ref(), actual.parent.as_ref()) {
+                pairs.push((expected, actual));
+            }
+            for (expected, actual) in pairs {
+                if !self.replayed.contains_key(expected as &str) {
+                    self.replayed.insert(expected.to_string(), actual.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Plays 'session' against the server of 'harness', which should be freshly started. Returns all
+/// divergences, i.e. an empty Vec if the server behaved exactly as recorded.
+pub fn replay(harness: &TestHarness, session: &Session) -> Result<Vec<Divergence>> {
+    let mut clients: HashMap<u64, Impersonation> = HashMap::new();
+    let mut contexts = Contexts {
+        replayed: HashMap::new(),
+    };
+    let mut divergences = Vec::new();
+
+    for (index, record) in session.records.iter().enumerate() {
+        if !is_replayed(record) {
+            continue;
+        }
+        if !clients.contains_key(&record.client) {
+            let mut client = try!(Impersonation::connect(&harness.socket_name));
+            // Named from its first record on, so the handshake happened before the recording.
+            if let Some(ref name) = record.client_name {
+                try!(client.handshake(name));
+            }
+            clients.insert(record.client, client);
+        }
+        let client = clients.get_mut(&record.client).unwrap();
+
+        match &record.direction as &str {
+            "in" => try!(client.writer.write_message(&contexts.translate(&record.message))),
+            "out" => {
+                let actual = client.receive();
+                if let Some(ref actual) = actual {
+                    contexts.learn(&record.message, actual);
+                }
+                let expected = contexts.translate(&record.message);
+                let matches = actual.as_ref()
+                    .map(|actual| serde_json::to_value(actual) == serde_json::to_value(&expected))
+                    .unwrap_or(false);
+                if !matches {
+                    divergences.push(Divergence {
+                        index: index,
+                        client: record.client,
+                        expected: record.message.clone(),
+                        actual: actual,
+                    });
+                }
+            },
+            other => return Err(Error::Io(::std::io::Error::new(
+                        ::std::io::ErrorKind::InvalidData,
+                        format!("Unknown direction '{}' in record {}.", other, index)))),
+        }
+    }
+    Ok(divergences)
+}
+
+/// Replays the session in 'path' against a new TestHarness.
+pub fn replay_file(path: &Path) -> Result<Vec<Divergence>> {
+    let session = try!(Session::load(path));
+    let harness = TestHarness::new();
+    replay(&harness, &session)
+}
+
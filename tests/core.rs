@@ -3,16 +3,30 @@
 // in the project root for license information.
 
 use ::CallbackRpc;
+use futures::{Future, Stream};
 use serde_json;
+use std::cmp;
 use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
 use std::path;
 use std::sync;
 use std::thread;
+use swiboe::Error;
 use swiboe::client::RpcCaller;
 use swiboe::client;
 use swiboe::rpc;
 use swiboe::server::Server;
+use swiboe::server::call_tree;
+use swiboe::server::config;
+use swiboe::server::permissions;
+use swiboe::server::plugin_core;
+use swiboe::server::quota;
+use swiboe::server::stats;
+use swiboe::server::tracer;
 use swiboe::testing::TestHarness;
+use unix_socket::{UnixListener, UnixStream};
 use uuid::Uuid;
 
 fn temporary_socket_name() -> path::PathBuf {
@@ -164,6 +178,7 @@ fn client_disconnects_should_not_stop_handling_of_rpcs() {
     let mut rpc = client.call("test.test", &as_json(r#"{}"#)).unwrap();
     assert_eq!(rpc::Result::Err(rpc::Error {
         kind: rpc::ErrorKind::UnknownRpc,
+        message: Some("Nobody registered test.test.".into()),
         details: None,
     }), rpc.wait().unwrap());
 }
@@ -176,6 +191,7 @@ fn call_not_existing_rpc() {
     let mut rpc = client.call("not_existing", &as_json("{}")).unwrap();
     assert_eq!(rpc::Result::Err(rpc::Error {
         kind: rpc::ErrorKind::UnknownRpc,
+        message: Some("Nobody registered not_existing.".into()),
         details: None,
     }), rpc.wait().unwrap());
 }
@@ -254,3 +270,1512 @@ fn call_streaming_rpc_cancelled() {
         }
     }
 }
+
+#[test]
+fn in_process_client_talks_to_socket_client() {
+    let socket_name = temporary_socket_name();
+    let mut server = Server::launch(&socket_name, &[]).unwrap();
+
+    let test_msg: serde_json::Value = as_json(r#"{ "blub": "blah" }"#);
+    {
+        let mut in_process_client = server.connect_in_process().unwrap();
+        in_process_client.new_rpc("test.test", Box::new(TestCall {
+            priority: 0,
+            result: rpc::Result::Ok(test_msg.clone()),
+        })).unwrap();
+
+        let mut socket_client = client::Client::connect_unix(&socket_name).unwrap();
+        let mut rpc = socket_client.call("test.test", &test_msg).unwrap();
+        assert_eq!(rpc.wait().unwrap(), rpc::Result::Ok(test_msg.clone()));
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn rpc_times_out_after_configured_timeout() {
+    let t = TestHarness::with_config(config::Config {
+        rpc_timeout_ms: Some(100),
+        .. config::Config::default()
+    });
+
+    let mut slow_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    slow_client.new_rpc("test.slow", Box::new(CallbackRpc {
+        priority: 50,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            thread::spawn(move || {
+                while !context.cancelled() {
+                    thread::sleep_ms(10);
+                }
+            });
+        },
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.slow", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::Err(rpc::Error {
+        kind: rpc::ErrorKind::Timeout,
+        message: Some("The call ran longer than the server allows.".into()),
+        details: None,
+    }), rpc.wait().unwrap());
+}
+
+#[test]
+fn stale_socket_is_removed_on_launch() {
+    let socket_name = temporary_socket_name();
+    // Binding and dropping a listener leaves the socket file behind, like a crashed server does.
+    drop(UnixListener::bind(&socket_name).unwrap());
+    assert!(socket_name.exists());
+
+    let mut server = Server::launch(&socket_name, &[]).unwrap();
+    let _client = client::Client::connect_unix(&socket_name).unwrap();
+    server.shutdown();
+    assert!(!socket_name.exists());
+}
+
+#[test]
+fn failed_launch_removes_its_sockets() {
+    let socket_name = temporary_socket_name();
+    let mut unbindable = temporary_socket_name();
+    unbindable.push("no_such_directory.socket");
+    let config = config::Config {
+        unix_sockets: Some(vec![socket_name.to_string_lossy().into_owned(),
+                                unbindable.to_string_lossy().into_owned()]),
+        .. config::Config::default()
+    };
+    assert!(Server::launch_with_config(&config).is_err());
+    assert!(!socket_name.exists());
+}
+
+#[test]
+fn launch_fails_if_another_server_is_listening() {
+    let t = TestHarness::new();
+    assert!(Server::launch(&t.socket_name, &[]).is_err());
+}
+
+fn launch_with_permissions(socket_name: &path::Path,
+                           rules: Vec<permissions::PermissionRule>) -> Server {
+    Server::launch_with_config(&config::Config {
+        unix_sockets: Some(vec![socket_name.to_string_lossy().into_owned()]),
+        permissions: Some(rules),
+        .. config::Config::default()
+    }).unwrap()
+}
+
+#[test]
+fn listener_rule_denies_calls() {
+    let socket_name = temporary_socket_name();
+    let mut server = launch_with_permissions(&socket_name, vec![permissions::PermissionRule {
+        listener: Some(socket_name.to_string_lossy().into_owned()),
+        call: Some(permissions::NamespaceFilter {
+            allow: None,
+            deny: Some(vec!["core.exit".into()]),
+        }),
+        .. permissions::PermissionRule::default()
+    }]);
+
+    {
+        let mut client = client::Client::connect_unix(&socket_name).unwrap();
+        let mut rpc = client.call("core.exit", &as_json("{}")).unwrap();
+        assert_eq!(rpc::Result::Err(rpc::Error {
+            kind: rpc::ErrorKind::PermissionDenied,
+            message: Some("This client may not call core.exit.".into()),
+            details: Some(as_json(r#""call core.exit""#)),
+        }), rpc.wait().unwrap());
+
+        // Other functions are not affected.
+        let mut rpc = client.call("buffer.list", &as_json("null")).unwrap();
+        assert!(rpc.wait().unwrap().is_ok());
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn client_rule_applies_after_handshake() {
+    let socket_name = temporary_socket_name();
+    let mut server = launch_with_permissions(&socket_name, vec![permissions::PermissionRule {
+        client: Some("untrusted".into()),
+        register: Some(permissions::NamespaceFilter {
+            allow: Some(vec!["untrusted".into()]),
+            deny: None,
+        }),
+        .. permissions::PermissionRule::default()
+    }]);
+
+    {
+        let mut client = client::Client::connect_unix(&socket_name).unwrap();
+        client.handshake("untrusted").unwrap();
+
+        let new_callback = || Box::new(CallbackRpc {
+            priority: 0,
+            callback: move |mut context: client::rpc::server::Context, _| {
+                context.finish(rpc::Result::success(())).unwrap();
+            },
+        });
+        match client.new_rpc("buffer.open", new_callback()) {
+            Err(Error::Rpc(ref err)) if err.kind == rpc::ErrorKind::PermissionDenied => (),
+            other => panic!("Expected PermissionDenied, got {:?}", other),
+        }
+        client.new_rpc("untrusted.hello", new_callback()).unwrap();
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn second_handshake_is_refused() {
+    let t = TestHarness::new();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("lua_keymap").unwrap();
+    match client.handshake("trusted") {
+        Err(Error::Rpc(ref err)) if err.kind == rpc::ErrorKind::PermissionDenied => (),
+        other => panic!("Expected PermissionDenied, got {:?}", other),
+    }
+}
+
+#[test]
+fn listener_rule_restricts_handshake_names() {
+    let socket_name = temporary_socket_name();
+    let mut server = launch_with_permissions(&socket_name, vec![permissions::PermissionRule {
+        listener: Some(socket_name.to_string_lossy().into_owned()),
+        handshake: Some(permissions::NamespaceFilter {
+            allow: Some(vec!["untrusted".into()]),
+            deny: None,
+        }),
+        .. permissions::PermissionRule::default()
+    }]);
+
+    {
+        let mut client = client::Client::connect_unix(&socket_name).unwrap();
+        match client.handshake("trusted") {
+            Err(Error::Rpc(ref err)) if err.kind == rpc::ErrorKind::PermissionDenied => (),
+            other => panic!("Expected PermissionDenied, got {:?}", other),
+        }
+        client.handshake("untrusted").unwrap();
+    }
+
+    server.shutdown();
+}
+
+#[test]
+fn new_rpc_with_invalid_arguments_is_refused() {
+    let t = TestHarness::new();
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("core.new_rpc", &as_json(r#"{ "priority": "high" }"#)).unwrap();
+    match rpc.wait().unwrap() {
+        rpc::Result::Err(ref err) if err.kind == rpc::ErrorKind::InvalidArgs => (),
+        other => panic!("Expected InvalidArgs, got {:?}", other),
+    }
+}
+
+#[test]
+fn calls_over_the_in_flight_limit_are_refused() {
+    let t = TestHarness::with_config(config::Config {
+        rate_limits: Some(vec![quota::RateLimitRule {
+            client: Some("greedy".into()),
+            max_in_flight: Some(1),
+            .. quota::RateLimitRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut slow_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    slow_client.new_rpc("test.slow", Box::new(CallbackRpc {
+        priority: 50,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            thread::spawn(move || {
+                while !context.cancelled() {
+                    thread::sleep_ms(10);
+                }
+            });
+        },
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("greedy").unwrap();
+    let first = client.call("test.slow", &as_json("{}")).unwrap();
+    let mut second = client.call("test.slow", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::Err(rpc::Error {
+        kind: rpc::ErrorKind::RateLimited,
+        message: Some("Too many calls of this client are running.".into()),
+        details: Some(as_json(r#""max_in_flight""#)),
+    }), second.wait().unwrap());
+
+    let mut rpc = client.call("core.clients", &as_json("{}")).unwrap();
+    let response: plugin_core::ClientsResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    let greedy = response.clients.iter()
+        .find(|status| status.name == Some("greedy".into()))
+        .unwrap();
+    assert_eq!(1, greedy.in_flight);
+    assert_eq!(1, greedy.rejected);
+
+    first.cancel().unwrap();
+}
+
+#[test]
+fn cancelled_calls_do_not_count_against_the_in_flight_limit() {
+    let t = TestHarness::with_config(config::Config {
+        rate_limits: Some(vec![quota::RateLimitRule {
+            client: Some("fickle".into()),
+            max_in_flight: Some(2),
+            .. quota::RateLimitRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+    inner_client.new_rpc("test.quick", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("fickle").unwrap();
+    for _ in 0..2 {
+        *cancelled.lock().unwrap() = false;
+        let rpc = client.call("test.inner", &as_json("{}")).unwrap();
+        rpc.cancel().unwrap();
+        wait_for_flag(&cancelled);
+    }
+
+    let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+
+    let mut rpc = client.call("core.clients", &as_json("{}")).unwrap();
+    let response: plugin_core::ClientsResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    let fickle = response.clients.iter()
+        .find(|status| status.name == Some("fickle".into()))
+        .unwrap();
+    assert_eq!(0, fickle.in_flight);
+    assert_eq!(0, fickle.rejected);
+}
+
+#[test]
+fn calls_over_calls_per_second_are_refused() {
+    let t = TestHarness::with_config(config::Config {
+        rate_limits: Some(vec![quota::RateLimitRule {
+            client: Some("hasty".into()),
+            calls_per_second: Some(2),
+            .. quota::RateLimitRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut callee = client::Client::connect_unix(&t.socket_name).unwrap();
+    callee.new_rpc("test.quick", Box::new(CallbackRpc {
+        priority: 50,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            context.finish(rpc::Result::success(())).unwrap();
+        },
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("hasty").unwrap();
+    // The burst allows as many calls as the limit per second.
+    for _ in 0..2 {
+        let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+        assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    }
+    let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::Err(rpc::Error {
+        kind: rpc::ErrorKind::RateLimited,
+        message: Some("This client makes too many calls per second.".into()),
+        details: Some(as_json(r#""calls_per_second""#)),
+    }), rpc.wait().unwrap());
+
+    // Refilled by two calls per second.
+    thread::sleep_ms(600);
+    let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+}
+
+#[test]
+fn notifications_about_topics_the_client_may_not_call_are_dropped() {
+    let t = TestHarness::with_config(config::Config {
+        permissions: Some(vec![permissions::PermissionRule {
+            client: Some("muted".into()),
+            call: Some(permissions::NamespaceFilter {
+                allow: None,
+                deny: Some(vec!["test.secret".into()]),
+            }),
+            .. permissions::PermissionRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe("test.").unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("muted").unwrap();
+    client.notify("test.secret", &as_json("{}")).unwrap();
+    client.notify("test.public", &as_json("{}")).unwrap();
+
+    // Notifications of a client are handled in order.
+    let timeout = ::std::time::Duration::from_secs(5);
+    assert_eq!("test.public", notifications.recv_timeout(timeout).unwrap().topic);
+}
+
+#[test]
+fn notifications_over_calls_per_second_are_dropped() {
+    let t = TestHarness::with_config(config::Config {
+        rate_limits: Some(vec![quota::RateLimitRule {
+            client: Some("chatty".into()),
+            calls_per_second: Some(1),
+            .. quota::RateLimitRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe("test.").unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("chatty").unwrap();
+    client.notify("test.first", &as_json("{}")).unwrap();
+    client.notify("test.second", &as_json("{}")).unwrap();
+    // Answered once both notifications were handled.
+    let mut rpc = client.call("core.clients", &as_json("{}")).unwrap();
+    match rpc.wait().unwrap() {
+        rpc::Result::Err(ref err) if err.kind == rpc::ErrorKind::RateLimited => (),
+        other => panic!("Expected RateLimited, got {:?}", other),
+    }
+
+    let mut other_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    other_client.notify("test.third", &as_json("{}")).unwrap();
+
+    let timeout = ::std::time::Duration::from_secs(5);
+    assert_eq!("test.first", notifications.recv_timeout(timeout).unwrap().topic);
+    assert_eq!("test.third", notifications.recv_timeout(timeout).unwrap().topic);
+}
+
+#[test]
+fn client_not_answering_pings_is_disconnected() {
+    let t = TestHarness::with_config(config::Config {
+        heartbeat: Some(config::HeartbeatConfig {
+            interval_ms: Some(50),
+            timeout_ms: Some(200),
+        }),
+        .. config::Config::default()
+    });
+
+    let client = client::Client::connect_unix(&t.socket_name).unwrap();
+
+    // Never answers the pings it gets.
+    let mut silent = UnixStream::connect(&t.socket_name).unwrap();
+    silent.set_read_timeout(Some(::std::time::Duration::from_secs(5))).unwrap();
+    let mut pings = Vec::new();
+    // Only returns once the server closed the connection.
+    silent.read_to_end(&mut pings).unwrap();
+    assert!(!pings.is_empty());
+
+    assert!(client.is_connected());
+}
+
+#[test]
+fn client_notices_unresponsive_server() {
+    let socket_name = temporary_socket_name();
+    // Accepts connections, but never answers.
+    let listener = UnixListener::bind(&socket_name).unwrap();
+
+    let mut client = client::Client::connect_unix(&socket_name).unwrap();
+    let _connection = listener.accept().unwrap();
+    let events = client.connection_events().unwrap();
+    // Never answered, the server is silent.
+    let mut pending = client.call("test.pending", &as_json("{}")).unwrap();
+    client.set_heartbeat(Some(config::Heartbeat {
+        interval: ::time::Duration::milliseconds(50),
+        timeout: ::time::Duration::milliseconds(200),
+    })).unwrap();
+    assert!(client.is_connected());
+
+    let mut noticed = false;
+    for _ in 0..40 {
+        if !client.is_connected() {
+            noticed = true;
+            break;
+        }
+        thread::sleep_ms(50);
+    }
+    assert!(noticed);
+    let timeout = ::std::time::Duration::from_secs(5);
+    assert_eq!(client::ConnectionEvent::Disconnected, events.recv_timeout(timeout).unwrap());
+    match pending.wait() {
+        Err(Error::Disconnected) => (),
+        other => panic!("Expected Disconnected, got {:?}", other),
+    }
+    match client.call("test.test", &as_json("{}")) {
+        Err(Error::Disconnected) => (),
+        other => panic!("Expected Disconnected, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn reconnecting_client_registers_its_rpcs_again() {
+    let socket_name = temporary_socket_name();
+    let mut server = Server::launch(&socket_name, &[]).unwrap();
+
+    let mut client = client::Client::connect_unix_reconnecting(&socket_name).unwrap();
+    let events = client.connection_events().unwrap();
+    client.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::Ok(as_json(r#"{ "from": "client" }"#)),
+    })).unwrap();
+
+    // Never finishes, so the call is still running when the server goes away.
+    let mut server_client = client::Client::connect_unix(&socket_name).unwrap();
+    server_client.new_rpc("test.endless", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            while !context.cancelled() {
+                thread::sleep_ms(10);
+            }
+        }
+    })).unwrap();
+    let mut endless = client.call("test.endless", &as_json("{}")).unwrap();
+
+    server.shutdown();
+    assert!(endless.wait().is_err());
+    assert_eq!(client::ConnectionEvent::Disconnected, events.recv().unwrap());
+    drop(server_client);
+
+    let mut server = Server::launch(&socket_name, &[]).unwrap();
+    assert_eq!(client::ConnectionEvent::Reconnected, events.recv().unwrap());
+    assert!(client.is_connected());
+
+    {
+        let mut other = client::Client::connect_unix(&socket_name).unwrap();
+        let mut found = false;
+        // Registering again is not synchronous with the event.
+        for _ in 0..50 {
+            let mut rpc = other.call("test.test", &as_json("{}")).unwrap();
+            if rpc.wait().unwrap() == rpc::Result::Ok(as_json(r#"{ "from": "client" }"#)) {
+                found = true;
+                break;
+            }
+            thread::sleep_ms(20);
+        }
+        assert!(found);
+    }
+
+    drop(client);
+    server.shutdown();
+}
+
+#[test]
+fn trace_records_matching_messages() {
+    let t = TestHarness::new();
+    let mut trace_file = t.temp_directory.path().to_path_buf();
+    trace_file.push("trace");
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut rpc = client2.call("core.trace", &tracer::TraceRequest {
+        enabled: true,
+        file: Some(trace_file.to_string_lossy().into_owned()),
+        functions: Some(vec!["test.".into()]),
+        clients: None,
+    }).unwrap();
+    assert!(rpc.wait().unwrap().is_ok());
+
+    let mut rpc = client2.call("test.test", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    let mut rpc = client2.call("test.not_there", &as_json("{}")).unwrap();
+    rpc.wait().unwrap();
+
+    let mut rpc = client2.call("core.trace", &tracer::TraceRequest::default()).unwrap();
+    assert!(rpc.wait().unwrap().is_ok());
+
+    let records: Vec<tracer::Record> = BufReader::new(fs::File::open(&trace_file).unwrap())
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    // test.test: call from client2, call to client1, answer from client1, answer to client2.
+    // test.not_there: call from client2, error to client2.
+    assert_eq!(6, records.len());
+    assert!(records.iter().all(|record| record.function.as_ref().unwrap().starts_with("test.")));
+    assert_eq!(3, records.iter().filter(|record| record.direction == "in").count());
+}
+
+#[test]
+fn stats_count_calls_and_errors() {
+    let mut t = TestHarness::with_config(config::Config {
+        prometheus_address: Some("127.0.0.1:0".into()),
+        .. config::Config::default()
+    });
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    for _ in 0..3 {
+        let mut rpc = client2.call("test.test", &as_json("{}")).unwrap();
+        rpc.wait().unwrap();
+    }
+    let mut rpc = client2.call("test.not_there", &as_json("{}")).unwrap();
+    rpc.wait().unwrap();
+
+    let mut rpc = client2.call("core.stats", &as_json("{}")).unwrap();
+    let response: stats::StatsResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    let test = response.functions.iter().find(|entry| entry.function == "test.test").unwrap();
+    assert_eq!(3, test.calls);
+    assert_eq!(0, test.errors);
+    assert_eq!(0, test.in_flight);
+    assert_eq!(3, test.latency.count);
+    assert!(response.functions.iter().all(|entry| entry.function != "test.not_there"));
+    let unknown = response.functions.iter()
+        .find(|entry| entry.function == stats::UNKNOWN_FUNCTION).unwrap();
+    assert_eq!(1, unknown.calls);
+    assert_eq!(1, unknown.errors);
+
+    let mut stream = TcpStream::connect(t.server().prometheus_address().unwrap()).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut page = String::new();
+    stream.read_to_string(&mut page).unwrap();
+    assert!(page.starts_with("HTTP/1.0 200 OK"));
+    assert!(page.contains("swiboe_rpc_calls_total{function=\"test.test\"} 3\n"));
+    assert!(page.contains("swiboe_rpc_latency_seconds_count{function=\"test.test\"} 3\n"));
+}
+
+fn in_flight_of(client: &mut client::Client, function: &str) -> usize {
+    let mut rpc = client.call("core.stats", &as_json("{}")).unwrap();
+    let response: stats::StatsResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    response.functions.iter()
+        .find(|entry| entry.function == function)
+        .map(|entry| entry.in_flight)
+        .unwrap_or(0)
+}
+
+#[test]
+fn in_flight_gauge_drops_after_a_cancel() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    assert_eq!(1, in_flight_of(&mut client, "test.inner"));
+
+    rpc.cancel().unwrap();
+    wait_for_flag(&cancelled);
+    assert_eq!(0, in_flight_of(&mut client, "test.inner"));
+}
+
+#[test]
+fn nested_calls_form_a_call_tree() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    inner_client.new_rpc("test.inner", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            thread::sleep_ms(20);
+            context.finish(rpc::Result::success(())).unwrap();
+        }
+    })).unwrap();
+
+    let mut outer_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    outer_client.new_rpc("test.outer", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            let result = context.call("test.inner", &as_json("{}")).unwrap().wait().unwrap();
+            context.finish(result).unwrap();
+        }
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.outer", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+
+    let mut rpc = client.call("core.call_trees", &call_tree::CallTreesRequest {
+        function: Some("test.outer".into()),
+        .. call_tree::CallTreesRequest::default()
+    }).unwrap();
+    let response: call_tree::CallTreesResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    assert_eq!(1, response.trees.len());
+
+    let outer = &response.trees[0];
+    assert_eq!("ok", outer.outcome);
+    assert_eq!(1, outer.children.len());
+    let inner = &outer.children[0];
+    assert_eq!("test.inner", inner.function);
+    assert!(inner.duration_us >= 20000);
+    assert!(outer.duration_us >= inner.offset_us + inner.duration_us);
+}
+
+fn call_tree_outcome(client: &mut client::Client, function: &str) -> String {
+    let mut rpc = client.call("core.call_trees", &call_tree::CallTreesRequest {
+        function: Some(function.into()),
+        .. call_tree::CallTreesRequest::default()
+    }).unwrap();
+    let response: call_tree::CallTreesResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    assert_eq!(1, response.trees.len());
+    response.trees[0].outcome.clone()
+}
+
+#[test]
+fn cancelled_call_is_no_longer_running_in_the_call_tree() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    assert_eq!("running", call_tree_outcome(&mut client, "test.inner"));
+
+    rpc.cancel().unwrap();
+    wait_for_flag(&cancelled);
+    assert_eq!("cancelled", call_tree_outcome(&mut client, "test.inner"));
+}
+
+// Calls 'function' from inside the RPC and finishes with its result.
+struct ForwardingRpc {
+    function: &'static str,
+}
+
+impl client::rpc::server::Rpc for ForwardingRpc {
+    fn call(&self, mut context: client::rpc::server::Context, args: serde_json::Value) {
+        let result = context.call(self.function, &args).unwrap().wait().unwrap();
+        context.finish(result).unwrap();
+    }
+}
+
+#[test]
+fn calling_back_into_a_waiting_client_is_a_deadlock() {
+    let t = TestHarness::new();
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.set_thread_pool_size(1).unwrap();
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.new_rpc("test.outer", Box::new(ForwardingRpc { function: "test.middle" })).unwrap();
+    client2.new_rpc("test.middle", Box::new(ForwardingRpc { function: "test.back" })).unwrap();
+    client1.new_rpc("test.back", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.outer", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::Err(rpc::Error {
+        kind: rpc::ErrorKind::Deadlock,
+        message: Some("The call would wait for itself: test.outer -> test.middle -> test.back.".into()),
+        details: Some(as_json(r#"["test.outer", "test.middle", "test.back"]"#)),
+    }), rpc.wait().unwrap());
+
+    // Calling the innermost function directly is fine.
+    let mut rpc = client.call("test.back", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+}
+
+#[test]
+fn calling_back_into_a_client_with_more_threads_is_no_deadlock() {
+    let t = TestHarness::new();
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.set_thread_pool_size(2).unwrap();
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.new_rpc("test.outer", Box::new(ForwardingRpc { function: "test.middle" })).unwrap();
+    client2.new_rpc("test.middle", Box::new(ForwardingRpc { function: "test.back" })).unwrap();
+    client1.new_rpc("test.back", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.outer", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+}
+
+#[test]
+fn calling_back_from_a_detached_call_is_no_deadlock() {
+    let t = TestHarness::new();
+    let (detached_tx, detached_rx) = sync::mpsc::channel();
+    let detached_tx = sync::Mutex::new(detached_tx);
+    let detached_rx = sync::Mutex::new(detached_rx);
+    let (outer_may_finish_tx, outer_may_finish_rx) = sync::mpsc::channel();
+    let outer_may_finish_tx = sync::Mutex::new(outer_may_finish_tx);
+    let outer_may_finish_rx = sync::Mutex::new(outer_may_finish_rx);
+    let (back_result_tx, back_result_rx) = sync::mpsc::channel();
+    let back_result_tx = sync::Mutex::new(back_result_tx);
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client1.set_thread_pool_size(1).unwrap();
+    client1.new_rpc("test.outer", Box::new(CallbackRpc {
+        priority: 0,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            context.call("test.middle", &as_json("{}")).unwrap().detach();
+            // The server has seen the detach once this is answered.
+            context.call("core.clients", &as_json("{}")).unwrap().wait().unwrap();
+            detached_tx.lock().unwrap().send(()).unwrap();
+            outer_may_finish_rx.lock().unwrap().recv().unwrap();
+            context.finish(rpc::Result::success(())).unwrap();
+        }
+    })).unwrap();
+    client1.new_rpc("test.back", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    client2.new_rpc("test.middle", Box::new(CallbackRpc {
+        priority: 0,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            detached_rx.lock().unwrap().recv().unwrap();
+            let mut back = context.call("test.back", &as_json("{}")).unwrap();
+            // The server has seen the call to 'test.back' once this is answered.
+            context.call("core.clients", &as_json("{}")).unwrap().wait().unwrap();
+            outer_may_finish_tx.lock().unwrap().send(()).unwrap();
+            let result = back.wait().unwrap();
+            back_result_tx.lock().unwrap().send(result.clone()).unwrap();
+            context.finish(result).unwrap();
+        }
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.outer", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    assert_eq!(rpc::Result::success(()), back_result_rx.recv().unwrap());
+}
+
+// Registers 'test.inner' on 'client', which runs until it is cancelled and then sets the returned
+// flag.
+fn new_rpc_waiting_for_cancel(client: &mut client::Client) -> sync::Arc<sync::Mutex<bool>> {
+    let cancelled = sync::Arc::new(sync::Mutex::new(false));
+    let cancelled_clone = cancelled.clone();
+    client.new_rpc("test.inner", Box::new(CallbackRpc {
+        priority: 0,
+        callback: move |mut context: client::rpc::server::Context, _| {
+            let cancelled = cancelled_clone.clone();
+            thread::spawn(move || {
+                while !context.cancelled() {
+                    thread::sleep_ms(10);
+                }
+                *cancelled.lock().unwrap() = true;
+            });
+        }
+    })).unwrap();
+    cancelled
+}
+
+fn wait_for_flag(flag: &sync::Arc<sync::Mutex<bool>>) {
+    for _ in 0..200 {
+        if *flag.lock().unwrap() {
+            return;
+        }
+        thread::sleep_ms(10);
+    }
+    panic!("The call was not cancelled.");
+}
+
+#[test]
+fn calls_of_a_handler_that_went_away_are_answered() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    // Known to be running once this is answered.
+    client.call("core.clients", &as_json("{}")).unwrap().wait().unwrap();
+    drop(inner_client);
+
+    match rpc.wait().unwrap() {
+        rpc::Result::Err(ref err) if err.kind == rpc::ErrorKind::Internal => (),
+        other => panic!("Expected an Internal error, got {:?}", other),
+    }
+}
+
+#[test]
+fn dropping_a_call_cancels_it() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    drop(rpc);
+    wait_for_flag(&cancelled);
+}
+
+#[test]
+fn cancelling_a_call_cancels_its_nested_calls() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut outer_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    outer_client.new_rpc("test.outer", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            let mut inner = context.call("test.inner", &as_json("{}")).unwrap();
+            // Blocks until the nested call is cancelled along with this one.
+            assert_eq!(rpc::ErrorKind::Cancelled, inner.wait().unwrap().unwrap_err().kind);
+            assert!(context.cancelled());
+        }
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let rpc = client.call("test.outer", &as_json("{}")).unwrap();
+    // Give the outer call time to start the inner one.
+    thread::sleep_ms(50);
+    rpc.cancel().unwrap();
+    wait_for_flag(&cancelled);
+}
+
+// Opened by the test to let handlers waiting at it go on.
+#[derive(Clone)]
+struct Gate(sync::Arc<(sync::Mutex<bool>, sync::Condvar)>);
+
+impl Gate {
+    fn new() -> Self {
+        Gate(sync::Arc::new((sync::Mutex::new(false), sync::Condvar::new())))
+    }
+
+    fn open(&self) {
+        *(self.0).0.lock().unwrap() = true;
+        (self.0).1.notify_all();
+    }
+
+    fn wait(&self) {
+        let mut open = (self.0).0.lock().unwrap();
+        while !*open {
+            open = (self.0).1.wait(open).unwrap();
+        }
+    }
+}
+
+// Waits at 'gate' in the handler itself and remembers how many of its calls ran at the same
+// time. Every call that started is announced through 'started'.
+struct BlockingRpc {
+    execution: client::rpc::server::Execution,
+    gate: Gate,
+    started: sync::Mutex<sync::mpsc::Sender<()>>,
+    running: sync::Arc<sync::Mutex<(usize, usize)>>,
+}
+
+impl BlockingRpc {
+    fn new(execution: client::rpc::server::Execution) -> (Self, sync::mpsc::Receiver<()>) {
+        let (tx, rx) = sync::mpsc::channel();
+        (BlockingRpc {
+            execution: execution,
+            gate: Gate::new(),
+            started: sync::Mutex::new(tx),
+            running: sync::Arc::new(sync::Mutex::new((0, 0))),
+        }, rx)
+    }
+}
+
+impl client::rpc::server::Rpc for BlockingRpc {
+    fn execution(&self) -> client::rpc::server::Execution { self.execution }
+    fn call(&self, mut context: client::rpc::server::Context, _: serde_json::Value) {
+        {
+            let mut running = self.running.lock().unwrap();
+            running.0 += 1;
+            running.1 = cmp::max(running.0, running.1);
+        }
+        self.started.lock().unwrap().send(()).unwrap();
+        self.gate.wait();
+        self.running.lock().unwrap().0 -= 1;
+        context.finish(rpc::Result::success(())).unwrap();
+    }
+}
+
+// Calls 'test.quick' while 'blocked' waits at its gate.
+fn assert_quick_call_is_not_blocked(client: &mut client::Client, blocked: &mut client::rpc::client::Context) {
+    let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    blocked.try_recv().unwrap();
+    assert!(!blocked.done());
+}
+
+#[test]
+fn long_running_handler_does_not_block_quick_one() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.set_thread_pool_size(2).unwrap();
+    let (slow, started) = BlockingRpc::new(client::rpc::server::Execution::Concurrent);
+    let gate = slow.gate.clone();
+    server_client.new_rpc("test.slow", Box::new(slow)).unwrap();
+    server_client.new_rpc("test.quick", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut slow = client.call("test.slow", &as_json("{}")).unwrap();
+    started.recv().unwrap();
+    assert_quick_call_is_not_blocked(&mut client, &mut slow);
+    gate.open();
+    assert_eq!(rpc::Result::success(()), slow.wait().unwrap());
+}
+
+#[test]
+fn serial_rpc_runs_one_call_at_a_time_without_blocking_others() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let (serial, started) = BlockingRpc::new(client::rpc::server::Execution::Serial);
+    let gate = serial.gate.clone();
+    let running = serial.running.clone();
+    server_client.new_rpc("test.slow", Box::new(serial)).unwrap();
+    server_client.new_rpc("test.quick", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut calls: Vec<_> = (0..3)
+        .map(|_| client.call("test.slow", &as_json("{}")).unwrap())
+        .collect();
+    started.recv().unwrap();
+    assert_quick_call_is_not_blocked(&mut client, &mut calls[2]);
+    gate.open();
+    for mut rpc in calls {
+        assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    }
+    assert_eq!(1, running.lock().unwrap().1);
+}
+
+#[test]
+fn dedicated_rpc_does_not_use_the_thread_pool() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let (dedicated, started) = BlockingRpc::new(client::rpc::server::Execution::Dedicated);
+    let gate = dedicated.gate.clone();
+    let running = dedicated.running.clone();
+    server_client.new_rpc("test.slow", Box::new(dedicated)).unwrap();
+    server_client.new_rpc("test.quick", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut calls: Vec<_> = (0..3)
+        .map(|_| client.call("test.slow", &as_json("{}")).unwrap())
+        .collect();
+    // All of them wait at the gate at the same time.
+    for _ in 0..3 {
+        started.recv().unwrap();
+    }
+    assert_quick_call_is_not_blocked(&mut client, &mut calls[0]);
+    gate.open();
+    for mut rpc in calls {
+        assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+    }
+    assert_eq!(3, running.lock().unwrap().1);
+}
+
+#[test]
+fn thread_pool_needs_a_thread() {
+    let t = TestHarness::new();
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    assert!(client.set_thread_pool_size(0).is_err());
+}
+
+#[test]
+fn panicking_handler_answers_with_internal_error() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.set_thread_pool_size(1).unwrap();
+    server_client.new_rpc("test.panic", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |_: client::rpc::server::Context, _| {
+            panic!("on purpose");
+        }
+    })).unwrap();
+    server_client.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.panic", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::Err(rpc::Error::new(
+        rpc::ErrorKind::Internal, "Handler panicked: on purpose")), rpc.wait().unwrap());
+
+    // The only thread of the pool survived.
+    let mut rpc = client.call("test.test", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+}
+
+#[test]
+fn dropping_an_unfinished_context_answers_with_internal_error() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.new_rpc("test.forgetful", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |_: client::rpc::server::Context, _| {}
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.forgetful", &as_json("{}")).unwrap();
+    assert_eq!(rpc::ErrorKind::Internal, rpc.wait().unwrap().unwrap_err().kind);
+}
+
+#[test]
+fn wait_for_returns_custom_errors() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.new_rpc("test.late", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::Err(rpc::Error::custom("test.too_late", "It is too late.")),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.late", &as_json("{}")).unwrap();
+    match rpc.wait_for::<serde_json::Value>() {
+        Err(::swiboe::Error::Rpc(error)) => {
+            assert_eq!(rpc::ErrorKind::Custom("test.too_late".into()), error.kind);
+            assert_eq!("test.too_late: It is too late.", format!("{}", error));
+        },
+        other => panic!("Expected an RPC error, got {:?}", other),
+    }
+}
+
+struct CountDefinition;
+
+impl rpc::RpcDefinition for CountDefinition {
+    type Request = usize;
+    type Response = String;
+    type Update = usize;
+
+    fn name() -> &'static str { "test.count" }
+}
+
+struct Count;
+
+impl client::rpc::typed::Rpc for Count {
+    type Definition = CountDefinition;
+
+    fn call(&self, mut context: client::rpc::typed::Context<CountDefinition>, up_to: usize) {
+        for i in 0..up_to {
+            context.update(&i).unwrap();
+        }
+        context.finish(Ok("done".into())).unwrap();
+    }
+}
+
+#[test]
+fn typed_rpc_streams_updates_and_result() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.new_typed_rpc(Count).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call_typed::<CountDefinition>(&3).unwrap();
+    assert_eq!(Some(0), rpc.recv().unwrap());
+    assert_eq!(Some(1), rpc.recv().unwrap());
+    assert_eq!(Some(2), rpc.recv().unwrap());
+    assert_eq!(None, rpc.recv().unwrap());
+    assert_eq!(Ok("done".to_string()), rpc.wait().unwrap());
+
+    // Arguments that do not fit the request type never reach the handler.
+    let mut rpc = client.call("test.count", &as_json(r#""three""#)).unwrap();
+    assert_eq!(rpc::ErrorKind::InvalidArgs, rpc.wait().unwrap().unwrap_err().kind);
+}
+
+#[test]
+fn call_async_is_completed_without_polling() {
+    let t = TestHarness::new();
+
+    let mut server_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    server_client.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(as_json(r#"{ "foo": "blah" }"#)),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let future = client.call_async("test.test", &as_json("{}")).unwrap();
+    // 'wait' parks the thread until the read thread wakes it.
+    assert_eq!(rpc::Result::success(as_json(r#"{ "foo": "blah" }"#)), future.wait().unwrap());
+}
+
+#[test]
+fn call_stream_yields_partial_results() {
+    let t = TestHarness::new();
+
+    let mut streaming_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    streaming_client.new_rpc("test.test", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            thread::spawn(move || {
+                for i in 0..3 {
+                    thread::sleep_ms(10);
+                    context.update(&i).unwrap();
+                }
+                context.finish(rpc::Result::success("done")).unwrap();
+            });
+        },
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut stream = client.call("test.test", &as_json("{}")).unwrap().stream();
+    let partials: Vec<_> = (&mut stream).wait().map(|value| value.unwrap()).collect();
+    assert_eq!(vec![as_json("0"), as_json("1"), as_json("2")], partials);
+    assert_eq!(Some(rpc::Result::success("done")), stream.result());
+}
+
+#[test]
+fn dropping_a_call_future_cancels_the_call() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let future = client.call_async("test.inner", &as_json("{}")).unwrap();
+    drop(future);
+    wait_for_flag(&cancelled);
+}
+
+fn in_flight_of_client(client: &mut client::Client, name: &str) -> usize {
+    let mut rpc = client.call("core.clients", &as_json("{}")).unwrap();
+    let response: plugin_core::ClientsResponse =
+        serde_json::from_value(rpc.wait().unwrap().unwrap()).unwrap();
+    response.clients.iter()
+        .find(|status| status.name == Some(name.into()))
+        .unwrap()
+        .in_flight
+}
+
+#[test]
+fn dropped_call_futures_are_no_longer_in_flight() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("impatient").unwrap();
+    for _ in 0..3 {
+        *cancelled.lock().unwrap() = false;
+        let future = client.call_async("test.inner", &as_json("{}")).unwrap();
+        assert_eq!(1, in_flight_of_client(&mut client, "impatient"));
+        drop(future);
+        wait_for_flag(&cancelled);
+    }
+    assert_eq!(0, in_flight_of_client(&mut client, "impatient"));
+}
+
+#[test]
+fn waiting_too_long_for_a_call_cancels_it() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    match rpc.wait_timeout(::time::Duration::milliseconds(50)) {
+        Err(::swiboe::Error::Timeout) => (),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    wait_for_flag(&cancelled);
+}
+
+#[test]
+fn default_timeout_applies_to_calls_of_client_and_thin_clients() {
+    let t = TestHarness::new();
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.set_default_timeout(Some(::time::Duration::milliseconds(50)));
+    let mut thin_client = client.clone().unwrap();
+
+    let mut rpc = thin_client.call("test.inner", &as_json("{}")).unwrap();
+    match rpc.recv() {
+        Err(::swiboe::Error::Timeout) => (),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    wait_for_flag(&cancelled);
+
+    *cancelled.lock().unwrap() = false;
+    let mut rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    match rpc.wait() {
+        Err(::swiboe::Error::Timeout) => (),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    wait_for_flag(&cancelled);
+}
+
+#[test]
+fn timed_out_calls_do_not_count_against_the_in_flight_limit() {
+    let t = TestHarness::with_config(config::Config {
+        rate_limits: Some(vec![quota::RateLimitRule {
+            client: Some("impatient".into()),
+            max_in_flight: Some(1),
+            .. quota::RateLimitRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let mut inner_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let cancelled = new_rpc_waiting_for_cancel(&mut inner_client);
+    inner_client.new_rpc("test.quick", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::success(()),
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.handshake("impatient").unwrap();
+    let mut rpc = client.call("test.inner", &as_json("{}")).unwrap();
+    match rpc.wait_timeout(::time::Duration::milliseconds(50)) {
+        Err(::swiboe::Error::Timeout) => (),
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+    wait_for_flag(&cancelled);
+
+    let mut rpc = client.call("test.quick", &as_json("{}")).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+}
+
+#[test]
+fn default_timeout_does_not_apply_to_calls_the_client_makes_by_itself() {
+    let t = TestHarness::new();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.set_default_timeout(Some(::time::Duration::milliseconds(0)));
+    client.handshake("impatient").unwrap();
+    client.set_thread_pool_size(2).unwrap();
+    client.new_rpc("test.test", Box::new(TestCall {
+        priority: 0,
+        result: rpc::Result::Ok(as_json(r#"{ "foo": "blah" }"#)),
+    })).unwrap();
+    client.subscribe("test.").unwrap();
+    client.unsubscribe("test.").unwrap();
+}
+
+// Finishes with the sum of all numbers the caller streamed.
+fn new_summing_rpc(client: &mut client::Client, priority: u16) {
+    client.new_rpc("test.sum", Box::new(CallbackRpc {
+        priority: priority,
+        callback: |mut context: client::rpc::server::Context, _| {
+            let mut sum = 0;
+            while let Some(value) = context.recv_partial().unwrap() {
+                sum += value.as_u64().unwrap();
+            }
+            context.finish(rpc::Result::success(sum)).unwrap();
+        }
+    })).unwrap();
+}
+
+#[test]
+fn caller_streams_partials_to_the_handler() {
+    let t = TestHarness::new();
+
+    let mut serving_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    new_summing_rpc(&mut serving_client, 0);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.sum", &as_json("{}")).unwrap();
+    for i in 1..5 {
+        rpc.send_partial(&i).unwrap();
+    }
+    rpc.close_partials().unwrap();
+    assert_eq!(rpc::Result::success(10u64), rpc.wait().unwrap());
+}
+
+#[test]
+fn partials_are_passed_on_to_the_next_handler() {
+    let t = TestHarness::new();
+
+    let mut serving_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    new_summing_rpc(&mut serving_client, 1);
+
+    let mut declining_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    declining_client.new_rpc("test.sum", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            assert_eq!(Some(as_json("1")), context.recv_partial().unwrap());
+            context.finish(rpc::Result::NotHandled).unwrap();
+        }
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.sum", &as_json("{}")).unwrap();
+    rpc.send_partial(&1).unwrap();
+    rpc.send_partial(&2).unwrap();
+    rpc.close_partials().unwrap();
+    assert_eq!(rpc::Result::success(3u64), rpc.wait().unwrap());
+}
+
+#[test]
+fn partials_are_not_passed_on_once_the_handler_answered() {
+    let t = TestHarness::new();
+
+    let mut serving_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    new_summing_rpc(&mut serving_client, 1);
+
+    let mut declining_client = client::Client::connect_unix(&t.socket_name).unwrap();
+    declining_client.new_rpc("test.sum", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, _| {
+            assert_eq!(Some(as_json("1")), context.recv_partial().unwrap());
+            context.update(&as_json("1")).unwrap();
+            context.finish(rpc::Result::NotHandled).unwrap();
+        }
+    })).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("test.sum", &as_json("{}")).unwrap();
+    rpc.send_partial(&1).unwrap();
+    rpc.send_partial(&2).unwrap();
+    rpc.close_partials().unwrap();
+    assert_eq!(Some(as_json("1")), rpc.recv().unwrap());
+    match rpc.wait().unwrap() {
+        rpc::Result::Err(err) => assert_eq!(rpc::ErrorKind::Internal, err.kind),
+        other => panic!("Expected an error, got {:?}", other),
+    }
+}
+
+#[test]
+fn notifications_are_delivered_to_all_subscribers() {
+    let t = TestHarness::new();
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let everything = subscriber.subscribe("test.").unwrap();
+    let mut other_subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let events = other_subscriber.subscribe("test.event").unwrap();
+    let others = other_subscriber.subscribe("other.").unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.notify("test.event", &as_json("{\"a\": 1}")).unwrap();
+
+    let timeout = ::std::time::Duration::from_secs(5);
+    let expected = rpc::Notification {
+        topic: "test.event".into(),
+        args: as_json("{\"a\": 1}"),
+    };
+    assert_eq!(expected, everything.recv_timeout(timeout).unwrap());
+    assert_eq!(expected, events.recv_timeout(timeout).unwrap());
+    assert!(others.try_recv().is_err());
+}
+
+#[test]
+fn unsubscribed_clients_get_no_notifications() {
+    let t = TestHarness::new();
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe("test.").unwrap();
+    subscriber.unsubscribe("test.").unwrap();
+    let later = subscriber.subscribe("later.").unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    client.notify("test.event", &as_json("{}")).unwrap();
+    client.notify("later.event", &as_json("{}")).unwrap();
+
+    // Notifications arrive in order, so the first one was not delivered if the second one is.
+    assert_eq!("later.event", later.recv_timeout(::std::time::Duration::from_secs(5)).unwrap().topic);
+    assert!(notifications.try_recv().is_err());
+}
+
+fn coalesce(mode: plugin_core::CoalesceMode, window_ms: u64, key: Option<&str>) -> plugin_core::Coalesce {
+    plugin_core::Coalesce {
+        mode: mode,
+        window_ms: window_ms,
+        key: key.map(|key| key.to_string()),
+    }
+}
+
+#[test]
+fn coalesced_subscription_gets_only_the_latest_notification_per_key() {
+    let t = TestHarness::new();
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe_coalesced(
+        "test.", coalesce(plugin_core::CoalesceMode::Latest, 200, Some("id"))).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    for i in 0..10 {
+        client.notify("test.moved", &as_json(&format!("{{\"id\": {}, \"position\": {}}}", i % 2, i))).unwrap();
+    }
+
+    let timeout = ::std::time::Duration::from_secs(5);
+    assert_eq!(as_json("{\"id\": 0, \"position\": 8}"), notifications.recv_timeout(timeout).unwrap().args);
+    assert_eq!(as_json("{\"id\": 1, \"position\": 9}"), notifications.recv_timeout(timeout).unwrap().args);
+    assert!(notifications.recv_timeout(::std::time::Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn batched_subscription_gets_all_notifications_in_order() {
+    let t = TestHarness::new();
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe_coalesced("test.", coalesce(plugin_core::CoalesceMode::Batch, 100, None)).unwrap();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    for i in 0..5 {
+        client.notify("test.edit", &i).unwrap();
+    }
+
+    let timeout = ::std::time::Duration::from_secs(5);
+    for i in 0..5u64 {
+        assert_eq!(Some(i), notifications.recv_timeout(timeout).unwrap().args.as_u64());
+    }
+}
+
+#[test]
+fn unknown_coalesce_mode_is_refused() {
+    let t = TestHarness::new();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut rpc = client.call("core.subscribe", &as_json(
+            r#"{ "prefix": "test.", "coalesce": { "mode": "sometimes", "window_ms": 100, "key": null } }"#)).unwrap();
+    match rpc.wait().unwrap() {
+        rpc::Result::Err(err) => assert_eq!(rpc::ErrorKind::InvalidArgs, err.kind),
+        other => panic!("Expected InvalidArgs, got {:?}", other),
+    }
+}
+
+#[test]
+fn notifications_from_clients_must_be_single() {
+    let t = TestHarness::new();
+
+    let mut subscriber = client::Client::connect_unix(&t.socket_name).unwrap();
+    let notifications = subscriber.subscribe("test.").unwrap();
+
+    // Speaks the wire format itself, since clients cannot send Notifications.
+    let mut client = UnixStream::connect(&t.socket_name).unwrap();
+    let messages = [
+        r#"{ "Notifications": { "prefix": "test.", "notifications": [ { "topic": "test.forged", "args": {} } ] } }"#,
+        r#"{ "Notification": { "topic": "test.event", "args": {} } }"#,
+    ];
+    for message in &messages {
+        let len = message.len();
+        client.write_all(&[(len >> 0) as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]).unwrap();
+        client.write_all(message.as_bytes()).unwrap();
+    }
+
+    let timeout = ::std::time::Duration::from_secs(5);
+    assert_eq!("test.event", notifications.recv_timeout(timeout).unwrap().topic);
+}
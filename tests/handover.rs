@@ -0,0 +1,115 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+use ::CallbackRpc;
+use serde_json;
+use std::env;
+use std::path;
+use std::process;
+use swiboe::client::RpcCaller;
+use swiboe::client;
+use swiboe::plugin::buffer;
+use swiboe::rpc;
+use swiboe::server::config;
+use swiboe::server::plugin_core;
+use swiboe::testing::{TestHarness, temporary_path};
+
+// The server binary is built next to the test binary, or one directory up when tests end up in
+// 'deps'.
+fn server_binary() -> path::PathBuf {
+    let mut directory = env::current_exe().unwrap();
+    for _ in 0..2 {
+        directory.pop();
+        let candidate = directory.join("server");
+        if candidate.exists() {
+            return candidate;
+        }
+    }
+    panic!("No server binary found near {:?}.", env::current_exe().unwrap());
+}
+
+#[test]
+fn clients_survive_a_handover() {
+    let mut t = TestHarness::with_config(config::Config {
+        handover_socket: Some(temporary_path(".handover").to_string_lossy().into_owned()),
+        .. config::Config::default()
+    });
+    let socket_name = t.socket_name.clone();
+
+    let mut callee = client::Client::connect_unix(&socket_name).unwrap();
+    callee.new_rpc("test.echo", Box::new(CallbackRpc {
+        priority: 100,
+        callback: move |mut context: client::rpc::server::Context, args: serde_json::Value| {
+            context.finish(rpc::Result::Ok(args)).unwrap();
+        }
+    })).unwrap();
+
+    let mut caller = client::Client::connect_unix(&socket_name).unwrap();
+    let mut rpc = caller.call("buffer.new", &buffer::new::Request {
+        content: Some("blub".into()),
+    }).unwrap();
+    assert_eq!(rpc::Result::success(buffer::new::Response { buffer_index: 0 }), rpc.wait().unwrap());
+
+    let handover_socket = t.config.handover_socket.clone().unwrap();
+    let mut new_server = process::Command::new(server_binary())
+        .arg("--socket").arg(&socket_name)
+        .arg("--handover_socket").arg(&handover_socket)
+        .arg("--takeover")
+        .spawn()
+        .unwrap();
+    t.wait_for_shutdown();
+    assert!(socket_name.exists());
+
+    let mut rpc = caller.call("test.echo", &"hello").unwrap();
+    assert_eq!(rpc::Result::success("hello"), rpc.wait().unwrap());
+
+    let mut rpc = caller.call("buffer.get_content", &buffer::get_content::Request {
+        buffer_index: 0,
+    }).unwrap();
+    assert_eq!(rpc::Result::success(buffer::get_content::Response {
+        content: "blub".into(),
+    }), rpc.wait().unwrap());
+
+    // The new server quits before it could answer.
+    let _ = caller.call("core.exit", &());
+    assert!(new_server.wait().unwrap().success());
+}
+
+#[test]
+fn held_back_notifications_are_delivered_before_a_handover() {
+    let mut t = TestHarness::with_config(config::Config {
+        handover_socket: Some(temporary_path(".handover").to_string_lossy().into_owned()),
+        .. config::Config::default()
+    });
+    let socket_name = t.socket_name.clone();
+
+    let mut subscriber = client::Client::connect_unix(&socket_name).unwrap();
+    // Far longer than the test runs.
+    let notifications = subscriber.subscribe_coalesced("test.", plugin_core::Coalesce {
+        mode: plugin_core::CoalesceMode::Batch,
+        window_ms: 600000,
+        key: None,
+    }).unwrap();
+
+    let mut client = client::Client::connect_unix(&socket_name).unwrap();
+    client.notify("test.event", &"held back").unwrap();
+    // Answered once the notification was handled.
+    let mut rpc = client.call("core.clients", &()).unwrap();
+    assert!(rpc.wait().unwrap().is_ok());
+
+    let handover_socket = t.config.handover_socket.clone().unwrap();
+    let mut new_server = process::Command::new(server_binary())
+        .arg("--socket").arg(&socket_name)
+        .arg("--handover_socket").arg(&handover_socket)
+        .arg("--takeover")
+        .spawn()
+        .unwrap();
+    t.wait_for_shutdown();
+
+    let notification = notifications.recv_timeout(::std::time::Duration::from_secs(5)).unwrap();
+    assert_eq!("test.event", notification.topic);
+
+    let _ = client.call("core.exit", &());
+    assert!(new_server.wait().unwrap().success());
+}
@@ -2,23 +2,22 @@
 // Licensed under the Apache License, Version 2.0. See LICENSE.txt
 // in the project root for license information.
 
-use ::{CallbackRpc, create_file};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use ::create_file;
+use serde_json;
+use std::iter;
+use std::sync::mpsc;
+use std::time::Duration;
+use swiboe::blob::{self, Blob};
 use swiboe::client::RpcCaller;
 use swiboe::client;
 use swiboe::plugin::buffer;
 use swiboe::rpc;
 use swiboe::testing::TestHarness;
+use swiboe::testing::replay;
 
-fn wait_for_true_with_timeout(mutex: &Mutex<bool>) -> bool {
-    for _ in (0..10) {
-        if *mutex.lock().unwrap() {
-            return true;
-        }
-        thread::sleep_ms(50);
-    }
-    false
+// The next notification, which must arrive soon.
+fn next_notification(notifications: &mpsc::Receiver<rpc::Notification>) -> rpc::Notification {
+    notifications.recv_timeout(Duration::from_secs(5)).unwrap()
 }
 
 fn create_buffer(client: &mut client::Client, expected_index: usize, content: Option<&str>) {
@@ -34,21 +33,14 @@ fn create_buffer(client: &mut client::Client, expected_index: usize, content: Op
 #[test]
 fn buffer_new() {
     let t = TestHarness::new();
-    let callback_called = Arc::new(Mutex::new(false));
     let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
-    {
-        let callback_called = callback_called.clone();
-        client.new_rpc("on.buffer.new", Box::new(CallbackRpc {
-            priority: 100,
-            callback: move |mut sender: client::rpc::server::Context, _| {
-                sender.finish(rpc::Result::success(())).unwrap();
-                *callback_called.lock().unwrap() = true;
-            }
-        })).unwrap();
-
-        create_buffer(&mut client, 0, None);
-    }
-    assert!(wait_for_true_with_timeout(&*callback_called));
+    let notifications = client.subscribe("on.buffer.").unwrap();
+
+    create_buffer(&mut client, 0, None);
+
+    let notification = next_notification(&notifications);
+    assert_eq!("on.buffer.new", notification.topic);
+    assert_eq!(Some(0), notification.args.find("buffer_index").and_then(|index| index.as_u64()));
 }
 
 #[test]
@@ -67,6 +59,94 @@ fn buffer_new_with_content() {
     }));
 }
 
+#[test]
+fn typed_buffer_new_and_get_content() {
+    let t = TestHarness::new();
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+
+    let content = "blub\nblah\nbli";
+    let mut rpc = client.call_typed::<buffer::new::Definition>(&buffer::new::Request {
+        content: Some(content.into()),
+    }).unwrap();
+    assert_eq!(rpc.wait().unwrap(), Ok(buffer::new::Response {
+        buffer_index: 0,
+    }));
+
+    let mut rpc = client.call_typed::<buffer::get_content::Definition>(&buffer::get_content::Request {
+        buffer_index: 0,
+    }).unwrap();
+    assert_eq!(rpc.wait().unwrap(), Ok(buffer::get_content::Response {
+        content: content.into(),
+    }));
+}
+
+// Gets the content of a new buffer with 'content' and returns the Blob it was passed through, if
+// any.
+fn get_content_blob(content: &str) -> Option<Blob> {
+    let t = TestHarness::new();
+    let mut session_file = t.temp_directory.path().to_path_buf();
+    session_file.push("session");
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    assert!(client.is_same_host());
+    create_buffer(&mut client, 0, Some(content));
+
+    replay::start_recording(&mut client, &session_file).unwrap();
+    let mut rpc = client.call_typed::<buffer::get_content::Definition>(&buffer::get_content::Request {
+        buffer_index: 0,
+    }).unwrap();
+    assert_eq!(rpc.wait().unwrap(), Ok(buffer::get_content::Response {
+        content: content.into(),
+    }));
+    replay::stop_recording(&mut client).unwrap();
+
+    let session = replay::Session::load(&session_file).unwrap();
+    session.records.iter()
+        .filter_map(|record| {
+            serde_json::to_value(&record.message).find("RpcResponseBlob")
+                .and_then(|response| response.find("blob").cloned())
+        })
+        .next()
+        .map(|blob| serde_json::from_value(blob).unwrap())
+}
+
+#[test]
+fn large_content_is_passed_through_a_blob() {
+    let content: String = iter::repeat("blub\n").take(blob::MIN_RESULT_BYTES / 4).collect();
+    let blob = get_content_blob(&content).unwrap();
+
+    // The client of the caller took it, which removed the segment.
+    assert!(blob.take().is_err());
+}
+
+#[test]
+fn blob_longer_than_its_segment_is_refused() {
+    let blob = Blob::create(b"blub").unwrap();
+    let too_long = Blob {
+        name: blob.name.clone(),
+        len: 1024 * 1024,
+    };
+    assert!(too_long.take().is_err());
+    // It was removed nevertheless.
+    assert!(blob.take().is_err());
+}
+
+#[test]
+fn blob_names_not_made_by_create_are_refused() {
+    for name in &["/etc-passwd", "/swiboe-", "/swiboe-0123456789abcdef0123/", "/swiboe-0123456789ABCDEF0123"] {
+        let blob = Blob {
+            name: name.to_string(),
+            len: 4,
+        };
+        assert!(blob.take().is_err());
+        assert!(blob.discard().is_err());
+    }
+}
+
+#[test]
+fn small_content_is_not_put_into_a_blob() {
+    assert_eq!(None, get_content_blob("blub"));
+}
+
 #[test]
 fn buffer_open_unhandled_uri() {
     let t = TestHarness::new();
@@ -105,27 +185,21 @@ fn buffer_open_file() {
 #[test]
 fn buffer_delete() {
     let t = TestHarness::new();
-    let callback_called = Arc::new(Mutex::new(false));
     let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
-    {
-        let callback_called = callback_called.clone();
-        client.new_rpc("on.buffer.deleted", Box::new(CallbackRpc {
-            priority: 100,
-            callback: move |mut sender: client::rpc::server::Context, _| {
-                sender.finish(rpc::Result::success(())).unwrap();
-                *callback_called.lock().unwrap() = true;
-            }
-        })).unwrap();
-
-        create_buffer(&mut client, 0, None);
-
-        let request = buffer::delete::Request {
-            buffer_index: 0,
-        };
-        let mut rpc = client.call("buffer.delete", &request).unwrap();
-        assert_eq!(rpc.wait().unwrap(), rpc::Result::success(()));
-    }
-    assert!(wait_for_true_with_timeout(&*callback_called));
+    let notifications = client.subscribe("on.buffer.deleted").unwrap();
+
+    create_buffer(&mut client, 0, None);
+
+    let request = buffer::delete::Request {
+        buffer_index: 0,
+    };
+    let mut rpc = client.call("buffer.delete", &request).unwrap();
+    assert_eq!(rpc.wait().unwrap(), rpc::Result::success(()));
+
+    // Only the deletion matches the prefix.
+    let notification = next_notification(&notifications);
+    assert_eq!("on.buffer.deleted", notification.topic);
+    assert_eq!(Some(0), notification.args.find("buffer_index").and_then(|index| index.as_u64()));
 }
 
 #[test]
@@ -137,6 +211,21 @@ fn buffer_delete_non_existing() {
         buffer_index: 0,
     };
     let mut rpc = client.call("buffer.delete", &request).unwrap();
-    assert_eq!(
-        rpc.wait().unwrap().unwrap_err().details.unwrap().as_string(), Some("unknown_buffer"));
+    let err = rpc.wait().unwrap().unwrap_err();
+    assert_eq!(rpc::ErrorKind::NotFound, err.kind);
+    assert_eq!(err.details.unwrap().as_string(), Some("unknown_buffer"));
+}
+
+#[test]
+fn typed_buffer_delete_non_existing() {
+    let t = TestHarness::new();
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let request = buffer::delete::Request {
+        buffer_index: 0,
+    };
+    let mut rpc = client.call_typed::<buffer::delete::Definition>(&request).unwrap();
+    let err = rpc.wait().unwrap().unwrap_err();
+    assert_eq!(rpc::ErrorKind::NotFound, err.kind);
+    assert_eq!(err.details.unwrap().as_string(), Some("unknown_buffer"));
 }
@@ -0,0 +1,141 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+use serde_json;
+use std::fs;
+use std::io::Write;
+use std::path;
+use std::thread;
+use std::time::Duration;
+use swiboe::client::RpcCaller;
+use swiboe::client;
+use swiboe::rpc;
+use swiboe::server::config;
+use swiboe::server::plugin_manager;
+use swiboe::testing::{TestHarness, temporary_path};
+
+fn write_manifest(directory: &path::Path, name: &str, content: &str) {
+    let mut file_name = directory.to_path_buf();
+    file_name.push(format!("{}.json", name));
+    let mut f = fs::File::create(&file_name).unwrap();
+    f.write_all(content.as_bytes()).unwrap();
+}
+
+fn launch(plugin_directory: &path::Path) -> TestHarness {
+    TestHarness::with_config(config::Config {
+        plugin_directory: Some(plugin_directory.to_string_lossy().into_owned()),
+        .. config::Config::default()
+    })
+}
+
+fn list_plugins(client: &mut client::Client) -> Vec<plugin_manager::PluginStatus> {
+    let mut rpc = client.call("core.plugins.list", &()).unwrap();
+    let response: plugin_manager::ListResponse = rpc.wait_for().unwrap();
+    response.plugins
+}
+
+#[test]
+fn plugin_is_autostarted_and_can_be_stopped() {
+    let plugin_directory = temporary_path(".plugins");
+    fs::create_dir(&plugin_directory).unwrap();
+    write_manifest(&plugin_directory, "sleeper", r#"{
+        "name": "sleeper",
+        "command": "sh",
+        "args": ["-c", "sleep 30"],
+        "rpcs": ["sleeper.nap"]
+    }"#);
+
+    let t = launch(&plugin_directory);
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+
+    let plugins = list_plugins(&mut client);
+    assert_eq!(1, plugins.len());
+    assert_eq!("running", plugins[0].state);
+    assert_eq!(vec!["sleeper.nap".to_string()], plugins[0].rpcs);
+
+    let mut rpc = client.call("core.plugins.stop", &plugin_manager::PluginRequest {
+        name: "sleeper".into(),
+    }).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+
+    let mut stopped = false;
+    for _ in 0..20 {
+        if list_plugins(&mut client)[0].state == "stopped" {
+            stopped = true;
+            break;
+        }
+        thread::sleep_ms(50);
+    }
+    assert!(stopped);
+
+    drop(t);
+    fs::remove_dir_all(&plugin_directory).unwrap();
+}
+
+#[test]
+fn crashing_plugin_is_restarted() {
+    let plugin_directory = temporary_path(".plugins");
+    fs::create_dir(&plugin_directory).unwrap();
+    write_manifest(&plugin_directory, "crasher", r#"{
+        "name": "crasher",
+        "command": "sh",
+        "args": ["-c", "exit 1"],
+        "restart": "on_failure",
+        "autostart": false
+    }"#);
+
+    let t = launch(&plugin_directory);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let restarts = client.subscribe("on.plugin.restarted").unwrap();
+
+    let mut rpc = client.call("core.plugins.start", &plugin_manager::PluginRequest {
+        name: "crasher".into(),
+    }).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+
+    let notification = restarts.recv_timeout(Duration::from_secs(2)).unwrap();
+    let event: plugin_manager::PluginRestarted = serde_json::from_value(notification.args).unwrap();
+    assert_eq!("crasher", event.name);
+
+    drop(t);
+    fs::remove_dir_all(&plugin_directory).unwrap();
+}
+
+#[test]
+fn plugin_exiting_successfully_did_not_crash() {
+    let plugin_directory = temporary_path(".plugins");
+    fs::create_dir(&plugin_directory).unwrap();
+    write_manifest(&plugin_directory, "oneshot", r#"{
+        "name": "oneshot",
+        "command": "sh",
+        "args": ["-c", "exit 0"],
+        "autostart": false
+    }"#);
+
+    let t = launch(&plugin_directory);
+
+    let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+    let crashes = client.subscribe("on.plugin.crashed").unwrap();
+
+    let mut rpc = client.call("core.plugins.start", &plugin_manager::PluginRequest {
+        name: "oneshot".into(),
+    }).unwrap();
+    assert_eq!(rpc::Result::success(()), rpc.wait().unwrap());
+
+    let mut stopped = false;
+    for _ in 0..20 {
+        if list_plugins(&mut client)[0].state == "stopped" {
+            stopped = true;
+            break;
+        }
+        thread::sleep_ms(50);
+    }
+    assert!(stopped);
+    // The plugin manager publishes before it answers the list that saw the plugin stopped.
+    assert!(crashes.recv_timeout(Duration::from_millis(100)).is_err());
+
+    drop(t);
+    fs::remove_dir_all(&plugin_directory).unwrap();
+}
@@ -0,0 +1,99 @@
+// Copyright (c) The Swiboe development team. All rights reserved.
+// Licensed under the Apache License, Version 2.0. See LICENSE.txt
+// in the project root for license information.
+
+use ::CallbackRpc;
+use serde_json;
+use swiboe::client::RpcCaller;
+use swiboe::client;
+use swiboe::rpc;
+use swiboe::server::config;
+use swiboe::server::permissions;
+use swiboe::testing::TestHarness;
+use swiboe::testing::replay;
+
+fn record_echo_session(t: &TestHarness) -> replay::Session {
+    let mut session_file = t.temp_directory.path().to_path_buf();
+    session_file.push("session");
+
+    let mut client1 = client::Client::connect_unix(&t.socket_name).unwrap();
+    let mut client2 = client::Client::connect_unix(&t.socket_name).unwrap();
+    replay::start_recording(&mut client2, &session_file).unwrap();
+
+    client1.new_rpc("test.echo", Box::new(CallbackRpc {
+        priority: 0,
+        callback: |mut context: client::rpc::server::Context, args| {
+            context.finish(rpc::Result::Ok(args)).unwrap();
+        }
+    })).unwrap();
+    for index in 0..3 {
+        let mut rpc = client2.call("test.echo", &index).unwrap();
+        assert_eq!(rpc::Result::success(index), rpc.wait().unwrap());
+    }
+
+    replay::stop_recording(&mut client2).unwrap();
+    replay::Session::load(&session_file).unwrap()
+}
+
+#[test]
+fn replaying_a_session_shows_no_divergences() {
+    let session = {
+        let t = TestHarness::new();
+        record_echo_session(&t)
+    };
+
+    let t = TestHarness::new();
+    let divergences = replay::replay(&t, &session).unwrap();
+    assert!(divergences.is_empty(), "{:?}", divergences);
+}
+
+#[test]
+fn replay_reports_missing_answers() {
+    let mut session = {
+        let t = TestHarness::new();
+        record_echo_session(&t)
+    };
+    // Drop the answers of the echo server, so the caller never gets one.
+    session.records.retain(|record| {
+        let message = serde_json::to_value(&record.message);
+        !(record.direction == "in" && message.find("RpcResponse").is_some())
+    });
+
+    let t = TestHarness::new();
+    let divergences = replay::replay(&t, &session).unwrap();
+    assert!(!divergences.is_empty());
+    assert!(divergences.iter().all(|divergence| divergence.actual.is_none()));
+}
+
+#[test]
+fn replay_repeats_handshakes_made_before_the_recording() {
+    let new_harness = || TestHarness::with_config(config::Config {
+        permissions: Some(vec![permissions::PermissionRule {
+            client: Some("untrusted".into()),
+            call: Some(permissions::NamespaceFilter {
+                allow: None,
+                deny: Some(vec!["test".into()]),
+            }),
+            .. permissions::PermissionRule::default()
+        }]),
+        .. config::Config::default()
+    });
+
+    let session = {
+        let t = new_harness();
+        let mut session_file = t.temp_directory.path().to_path_buf();
+        session_file.push("session");
+
+        let mut client = client::Client::connect_unix(&t.socket_name).unwrap();
+        client.handshake("untrusted").unwrap();
+        replay::start_recording(&mut client, &session_file).unwrap();
+        let mut rpc = client.call("test.echo", &1).unwrap();
+        assert_eq!(rpc::ErrorKind::PermissionDenied, rpc.wait().unwrap().unwrap_err().kind);
+        replay::stop_recording(&mut client).unwrap();
+        replay::Session::load(&session_file).unwrap()
+    };
+
+    let t = new_harness();
+    let divergences = replay::replay(&t, &session).unwrap();
+    assert!(divergences.is_empty(), "{:?}", divergences);
+}
@@ -4,9 +4,12 @@
 
 #![feature(unboxed_closures)]
 
+extern crate futures;
 extern crate serde;
 extern crate serde_json;
 extern crate swiboe;
+extern crate time;
+extern crate unix_socket;
 extern crate uuid;
 
 use std::fs;
@@ -16,7 +19,10 @@ use swiboe::client;
 use swiboe::testing;
 
 mod core;
+mod handover;
 mod plugin_buffer;
+mod plugin_manager;
+mod replay;
 
 pub struct CallbackRpc<F> {
     pub priority: u16,